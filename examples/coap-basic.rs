@@ -0,0 +1,22 @@
+use aiot::{Coap, DataModelMsg, ThreeTuple};
+use anyhow::Result;
+use serde_json::json;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let three = ThreeTuple::from_env();
+    let host = format!("{}.coap.cn-shanghai.link.aliyuncs.com", three.product_key);
+
+    // 对称加密方式，第一次发送时会自动认证
+    let mut client = Coap::new_aes(&host, &three).await?;
+
+    let res = client
+        .send_dm(DataModelMsg::property_post(json!({
+            "LightSwitch": 1
+        })))
+        .await?;
+    log::info!("{}", String::from_utf8_lossy(&res));
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
-use aiot::{Http, ThreeTuple};
+use aiot::{DataModelMsg, Http, ThreeTuple};
 use anyhow::Result;
+use serde_json::json;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -7,13 +8,20 @@ async fn main() -> Result<()> {
     let host = "iot-as-http.cn-shanghai.aliyuncs.com";
     let three = ThreeTuple::from_env();
 
+    // 第一次发送时会自动鉴权，token 过期后也会自动刷新
     let mut client = Http::new_tls(host, &three)?;
-    client.auth().await?;
 
     let topic = "/sys/a13FN5TplKq/http_basic_demo/thing/event/property/post";
     let data = b"{\"id\":\"1\",\"version\":\"1.0\",\"params\":{\"LightSwitch\":1}}";
     let res = client.send(topic, data).await?;
     log::info!("{:?}", res);
 
+    let res = client
+        .send_dm(DataModelMsg::property_post(json!({
+            "LightSwitch": 0
+        })))
+        .await?;
+    log::info!("{:?}", res);
+
     Ok(())
 }
@@ -1,3 +1,5 @@
+use aiot::tunnel::audit::SessionRequest;
+use aiot::tunnel::Event;
 use aiot::{LocalService, MqttClient, SecureTunnelNotify, ThreeTuple, TunnelProxy};
 use anyhow::Result;
 use log::*;
@@ -13,9 +15,21 @@ async fn main() -> Result<()> {
     let proxy = TunnelProxy::new();
     let ssh = LocalService::default(); // 默认是 _SSH 127.0.0.1:22
     proxy.add_service(ssh).await?;
+    // 只允许 SSH 会话
+    proxy
+        .set_policy(
+            |request: &SessionRequest| match request.service_type.as_str() {
+                "_SSH" => Ok(()),
+                other => Err(format!("不允许访问 {}", other)),
+            },
+        )
+        .await?;
+    let mut events = proxy.events();
 
     let mut ra = conn.remote_access()?;
     ra.init().await?;
+    // 会话审计记录通过日志上报
+    let log_post = conn.log_post()?;
     loop {
         tokio::select! {
             Ok(_) = conn.poll() => {
@@ -29,10 +43,7 @@ async fn main() -> Result<()> {
                     }
                     SecureTunnelNotify::Update(data) => {
                         info!("Update = {:?}", data);
-                        proxy.delete_tunnel(&data.tunnel_id).await.ok();
-                        proxy.add_tunnel(data.into()).await.ok();
-                        // update_tunnel 暂不可用
-                        // proxy.update_tunnel(data.into()).await.ok();
+                        proxy.update_tunnel(data.into()).await.ok();
                     }
                     SecureTunnelNotify::Close(data) => {
                         info!("Close = {:?}", data);
@@ -40,6 +51,19 @@ async fn main() -> Result<()> {
                     }
                 }
             }
+            Ok(event) = events.recv() => {
+                info!("Event = {:?}", event);
+                match event {
+                    Event::Expired(_) => {
+                        // token 即将过期，请求新的认证信息，云端以 Update 应答
+                        ra.proxy_request().await.ok();
+                    }
+                    Event::Audit(record) => {
+                        log_post.post(vec![record.into()]).await.ok();
+                    }
+                    _ => {}
+                }
+            }
         }
     }
 }
@@ -1,17 +1,21 @@
 use super::alink_topic::ALinkSubscribeTopic;
+use super::channel::{Channel, Incoming};
+use super::queue::{queue, OverflowPolicy, QueueReceiver, QueueSender};
+use super::record::{Direction, TrafficRecorder};
+use crate::util::clock::{default_clock, Clock};
 use crate::Error;
 use crate::ThreeTuple;
 use crate::{mqtt::MqttConnection, Result};
 use enum_iterator::IntoEnumIterator;
 use enum_kinds::EnumKind;
+use futures_util::StreamExt;
 use lazy_static::__Deref;
 use log::debug;
-use rumqttc::{AsyncClient, QoS};
+use rumqttc::AsyncClient;
 use serde::Serialize;
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
 
 pub trait ModuleRecvKind: IntoEnumIterator {
     type Recv;
@@ -39,50 +43,142 @@ pub trait ModuleRecvKind: IntoEnumIterator {
     fn get_topic(&self) -> ALinkSubscribeTopic;
 }
 
-pub struct AiotModule<TRecv, O = ()> {
-    pub rx: Receiver<TRecv>,
-    pub client: Arc<AsyncClient>,
+pub struct AiotModule<TRecv, O = (), C = AsyncClient> {
+    pub rx: QueueReceiver<TRecv>,
+    pub client: Arc<C>,
     pub three: Arc<ThreeTuple>,
     pub data: O,
+    pub recorder: Option<Arc<TrafficRecorder>>,
+    pub clock: Arc<dyn Clock>,
+    /// 连接是否在线，见 [`ChannelConnection::set_online`]
+    pub online: watch::Receiver<bool>,
 }
 
-impl<TRecv, O> AiotModule<TRecv, O> {
+impl<TRecv, O, C: Channel> AiotModule<TRecv, O, C> {
     pub async fn sub_all<RecvKind>(&self) -> Result<()>
     where
         RecvKind: ModuleRecvKind,
     {
         let two = format!("{}/{}", self.three.product_key, self.three.device_name);
-        let mut client = self.client.clone();
-        let mut topics = rumqttc::Subscribe::empty_subscribe();
-        for item in RecvKind::into_enum_iter() {
-            let topic = item.get_topic();
-            let topic = topic.topic.replace("+/+", &two);
-            topics.add(topic, QoS::AtMostOnce);
-        }
-        client.subscribe_many(topics.filters).await?;
-        Ok(())
+        let topics = RecvKind::into_enum_iter()
+            .map(|item| item.get_topic().topic.replace("+/+", &two))
+            .collect();
+        self.client.subscribe(topics).await
     }
 }
 
-impl MqttConnection {
+/// 基于任意 [`Channel`] 的连接，负责创建模块并把下行消息分发给各模块。
+///
+/// [`MqttConnection`] 可以解引用为 `ChannelConnection<AsyncClient>`。
+pub struct ChannelConnection<C = AsyncClient> {
+    pub three: Arc<ThreeTuple>,
+    pub channel: Arc<C>,
+    pub(crate) executors: Vec<Box<dyn crate::Executor + Send + Sync>>,
+    pub recorder: Option<Arc<TrafficRecorder>>,
+    pub clock: Arc<dyn Clock>,
+    /// 模块接收队列满时的处理方式
+    pub overflow_policy: OverflowPolicy,
+    pub(crate) online: Arc<watch::Sender<bool>>,
+    incoming: Option<Incoming>,
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    pub fn new(three: &ThreeTuple, channel: C) -> Self {
+        Self::from_arc(Arc::new(three.clone()), Arc::new(channel))
+    }
+
+    pub fn from_arc(three: Arc<ThreeTuple>, channel: Arc<C>) -> Self {
+        let incoming = channel.incoming();
+        Self {
+            three,
+            channel,
+            executors: Vec::new(),
+            recorder: None,
+            clock: default_clock(),
+            overflow_policy: OverflowPolicy::default(),
+            online: Arc::new(watch::channel(true).0),
+            incoming,
+        }
+    }
+
+    /// 录制收发的消息，只对之后创建的模块生效
+    pub fn set_recorder(&mut self, recorder: Arc<TrafficRecorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// 设置模块使用的时钟，只对之后创建的模块生效
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// 设置模块接收队列满时的处理方式，只对之后创建的模块生效。
+    ///
+    /// 默认的 [`OverflowPolicy::Block`] 下，任何一个模块不读取都会让 [`MqttConnection::poll`]
+    /// 停在分发上，所有模块都收不到新消息；不一定及时读取的模块应使用丢弃策略。
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    /// 更新连接状态，模块可以通过 [`AiotModule::online`] 得知断线和重连。
+    ///
+    /// [`MqttConnection::poll`] 在收到 CONNACK 和连接出错时自动更新，其他通道默认在线。
+    pub fn set_online(&self, online: bool) {
+        self.online
+            .send_if_modified(|value| std::mem::replace(value, online) != online);
+    }
+
+    pub fn is_online(&self) -> bool {
+        *self.online.borrow()
+    }
+
+    /// 创建模块的接收队列
+    pub fn queue<T>(&self, capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+        queue(capacity, self.overflow_policy)
+    }
+
     pub fn module<TModuleRecv, O>(
         &mut self,
         executor: Box<dyn crate::Executor + Send + Sync>,
-        rx: Receiver<TModuleRecv>,
+        rx: QueueReceiver<TModuleRecv>,
         data: O,
-    ) -> Result<AiotModule<TModuleRecv, O>> {
-        self.mqtt_client.executors.push(executor);
-        let runner = AiotModule::<TModuleRecv, O> {
+    ) -> Result<AiotModule<TModuleRecv, O, C>> {
+        self.executors.push(executor);
+        let runner = AiotModule::<TModuleRecv, O, C> {
             rx,
-            three: self.mqtt_client.three.clone(),
-            client: self.mqtt.clone(),
+            three: self.three.clone(),
+            client: self.channel.clone(),
             data,
+            recorder: self.recorder.clone(),
+            clock: self.clock.clone(),
+            online: self.online.subscribe(),
         };
         Ok(runner)
     }
+
+    /// 把一条下行消息依次交给所有模块处理
+    pub async fn dispatch(&mut self, topic: &str, payload: &[u8]) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&*self.clock, Direction::Down, topic, payload);
+        }
+        for e in &mut self.executors {
+            if let Err(err) = e.execute(topic, payload).await {
+                debug!("{} error: {}", topic, err);
+            }
+        }
+    }
+
+    /// 从通道的下行消息流中取出一条消息并分发。
+    ///
+    /// 通道没有提供下行消息流（如 rumqttc，应使用 [`MqttConnection::poll`]）或消息流结束时返回错误。
+    pub async fn poll(&mut self) -> Result<(String, Vec<u8>)> {
+        let incoming = self.incoming.as_mut().ok_or(Error::EventLoopError)?;
+        let (topic, payload) = incoming.next().await.ok_or(Error::EventLoopError)?;
+        self.dispatch(&topic, &payload).await;
+        Ok((topic, payload))
+    }
 }
 
-impl<TRecv, O> AiotModule<TRecv, O> {
+impl<TRecv, O, C: Channel> AiotModule<TRecv, O, C> {
     pub async fn publish<T>(&self, topic: String, payload: &T) -> Result<()>
     where
         T: ?Sized + Serialize,
@@ -93,13 +189,12 @@ impl<TRecv, O> AiotModule<TRecv, O> {
 
     pub async fn publish_raw(&self, topic: String, payload: Vec<u8>) -> Result<()> {
         debug!("publish: {} {}", topic, String::from_utf8_lossy(&payload));
-        if let Err(err) = self
-            .client
-            .publish(topic, QoS::AtMostOnce, false, payload)
-            .await
-        {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&*self.clock, Direction::Up, &topic, &payload);
+        }
+        if let Err(err) = self.client.publish(topic, payload).await {
             log::error!("publish error: {}", err);
-            return Err(err.into());
+            return Err(err);
         }
         Ok(())
     }
@@ -107,6 +202,16 @@ impl<TRecv, O> AiotModule<TRecv, O> {
     pub async fn poll(&mut self) -> Result<TRecv> {
         self.rx.recv().await.ok_or(Error::RecvTopicError)
     }
+
+    /// 修改接收队列满时的处理方式
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.rx.set_policy(policy);
+    }
+
+    /// 取出接收队列，例如交给 [`EventStream`](super::event::EventStream)，之后 `poll` 返回错误
+    pub fn take_receiver(&mut self) -> QueueReceiver<TRecv> {
+        std::mem::replace(&mut self.rx, QueueReceiver::closed())
+    }
 }
 
 pub fn get_aiot_json(payload: &[u8]) -> String {
@@ -43,3 +43,36 @@ impl ALinkSubscribeTopic {
         None
     }
 }
+
+/// 判断 topic 是否匹配 MQTT 订阅过滤器，支持 `+` 和 `#` 通配符。
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[test]
+fn test_topic_matches() {
+    assert!(topic_matches(
+        "/sys/pk/dn/thing/event/+/post_reply",
+        "/sys/pk/dn/thing/event/property/post_reply"
+    ));
+    assert!(topic_matches(
+        "/ext/rrpc/+/sys/pk/dn/thing/service/+",
+        "/ext/rrpc/1/sys/pk/dn/thing/service/s"
+    ));
+    assert!(topic_matches("/sys/#", "/sys/pk/dn/thing"));
+    assert!(!topic_matches(
+        "/sys/pk/dn/thing/service/+",
+        "/sys/pk/dn/thing/service/a/b"
+    ));
+    assert!(!topic_matches("/sys/pk/dn", "/sys/pk/dn/thing"));
+}
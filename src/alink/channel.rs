@@ -0,0 +1,165 @@
+//! 消息通道。
+//!
+//! 各个模块通过 [`Channel`] 发布和订阅消息，默认实现为 rumqttc 的 [`AsyncClient`]。
+
+use crate::{Error, Result};
+use futures::stream::BoxStream;
+use rumqttc::{AsyncClient, QoS};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// 下行消息流，每一项为 `(topic, payload)`。
+pub type Incoming = BoxStream<'static, Item>;
+
+type Item = (String, Vec<u8>);
+
+#[async_trait::async_trait]
+pub trait Channel: Send + Sync + 'static {
+    /// 发布消息
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<()>;
+
+    /// 订阅一组 topic
+    async fn subscribe(&self, topics: Vec<String>) -> Result<()>;
+
+    /// 取出下行消息流，只能取出一次。
+    ///
+    /// 由连接自行驱动下行数据的通道（如 rumqttc 的 `EventLoop`）返回 `None`。
+    fn incoming(&self) -> Option<Incoming> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for AsyncClient {
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<()> {
+        AsyncClient::publish(self, topic, QoS::AtMostOnce, false, payload).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topics: Vec<String>) -> Result<()> {
+        let mut filters = rumqttc::Subscribe::empty_subscribe();
+        for topic in topics {
+            filters.add(topic, QoS::AtMostOnce);
+        }
+        self.subscribe_many(filters.filters).await?;
+        Ok(())
+    }
+}
+
+/// 内存中的回环通道，用于测试或在进程内对接其他传输方式。
+///
+/// 模块发布的消息由 [`LoopbackPeer::recv`] 取出，[`LoopbackPeer::send`] 注入的消息作为下行消息分发给模块。
+pub struct LoopbackChannel {
+    up_tx: mpsc::UnboundedSender<Item>,
+    down_rx: Mutex<Option<mpsc::UnboundedReceiver<Item>>>,
+    subscriptions: Mutex<Vec<String>>,
+}
+
+/// 回环通道的对端
+pub struct LoopbackPeer {
+    up_rx: mpsc::UnboundedReceiver<Item>,
+    down_tx: mpsc::UnboundedSender<Item>,
+}
+
+impl LoopbackChannel {
+    pub fn pair() -> (Self, LoopbackPeer) {
+        let (up_tx, up_rx) = mpsc::unbounded_channel();
+        let (down_tx, down_rx) = mpsc::unbounded_channel();
+        let channel = Self {
+            up_tx,
+            down_rx: Mutex::new(Some(down_rx)),
+            subscriptions: Mutex::new(Vec::new()),
+        };
+        (channel, LoopbackPeer { up_rx, down_tx })
+    }
+
+    /// 已经订阅的 topic
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.subscriptions
+            .lock()
+            .map(|s| s.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for LoopbackChannel {
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<()> {
+        self.up_tx
+            .send((topic, payload))
+            .map_err(|_| Error::MpscSendError)
+    }
+
+    async fn subscribe(&self, topics: Vec<String>) -> Result<()> {
+        self.subscriptions
+            .lock()
+            .map_err(|_| Error::Lock)?
+            .extend(topics);
+        Ok(())
+    }
+
+    fn incoming(&self) -> Option<Incoming> {
+        let rx = self.down_rx.lock().ok()?.take()?;
+        Some(Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })))
+    }
+}
+
+impl LoopbackPeer {
+    /// 取出模块发布的下一条消息
+    pub async fn recv(&mut self) -> Option<Item> {
+        self.up_rx.recv().await
+    }
+
+    /// 取出模块发布的消息，没有则立即返回 `None`
+    pub fn try_recv(&mut self) -> Option<Item> {
+        self.up_rx.try_recv().ok()
+    }
+
+    /// 注入一条下行消息
+    pub fn send(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.down_tx
+            .send((topic.to_string(), payload.to_vec()))
+            .map_err(|_| Error::MpscSendError)
+    }
+}
+
+#[tokio::test]
+async fn test_loopback_data_model() {
+    use crate::dm::recv::RecvEnum;
+    use crate::{ChannelConnection, DataModelMsg, DataModelOptions, ThreeTuple};
+
+    let three = ThreeTuple {
+        product_key: "pk".to_string(),
+        device_name: "dn".to_string(),
+        device_secret: "ds".to_string(),
+    };
+    let (channel, mut peer) = LoopbackChannel::pair();
+    let mut conn = ChannelConnection::new(&three, channel);
+    let mut dm = conn.data_model(DataModelOptions::new()).unwrap();
+    dm.init().await.unwrap();
+    assert!(conn
+        .channel
+        .subscriptions()
+        .contains(&"/sys/pk/dn/thing/service/property/set".to_string()));
+
+    dm.send(DataModelMsg::property_post(
+        serde_json::json!({"LightSwitch": 1}),
+    ))
+    .await
+    .unwrap();
+    let (topic, _) = peer.recv().await.unwrap();
+    assert_eq!(topic, "/sys/pk/dn/thing/event/property/post");
+
+    peer.send(
+        "/sys/pk/dn/thing/service/property/set",
+        br#"{"id":"7","version":"1.0","params":{"LightSwitch":0},"method":"thing.service.property.set"}"#,
+    )
+    .unwrap();
+    conn.poll().await.unwrap();
+    match dm.poll().await.unwrap() {
+        RecvEnum::ServicePropertySet(data) => assert_eq!(data.msg_id, 7),
+        other => panic!("{:?}", other),
+    }
+}
@@ -0,0 +1,173 @@
+//! 合并所有模块下行消息的事件流。
+//!
+//! 模块的接收队列加入 [`EventStream`] 后，所有模块的消息从同一个流中读出，
+//! 不需要为每个模块单独读取。同一个模块的消息保持到达顺序，
+//! 不同模块的消息轮流读出，不保证与到达连接的先后顺序一致。
+
+use super::aiot_module::AiotModule;
+use super::channel::Channel;
+use super::queue::QueueReceiver;
+use futures_util::stream::{BoxStream, SelectAll};
+use futures_util::{Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+macro_rules! aiot_events {
+    ($($(#[$meta:meta])* $name:ident($recv:ty),)*) => {
+        /// 各模块的下行消息
+        #[derive(Debug)]
+        pub enum AiotEvent {
+            $($(#[$meta])* $name($recv),)*
+        }
+
+        $(impl From<$recv> for AiotEvent {
+            fn from(recv: $recv) -> Self {
+                Self::$name(recv)
+            }
+        })*
+    };
+}
+
+aiot_events! {
+    /// 物模型
+    Dm(crate::dm::Recv),
+    /// OTA 升级
+    Ota(crate::ota::Recv),
+    /// 设备影子
+    Shadow(crate::shadow::Recv),
+    /// 子设备管理
+    SubDev(crate::subdev::Recv),
+    /// 设备标签
+    Tag(crate::tag::Recv),
+    /// 远程配置
+    RemoteConfig(crate::remote_config::Recv),
+    /// NTP 时间同步
+    Ntp(crate::ntp::Recv),
+    /// 日志上报
+    LogPost(crate::logpost::Recv),
+    /// 远程访问
+    RemoteAccess(crate::ra::Recv),
+    /// 文件上传
+    File(crate::file::Recv),
+    /// 网络诊断
+    Diag(crate::diag::Recv),
+    /// 设备分发
+    Bootstrap(crate::bootstrap::Recv),
+}
+
+/// 合并多个模块的接收队列，没有加入任何模块时流立即结束
+#[derive(Default)]
+pub struct EventStream {
+    streams: SelectAll<BoxStream<'static, AiotEvent>>,
+}
+
+impl EventStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 加入模块，之后模块的 `poll` 不再收到消息
+    pub fn add<TRecv, O, C>(&mut self, module: &mut AiotModule<TRecv, O, C>)
+    where
+        C: Channel,
+        TRecv: Send + 'static,
+        AiotEvent: From<TRecv>,
+    {
+        self.add_receiver(module.take_receiver());
+    }
+
+    pub fn add_receiver<TRecv>(&mut self, rx: QueueReceiver<TRecv>)
+    where
+        TRecv: Send + 'static,
+        AiotEvent: From<TRecv>,
+    {
+        self.streams.push(rx.map(AiotEvent::from).boxed());
+    }
+
+    /// 等待下一个事件
+    pub async fn recv(&mut self) -> Option<AiotEvent> {
+        self.streams.next().await
+    }
+}
+
+impl Stream for EventStream {
+    type Item = AiotEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<AiotEvent>> {
+        self.streams.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alink::queue::OverflowPolicy;
+    use crate::mock::testing::{drive, three};
+    use crate::mock::MockCloud;
+    use crate::ota::base::PackageData;
+    use crate::ota::recv::OTARecv;
+    use crate::remote_config::recv::{RemoteConfigFileInfo, RemoteConfigRecv};
+    use crate::{DataModelOptions, DeviceAuthInfo, Error};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn 事件流() {
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        // 不读取的模块丢弃最早的消息，不阻塞其他模块
+        conn.set_overflow_policy(OverflowPolicy::DropOldest);
+        let dm = conn.data_model(DataModelOptions::new()).unwrap();
+        dm.init().await.unwrap();
+        conn.set_overflow_policy(OverflowPolicy::Block);
+        let mut ota = conn.ota().unwrap();
+        ota.sub_all::<crate::ota::RecvKind>().await.unwrap();
+        let mut remote_config = conn.remote_config().unwrap();
+        remote_config.init().await.unwrap();
+        let mut events = EventStream::new();
+        events.add(&mut ota);
+        events.add(&mut remote_config);
+
+        for i in 0..100 {
+            cloud.property_set(json!({ "i": i })).unwrap();
+        }
+        let package = PackageData {
+            size: 1,
+            version: "1.0.1".to_string(),
+            is_diff: None,
+            url: "http://127.0.0.1/a.bin".to_string(),
+            md5: None,
+            sign: "".to_string(),
+            sign_method: "Md5".to_string(),
+            module: None,
+            ext_data: None,
+        };
+        cloud.ota_push(package).unwrap();
+        cloud
+            .config_push(RemoteConfigFileInfo {
+                config_id: "c1".to_string(),
+                config_size: 1,
+                sign: "".to_string(),
+                sign_method: "Sha256".to_string(),
+                url: "http://127.0.0.1/c1".to_string(),
+                get_type: "file".to_string(),
+            })
+            .unwrap();
+
+        let event = drive(&mut conn, events.recv()).await.unwrap();
+        assert!(matches!(
+            event,
+            AiotEvent::Ota(OTARecv::UpgradePackageRequest(_))
+        ));
+        let event = drive(&mut conn, events.recv()).await.unwrap();
+        assert!(matches!(
+            event,
+            AiotEvent::RemoteConfig(RemoteConfigRecv::RemoteConfigPush(_))
+        ));
+        assert_eq!(dm.rx.len(), 64);
+        assert_eq!(dm.rx.dropped(), 36);
+        // 加入事件流的模块不再单独收到消息
+        assert!(matches!(ota.poll().await, Err(Error::RecvTopicError)));
+    }
+}
@@ -8,6 +8,10 @@ use serde_with::DisplayFromStr;
 
 pub mod aiot_module;
 pub mod alink_topic;
+pub mod channel;
+pub mod event;
+pub mod queue;
+pub mod record;
 
 /// 设备认证三元组。
 ///
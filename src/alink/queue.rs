@@ -0,0 +1,232 @@
+//! 模块的接收队列。
+//!
+//! 连接按顺序把下行消息交给各模块的执行器，执行器再放入模块的接收队列。
+//! 队列满时按 [`OverflowPolicy`] 处理，使用丢弃策略的模块即使一直不读取也不会阻塞连接。
+//! 默认的 [`OverflowPolicy::Block`] 不丢弃消息，但只要有一个模块不读取，
+//! [`MqttConnection::poll`](crate::MqttConnection::poll) 就会停在分发上，所有模块都收不到新消息。
+
+use crate::{Error, Result};
+use futures::task::AtomicWaker;
+use futures_util::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+/// 队列满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// 等待模块读取。队列满时连接停止分发，包括发给其他模块的消息
+    #[default]
+    Block,
+    /// 丢弃最早的消息
+    DropOldest,
+    /// 丢弃新收到的消息
+    DropNewest,
+}
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// 丢弃的消息数
+    dropped: u64,
+    senders: usize,
+    receiver_closed: bool,
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+    /// 唤醒等待消息的接收端
+    waker: AtomicWaker,
+    /// 唤醒等待空位的发送端
+    space: Notify,
+}
+
+/// 创建容量为 `capacity` 的队列
+pub fn queue<T>(capacity: usize, policy: OverflowPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: 0,
+            senders: 1,
+            receiver_closed: false,
+        }),
+        waker: AtomicWaker::new(),
+        space: Notify::new(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    /// 放入一条消息，只有 [`OverflowPolicy::Block`] 会等待；接收端已关闭时返回错误
+    pub async fn send(&self, item: T) -> Result<()> {
+        let mut item = Some(item);
+        loop {
+            let space = self.shared.space.notified();
+            if self.try_push(&mut item)? {
+                self.shared.waker.wake();
+                return Ok(());
+            }
+            space.await;
+        }
+    }
+
+    /// 放入队列或按策略丢弃后返回 true，需要等待空位时返回 false
+    fn try_push(&self, item: &mut Option<T>) -> Result<bool> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.receiver_closed {
+            return Err(Error::MpscSendError);
+        }
+        if inner.queue.len() >= inner.capacity {
+            match inner.policy {
+                OverflowPolicy::Block => return Ok(false),
+                OverflowPolicy::DropOldest => {
+                    inner.queue.pop_front();
+                }
+                OverflowPolicy::DropNewest => {
+                    item.take();
+                }
+            }
+            inner.dropped += 1;
+        }
+        inner.queue.extend(item.take());
+        Ok(true)
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().senders -= 1;
+        self.shared.waker.wake();
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// 没有发送端的队列，读取时立即返回 `None`
+    pub fn closed() -> Self {
+        let (_, receiver) = queue(1, OverflowPolicy::Block);
+        receiver
+    }
+
+    /// 等待下一条消息，所有发送端都已关闭且队列为空时返回 `None`
+    pub async fn recv(&mut self) -> Option<T> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let item = self.shared.inner.lock().unwrap().queue.pop_front();
+        if item.is_some() {
+            self.shared.space.notify_waiters();
+        }
+        item
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if let Some(item) = inner.queue.pop_front() {
+            drop(inner);
+            self.shared.space.notify_waiters();
+            return Poll::Ready(Some(item));
+        }
+        if inner.senders == 0 {
+            return Poll::Ready(None);
+        }
+        self.shared.waker.register(cx.waker());
+        Poll::Pending
+    }
+
+    /// 修改队列满时的处理方式，立即生效
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        self.shared.inner.lock().unwrap().policy = policy;
+        self.shared.space.notify_waiters();
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.shared.inner.lock().unwrap().policy
+    }
+
+    /// 因为队列满丢弃的消息数
+    pub fn dropped(&self) -> u64 {
+        self.shared.inner.lock().unwrap().dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.inner.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().receiver_closed = true;
+        self.shared.space.notify_waiters();
+    }
+}
+
+#[tokio::test]
+async fn test_overflow_policy() {
+    let (tx, mut rx) = queue(2, OverflowPolicy::DropOldest);
+    for i in 0..4 {
+        tx.send(i).await.unwrap();
+    }
+    assert_eq!(rx.dropped(), 2);
+    assert_eq!(rx.try_recv(), Some(2));
+
+    rx.set_policy(OverflowPolicy::DropNewest);
+    tx.send(4).await.unwrap();
+    tx.send(5).await.unwrap();
+    assert_eq!(rx.dropped(), 3);
+    assert_eq!(rx.recv().await, Some(3));
+    assert_eq!(rx.recv().await, Some(4));
+
+    // 队列满时等待读取
+    rx.set_policy(OverflowPolicy::Block);
+    tx.send(6).await.unwrap();
+    tx.send(7).await.unwrap();
+    let send = tokio::spawn(async move { tx.send(8).await });
+    tokio::task::yield_now().await;
+    assert!(!send.is_finished());
+    assert_eq!(rx.recv().await, Some(6));
+    send.await.unwrap().unwrap();
+    assert_eq!(rx.recv().await, Some(7));
+    assert_eq!(rx.recv().await, Some(8));
+    // 发送端全部关闭
+    assert_eq!(rx.recv().await, None);
+}
@@ -0,0 +1,339 @@
+//! 流量录制与回放，用于回归测试。
+//!
+//! [`TrafficRecorder`] 以 JSON Lines 格式记录连接收到的下行消息和模块发布的上行消息，
+//! [`Replay`] 把录制的下行消息重新交给模块处理，并对比模块产生的上行消息与录制时是否一致。
+
+use super::aiot_module::ChannelConnection;
+use super::channel::Channel;
+use crate::util::clock::Clock;
+use crate::util::{hex2str, str2hex};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// 云端下发给设备
+    Down,
+    /// 设备发布到云端
+    Up,
+}
+
+/// 一条录制的消息
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TrafficRecord {
+    /// 录制时间，毫秒时间戳
+    pub time: i64,
+    pub direction: Direction,
+    pub topic: String,
+    /// 负载为 UTF-8 文本时原样保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    /// 负载为二进制时保存为十六进制字符串
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_hex: Option<String>,
+}
+
+impl TrafficRecord {
+    /// `time` 为录制时的 Unix 毫秒时间
+    pub fn new(time: i64, direction: Direction, topic: &str, payload: &[u8]) -> Self {
+        let (text, hex) = match std::str::from_utf8(payload) {
+            Ok(s) => (Some(s.to_string()), None),
+            Err(_) => (None, Some(hex2str(payload))),
+        };
+        Self {
+            time,
+            direction,
+            topic: topic.to_string(),
+            payload: text,
+            payload_hex: hex,
+        }
+    }
+
+    pub fn payload_bytes(&self) -> Vec<u8> {
+        match (&self.payload, &self.payload_hex) {
+            (Some(s), _) => s.as_bytes().to_vec(),
+            (None, Some(hex)) => str2hex(hex),
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+enum Sink {
+    Writer(Box<dyn Write + Send>),
+    Memory(Vec<TrafficRecord>),
+}
+
+/// 流量录制器，通过 [`ChannelConnection::set_recorder`] 设置到连接上。
+///
+/// 录制失败只记录日志，不影响消息收发。
+pub struct TrafficRecorder {
+    sink: Mutex<Sink>,
+}
+
+impl TrafficRecorder {
+    /// 录制到文件，文件已存在时追加
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self::from_writer(Box::new(file)))
+    }
+
+    pub fn from_writer(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            sink: Mutex::new(Sink::Writer(writer)),
+        }
+    }
+
+    /// 录制到内存，通过 [`TrafficRecorder::records`] 取出
+    pub fn memory() -> Self {
+        Self {
+            sink: Mutex::new(Sink::Memory(Vec::new())),
+        }
+    }
+
+    /// 录制一条消息，时间取自连接的 `clock`
+    pub fn record(&self, clock: &dyn Clock, direction: Direction, topic: &str, payload: &[u8]) {
+        let record = TrafficRecord::new(clock.now_millis(), direction, topic, payload);
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(_) => return,
+        };
+        match &mut *sink {
+            Sink::Writer(writer) => {
+                let res = serde_json::to_writer(&mut *writer, &record)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| writer.write_all(b"\n"))
+                    .and_then(|_| writer.flush());
+                if let Err(err) = res {
+                    log::error!("record traffic error: {}", err);
+                }
+            }
+            Sink::Memory(records) => records.push(record),
+        }
+    }
+
+    /// 内存录制的消息，录制到文件时为空
+    pub fn records(&self) -> Vec<TrafficRecord> {
+        match self.sink.lock().as_deref() {
+            Ok(Sink::Memory(records)) => records.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// 回放结果与录制结果的差异
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayDiff {
+    /// 录制时有而回放时没有产生的上行消息
+    Missing(TrafficRecord),
+    /// 回放时多产生的上行消息
+    Unexpected(TrafficRecord),
+    /// 同一位置上 topic 或负载不一致的上行消息
+    Changed {
+        expected: TrafficRecord,
+        actual: TrafficRecord,
+    },
+}
+
+/// 回放录制的流量
+///
+/// # Examples
+///
+/// ```no_run
+/// use aiot::alink::record::{Replay, TrafficRecorder};
+/// use aiot::{ChannelConnection, DataModelOptions, LoopbackChannel, ThreeTuple};
+/// use std::sync::Arc;
+///
+/// # async fn run() -> aiot::Result<()> {
+/// let replay = Replay::load("traffic.jsonl")?;
+/// let recorder = Arc::new(TrafficRecorder::memory());
+/// let (channel, _peer) = LoopbackChannel::pair();
+/// let mut conn = ChannelConnection::new(&ThreeTuple::from_env(), channel);
+/// conn.set_recorder(recorder.clone());
+/// let mut dm = conn.data_model(DataModelOptions::new())?;
+///
+/// replay.feed(&mut conn).await;
+/// let _data = dm.poll().await?;
+/// // 与录制时相同的处理逻辑
+/// assert!(replay.diff(&recorder.records()).is_empty());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Replay {
+    pub records: Vec<TrafficRecord>,
+    ignore: Vec<String>,
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(Self::from_records(records))
+    }
+
+    /// 默认对比时忽略 JSON 负载中的 `id` 字段
+    pub fn from_records(records: Vec<TrafficRecord>) -> Self {
+        Self {
+            records,
+            ignore: vec!["id".to_string()],
+        }
+    }
+
+    /// 对比时忽略 JSON 负载中任意层级的同名字段，如时间戳
+    pub fn ignore_field(mut self, name: &str) -> Self {
+        self.ignore.push(name.to_string());
+        self
+    }
+
+    pub fn downlinks(&self) -> impl Iterator<Item = &TrafficRecord> {
+        self.records
+            .iter()
+            .filter(|r| r.direction == Direction::Down)
+    }
+
+    pub fn uplinks(&self) -> impl Iterator<Item = &TrafficRecord> {
+        self.records.iter().filter(|r| r.direction == Direction::Up)
+    }
+
+    /// 按顺序把录制的下行消息交给连接上的模块处理，返回分发的消息数
+    pub async fn feed<C: Channel>(&self, conn: &mut ChannelConnection<C>) -> usize {
+        let mut count = 0;
+        for record in self.downlinks() {
+            conn.dispatch(&record.topic, &record.payload_bytes()).await;
+            count += 1;
+        }
+        count
+    }
+
+    /// 按顺序对比录制的上行消息和回放产生的消息，`actual` 中的下行消息会被忽略
+    pub fn diff(&self, actual: &[TrafficRecord]) -> Vec<ReplayDiff> {
+        let mut expected = self.uplinks();
+        let mut actual = actual.iter().filter(|r| r.direction == Direction::Up);
+        let mut diffs = Vec::new();
+        loop {
+            match (expected.next(), actual.next()) {
+                (Some(e), Some(a)) => {
+                    if e.topic != a.topic || !self.payload_eq(e, a) {
+                        diffs.push(ReplayDiff::Changed {
+                            expected: e.clone(),
+                            actual: a.clone(),
+                        });
+                    }
+                }
+                (Some(e), None) => diffs.push(ReplayDiff::Missing(e.clone())),
+                (None, Some(a)) => diffs.push(ReplayDiff::Unexpected(a.clone())),
+                (None, None) => break,
+            }
+        }
+        diffs
+    }
+
+    fn payload_eq(&self, expected: &TrafficRecord, actual: &TrafficRecord) -> bool {
+        let e = expected.payload_bytes();
+        let a = actual.payload_bytes();
+        match (
+            serde_json::from_slice::<Value>(&e),
+            serde_json::from_slice::<Value>(&a),
+        ) {
+            (Ok(mut e), Ok(mut a)) => {
+                self.strip(&mut e);
+                self.strip(&mut a);
+                e == a
+            }
+            _ => e == a,
+        }
+    }
+
+    fn strip(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                map.retain(|k, _| !self.ignore.contains(k));
+                map.values_mut().for_each(|v| self.strip(v));
+            }
+            Value::Array(list) => list.iter_mut().for_each(|v| self.strip(v)),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_replay_data_model() {
+    use crate::dm::recv::RecvEnum;
+    use crate::{DataModelOptions, LoopbackChannel, ThreeTuple};
+    use std::sync::Arc;
+
+    let three = ThreeTuple {
+        product_key: "pk".to_string(),
+        device_name: "dn".to_string(),
+        device_secret: "ds".to_string(),
+    };
+    let records = vec![
+        TrafficRecord::new(
+            0,
+            Direction::Down,
+            "/sys/pk/dn/thing/service/property/set",
+            br#"{"id":"7","version":"1.0","params":{"LightSwitch":0},"method":"thing.service.property.set"}"#,
+        ),
+        TrafficRecord::new(
+            0,
+            Direction::Up,
+            "/sys/pk/dn/thing/event/property/post",
+            br#"{"id":"1","version":"1.0","params":{"LightSwitch":0},"sys":{"ack":1},"method":"thing.event.property.post"}"#,
+        ),
+        TrafficRecord::new(0, Direction::Up, "/raw", &[0xFF, 0x00]),
+    ];
+    let replay = Replay::from_records(records);
+
+    let recorder = Arc::new(TrafficRecorder::memory());
+    let (channel, _peer) = LoopbackChannel::pair();
+    let mut conn = ChannelConnection::new(&three, channel);
+    conn.set_recorder(recorder.clone());
+    conn.set_clock(crate::util::clock::ManualClock::new(1_000));
+    let mut dm = conn.data_model(DataModelOptions::new()).unwrap();
+
+    assert_eq!(replay.feed(&mut conn).await, 1);
+    match dm.poll().await.unwrap() {
+        RecvEnum::ServicePropertySet(data) => {
+            dm.send(crate::DataModelMsg::property_post(data.params))
+                .await
+                .unwrap();
+        }
+        other => panic!("{:?}", other),
+    }
+    dm.publish_raw("/raw".to_string(), vec![0xFF, 0x00])
+        .await
+        .unwrap();
+
+    let actual = recorder.records();
+    assert_eq!(actual[0].direction, Direction::Down);
+    // 录制时间来自连接的时钟
+    assert!(actual.iter().all(|record| record.time == 1_000));
+    assert_eq!(actual[2].payload_hex.as_deref(), Some("FF00"));
+    assert_eq!(replay.diff(&actual), vec![]);
+
+    let changed = Replay::from_records(vec![TrafficRecord::new(
+        0,
+        Direction::Up,
+        "/sys/pk/dn/thing/event/property/post",
+        br#"{"id":"1","params":{"LightSwitch":1}}"#,
+    )]);
+    assert!(matches!(
+        changed.diff(&actual)[..],
+        [ReplayDiff::Changed { .. }, ReplayDiff::Unexpected(_)]
+    ));
+}
@@ -1,3 +1,4 @@
+use crate::alink::channel::Channel;
 use std::fs;
 
 use crate::alink::{global_id_next, AlinkRequest, AlinkResponse, SysAck, ALINK_VERSION};
@@ -6,7 +7,7 @@ use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 设备分发通知响应
     pub async fn notify_reply(&self, id: String, code: u64) -> crate::Result<()> {
         let payload = AlinkResponse::<()> {
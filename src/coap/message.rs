@@ -0,0 +1,304 @@
+//! CoAP 报文编解码，参考 [RFC 7252](https://www.rfc-editor.org/rfc/rfc7252)。
+
+use super::CoapError;
+use std::fmt;
+
+type Result<T> = core::result::Result<T, CoapError>;
+
+/// 报文头中的版本号，固定为 1
+pub const VERSION: u8 = 1;
+/// 选项 Uri-Host
+pub const OPTION_URI_HOST: u16 = 3;
+/// 选项 Uri-Port
+pub const OPTION_URI_PORT: u16 = 7;
+/// 选项 Uri-Path，每一段路径对应一个选项
+pub const OPTION_URI_PATH: u16 = 11;
+/// 选项 Content-Format
+pub const OPTION_CONTENT_FORMAT: u16 = 12;
+/// 选项 Accept
+pub const OPTION_ACCEPT: u16 = 17;
+/// 阿里云自定义选项，认证后返回的 token
+pub const OPTION_AUTH_TOKEN: u16 = 2088;
+/// 阿里云自定义选项，对称加密方式下经过加密的 seq
+pub const OPTION_SEQ: u16 = 2089;
+/// Content-Format: application/json
+pub const CONTENT_FORMAT_JSON: u16 = 50;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// 需要对端确认的报文
+    Confirmable = 0,
+    /// 不需要确认的报文
+    NonConfirmable = 1,
+    /// 确认报文
+    Acknowledgement = 2,
+    /// 拒绝报文
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Self {
+        match value & 0x03 {
+            0 => Self::Confirmable,
+            1 => Self::NonConfirmable,
+            2 => Self::Acknowledgement,
+            _ => Self::Reset,
+        }
+    }
+}
+
+/// 请求方法或响应码，高 3 位为类别，低 5 位为详情，例如 `2.05`。
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Code(pub u8);
+
+impl Code {
+    pub const EMPTY: Code = Code(0x00);
+    pub const GET: Code = Code(0x01);
+    pub const POST: Code = Code(0x02);
+    pub const CONTENT: Code = Code::new(2, 5);
+    pub const UNAUTHORIZED: Code = Code::new(4, 1);
+    pub const FORBIDDEN: Code = Code::new(4, 3);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self((class << 5) | (detail & 0x1F))
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.0 & 0x1F
+    }
+
+    /// 是否为 2.xx 成功响应
+    pub fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.class(), self.detail())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub r#type: MessageType,
+    pub code: Code,
+    pub message_id: u16,
+    /// 0~8 字节，用于匹配请求和响应
+    pub token: Vec<u8>,
+    /// 选项编号和值
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(r#type: MessageType, code: Code, message_id: u16, token: Vec<u8>) -> Self {
+        Self {
+            r#type,
+            code,
+            message_id,
+            token,
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// 对可确认报文的空确认
+    pub fn ack(&self) -> Self {
+        Self::new(
+            MessageType::Acknowledgement,
+            Code::EMPTY,
+            self.message_id,
+            Vec::new(),
+        )
+    }
+
+    pub fn add_option(&mut self, number: u16, value: impl Into<Vec<u8>>) {
+        self.options.push((number, value.into()));
+    }
+
+    /// 以最短的字节数写入整数选项
+    pub fn add_uint_option(&mut self, number: u16, value: u32) {
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        self.add_option(number, bytes[skip..].to_vec());
+    }
+
+    /// 按 `/` 拆分路径，逐段写入 Uri-Path 选项
+    pub fn set_uri_path(&mut self, path: &str) {
+        self.options.retain(|(n, _)| *n != OPTION_URI_PATH);
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            self.add_option(OPTION_URI_PATH, segment.as_bytes());
+        }
+    }
+
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn uri_path(&self) -> String {
+        let segments: Vec<String> = self
+            .options
+            .iter()
+            .filter(|(n, _)| *n == OPTION_URI_PATH)
+            .map(|(_, v)| String::from_utf8_lossy(v).to_string())
+            .collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        if self.token.len() > 8 {
+            return Err(CoapError::FormatError(format!(
+                "token 长度 {} 超过 8",
+                self.token.len()
+            )));
+        }
+        let mut buf = Vec::with_capacity(4 + self.token.len() + self.payload.len() + 64);
+        buf.push((VERSION << 6) | ((self.r#type as u8) << 4) | self.token.len() as u8);
+        buf.push(self.code.0);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&self.token);
+
+        let mut options = self.options.clone();
+        // 选项必须按编号升序排列，同编号保持原有顺序
+        options.sort_by_key(|(n, _)| *n);
+        let mut last = 0u16;
+        for (number, value) in &options {
+            let delta = number - last;
+            last = *number;
+            let (delta_nibble, delta_ext) = Self::encode_nibble(delta as usize);
+            let (len_nibble, len_ext) = Self::encode_nibble(value.len());
+            buf.push((delta_nibble << 4) | len_nibble);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&len_ext);
+            buf.extend_from_slice(value);
+        }
+        if !self.payload.is_empty() {
+            buf.push(0xFF);
+            buf.extend_from_slice(&self.payload);
+        }
+        Ok(buf)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(CoapError::FormatError("长度不够".into()));
+        }
+        let version = bytes[0] >> 6;
+        if version != VERSION {
+            return Err(CoapError::FormatError(format!("版本号错误 {}", version)));
+        }
+        let r#type = MessageType::from_u8(bytes[0] >> 4);
+        let tkl = (bytes[0] & 0x0F) as usize;
+        if tkl > 8 || bytes.len() < 4 + tkl {
+            return Err(CoapError::FormatError(format!("token 长度错误 {}", tkl)));
+        }
+        let code = Code(bytes[1]);
+        let message_id = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let token = bytes[4..4 + tkl].to_vec();
+
+        let mut options = Vec::new();
+        let mut payload = Vec::new();
+        let mut pos = 4 + tkl;
+        let mut number = 0u16;
+        while pos < bytes.len() {
+            let byte = bytes[pos];
+            pos += 1;
+            if byte == 0xFF {
+                if pos == bytes.len() {
+                    return Err(CoapError::FormatError("负载标记后没有数据".into()));
+                }
+                payload = bytes[pos..].to_vec();
+                break;
+            }
+            let delta = Self::decode_nibble(byte >> 4, bytes, &mut pos)?;
+            let len = Self::decode_nibble(byte & 0x0F, bytes, &mut pos)?;
+            if pos + len > bytes.len() {
+                return Err(CoapError::FormatError("选项长度越界".into()));
+            }
+            number = number
+                .checked_add(delta as u16)
+                .ok_or_else(|| CoapError::FormatError("选项编号溢出".into()))?;
+            options.push((number, bytes[pos..pos + len].to_vec()));
+            pos += len;
+        }
+        Ok(Self {
+            r#type,
+            code,
+            message_id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    fn encode_nibble(value: usize) -> (u8, Vec<u8>) {
+        if value < 13 {
+            (value as u8, Vec::new())
+        } else if value < 269 {
+            (13, vec![(value - 13) as u8])
+        } else {
+            (14, ((value - 269) as u16).to_be_bytes().to_vec())
+        }
+    }
+
+    fn decode_nibble(nibble: u8, bytes: &[u8], pos: &mut usize) -> Result<usize> {
+        match nibble {
+            13 => {
+                let v = *bytes
+                    .get(*pos)
+                    .ok_or_else(|| CoapError::FormatError("扩展选项长度不够".into()))?;
+                *pos += 1;
+                Ok(v as usize + 13)
+            }
+            14 => {
+                if *pos + 2 > bytes.len() {
+                    return Err(CoapError::FormatError("扩展选项长度不够".into()));
+                }
+                let v = u16::from_be_bytes([bytes[*pos], bytes[*pos + 1]]);
+                *pos += 2;
+                Ok(v as usize + 269)
+            }
+            15 => Err(CoapError::FormatError("保留的选项值 15".into())),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+#[test]
+fn test_message_build() {
+    let mut msg = Message::new(
+        MessageType::Confirmable,
+        Code::POST,
+        0x1234,
+        vec![1, 2, 3, 4],
+    );
+    msg.set_uri_path("/topic/sys/pk/dn/thing/event/property/post");
+    msg.add_uint_option(OPTION_CONTENT_FORMAT, CONTENT_FORMAT_JSON as u32);
+    msg.add_option(OPTION_AUTH_TOKEN, "token".as_bytes());
+    msg.add_option(OPTION_SEQ, vec![0xAB; 300]);
+    msg.payload = b"{}".to_vec();
+    let bytes = msg.to_vec().unwrap();
+    let parsed = Message::from_slice(&bytes).unwrap();
+    assert_eq!(parsed.r#type, MessageType::Confirmable);
+    assert_eq!(parsed.code, Code::POST);
+    assert_eq!(parsed.message_id, 0x1234);
+    assert_eq!(parsed.token, vec![1, 2, 3, 4]);
+    assert_eq!(
+        parsed.uri_path(),
+        "/topic/sys/pk/dn/thing/event/property/post"
+    );
+    assert_eq!(parsed.option(OPTION_CONTENT_FORMAT), Some(&[50u8][..]));
+    assert_eq!(parsed.option(OPTION_AUTH_TOKEN), Some("token".as_bytes()));
+    assert_eq!(parsed.option(OPTION_SEQ).map(|v| v.len()), Some(300));
+    assert_eq!(parsed.payload, b"{}".to_vec());
+    assert_eq!(Code::CONTENT.to_string(), "2.05");
+}
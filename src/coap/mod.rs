@@ -0,0 +1,619 @@
+//! CoAP 接入。
+//!
+//! 支持阿里云物联网平台的两种 CoAP 接入方式：
+//!
+//! - 对称加密方式：明文 UDP 连接 5682 端口，认证后使用 AES-128-CBC 加密上行数据。
+//! - DTLS 方式：连接 5684 端口，由调用者提供实现了 [`CoapTransport`] 的 DTLS 数据报通道。
+//!
+//! 参考 [CoAP连接通信](https://help.aliyun.com/document_detail/57697.html)。
+
+use crate::util::auth;
+use crate::util::clock::{default_clock, Clock};
+use crate::{DataModelMsg, ThreeTuple};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use self::message::*;
+
+pub mod message;
+
+type Result<T> = core::result::Result<T, CoapError>;
+
+/// 对称加密方式使用的 AES 初始向量
+const AES_IV: &[u8; 16] = b"543yhjy97ae7fyfg";
+/// 单个 CoAP 报文的最大长度
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// CoAP 数据报通道。
+///
+/// 对称加密方式直接使用 UDP；DTLS 方式需要由调用者基于自己的 DTLS 实现提供。
+#[async_trait::async_trait]
+pub trait CoapTransport: Send + Sync {
+    /// 发送一个完整的数据报
+    async fn send(&self, data: &[u8]) -> std::io::Result<()>;
+    /// 接收一个完整的数据报，返回长度
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize>;
+}
+
+#[async_trait::async_trait]
+impl CoapTransport for UdpSocket {
+    async fn send(&self, data: &[u8]) -> std::io::Result<()> {
+        UdpSocket::send(self, data).await.map(|_| ())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        UdpSocket::recv(self, buf).await
+    }
+}
+
+/// CoAP 接入方式
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CoapMode {
+    /// 对称加密方式，默认端口 5682
+    Aes,
+    /// DTLS 方式，默认端口 5684
+    Dtls,
+}
+
+/// CoAP 通道设置
+#[derive(Debug, Clone)]
+pub struct CoapOptions {
+    /// token 的有效时长，单位秒。超过该时长后会在发送前重新认证。
+    pub token_lifetime: u64,
+    /// 等待确认报文的初始超时时间，之后每次重传翻倍
+    pub ack_timeout: Duration,
+    /// 最大重传次数
+    pub max_retransmit: u32,
+    /// 收到空确认后，等待单独响应的超时时间
+    pub response_timeout: Duration,
+    /// token 有效期和签名时间戳使用的时钟
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for CoapOptions {
+    fn default() -> Self {
+        Self {
+            token_lifetime: 47 * 3600,
+            ack_timeout: Duration::from_secs(2),
+            max_retransmit: 4,
+            response_timeout: Duration::from_secs(30),
+            clock: default_clock(),
+        }
+    }
+}
+
+pub struct Coap {
+    pub host: String,
+    pub three: ThreeTuple,
+    pub mode: CoapMode,
+    pub token: Option<String>,
+    /// 获取 token 时时钟的单调时间
+    pub token_time: Duration,
+    pub options: CoapOptions,
+    transport: Box<dyn CoapTransport>,
+    /// 对称加密方式认证后得到的 AES 密钥
+    key: Option<[u8; 16]>,
+    seq: u64,
+    message_id: u16,
+}
+
+impl Coap {
+    /// 使用对称加密方式接入，`host` 形如 `${ProductKey}.coap.cn-shanghai.link.aliyuncs.com`。
+    pub async fn new_aes(host: &str, three: &ThreeTuple) -> crate::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect((host, 5682)).await?;
+        Ok(Self::with_transport(
+            host,
+            three,
+            CoapMode::Aes,
+            Box::new(socket),
+        ))
+    }
+
+    /// 使用 DTLS 方式接入，`transport` 为已经完成握手、连接到 `host:5684` 的 DTLS 通道。
+    pub fn new_dtls(host: &str, three: &ThreeTuple, transport: Box<dyn CoapTransport>) -> Self {
+        Self::with_transport(host, three, CoapMode::Dtls, transport)
+    }
+
+    pub fn with_transport(
+        host: &str,
+        three: &ThreeTuple,
+        mode: CoapMode,
+        transport: Box<dyn CoapTransport>,
+    ) -> Self {
+        Self {
+            host: host.to_string(),
+            three: three.clone(),
+            mode,
+            token: None,
+            token_time: Duration::ZERO,
+            options: CoapOptions::default(),
+            transport,
+            key: None,
+            seq: 0,
+            message_id: crate::util::rand_u64() as u16,
+        }
+    }
+
+    /// 设备认证，获取 token。对称加密方式下同时根据返回的 `random` 计算 AES 密钥。
+    pub async fn auth(&mut self) -> crate::Result<()> {
+        let timestamp = self.options.clock.now_millis().to_string();
+        let seq = match self.mode {
+            CoapMode::Aes => Some((crate::util::rand_u64() % 10000).to_string()),
+            CoapMode::Dtls => None,
+        };
+        let body = CoapAuthBody {
+            product_key: self.three.product_key.to_string(),
+            device_name: self.three.device_name.to_string(),
+            client_id: auth::coap::client_id(&self.three.product_key, &self.three.device_name),
+            sign: auth::coap::sign(
+                &self.three.product_key,
+                &self.three.device_name,
+                &self.three.device_secret,
+                seq.as_deref(),
+                &timestamp,
+            ),
+            signmethod: auth::SIGN_METHOD.to_string(),
+            timestamp,
+            seq,
+        };
+        debug!("{}", serde_json::to_string(&body)?);
+        let mut msg = self.new_request("/auth");
+        msg.add_uint_option(OPTION_ACCEPT, CONTENT_FORMAT_JSON as u32);
+        msg.payload = serde_json::to_vec(&body)?;
+        let res = self.request(msg).await?;
+        if !res.code.is_success() {
+            return Err(CoapError::AuthCheckError(res.code.to_string()).into());
+        }
+        let res: CoapAuthResponse = serde_json::from_slice(&res.payload)?;
+        debug!("{:?}", res);
+        if self.mode == CoapMode::Aes {
+            let random = res.random.as_deref().ok_or(CoapError::ParseError)?;
+            self.key = Some(aes_key(&self.three.device_secret, random));
+            self.seq = res.seq_offset.unwrap_or(1);
+        }
+        self.token = Some(res.token);
+        self.token_time = self.options.clock.monotonic();
+        Ok(())
+    }
+
+    /// token 不存在或已超过有效时长
+    pub fn token_expired(&self) -> bool {
+        self.token.is_none()
+            || self.options.clock.monotonic()
+                >= self.token_time + Duration::from_secs(self.options.token_lifetime)
+    }
+
+    /// 上报数据到指定 topic，返回云端响应的负载（对称加密方式下已解密）。
+    ///
+    /// 没有 token 或 token 过期时会自动调用 [`Coap::auth`]，云端返回 4.01/4.03 时重新认证后重发一次。
+    pub async fn send(&mut self, topic: &str, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let mut retried = false;
+        loop {
+            if self.token_expired() {
+                self.auth().await?;
+            }
+            match self.send_once(topic, data).await {
+                Err(crate::Error::CoapError(CoapError::Unauthorized(code))) if !retried => {
+                    debug!("token 失效 {}，重新认证", code);
+                    self.token = None;
+                    retried = true;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// 通过 CoAP 发送物模型消息。
+    ///
+    /// CoAP 通道没有下行消息，所以消息总是以不需要云端应答的方式发送。
+    pub async fn send_dm(&mut self, data: DataModelMsg) -> crate::Result<Vec<u8>> {
+        let mut data = data;
+        if data.product_key.is_none() {
+            data.product_key = Some(self.three.product_key.to_string());
+        }
+        if data.device_name.is_none() {
+            data.device_name = Some(self.three.device_name.to_string());
+        }
+        let (topic, payload) = data.to_payload(0)?;
+        self.send(&topic, &payload).await
+    }
+
+    async fn send_once(&mut self, topic: &str, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let token = self.token.clone().ok_or(CoapError::TokenIsNull)?;
+        let mut msg = self.new_request(&format!("/topic{}", topic));
+        msg.add_uint_option(OPTION_ACCEPT, CONTENT_FORMAT_JSON as u32);
+        msg.add_option(OPTION_AUTH_TOKEN, token.as_bytes());
+        match self.key {
+            Some(key) => {
+                self.seq += 1;
+                msg.add_option(
+                    OPTION_SEQ,
+                    aes_encrypt(&key, self.seq.to_string().as_bytes()),
+                );
+                msg.payload = aes_encrypt(&key, data);
+            }
+            None => msg.payload = data.to_vec(),
+        }
+        let res = self.request(msg).await?;
+        if res.code == Code::UNAUTHORIZED || res.code == Code::FORBIDDEN {
+            return Err(CoapError::Unauthorized(res.code.to_string()).into());
+        }
+        if !res.code.is_success() {
+            return Err(CoapError::ResponseCode(res.code.to_string()).into());
+        }
+        match (self.key, res.payload.is_empty()) {
+            (Some(key), false) => Ok(aes_decrypt(&key, &res.payload)?),
+            _ => Ok(res.payload),
+        }
+    }
+
+    fn new_request(&mut self, path: &str) -> Message {
+        self.message_id = self.message_id.wrapping_add(1);
+        let token = crate::util::rand_u64().to_be_bytes().to_vec();
+        let mut msg = Message::new(MessageType::Confirmable, Code::POST, self.message_id, token);
+        msg.add_option(OPTION_URI_HOST, self.host.as_bytes());
+        msg.set_uri_path(path);
+        msg.add_uint_option(OPTION_CONTENT_FORMAT, CONTENT_FORMAT_JSON as u32);
+        msg
+    }
+
+    /// 发送可确认请求，超时重传，返回与之匹配的响应
+    async fn request(&self, msg: Message) -> crate::Result<Message> {
+        let bytes = msg.to_vec()?;
+        let mut timeout = self.options.ack_timeout;
+        for _ in 0..=self.options.max_retransmit {
+            self.transport.send(&bytes).await?;
+            match tokio::time::timeout(timeout, self.wait_ack(&msg)).await {
+                Ok(Ok(Some(res))) => return Ok(res),
+                Ok(Ok(None)) => {
+                    // 空确认，响应会单独下发
+                    return tokio::time::timeout(
+                        self.options.response_timeout,
+                        self.wait_response(&msg),
+                    )
+                    .await
+                    .map_err(|_| CoapError::Timeout)?;
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    debug!("等待确认超时 {:?}，重传 {}", timeout, msg.message_id);
+                    timeout *= 2;
+                }
+            }
+        }
+        Err(CoapError::Timeout.into())
+    }
+
+    /// 等待确认报文。携带响应的确认返回 `Some`，空确认返回 `None`。
+    async fn wait_ack(&self, req: &Message) -> crate::Result<Option<Message>> {
+        loop {
+            let res = self.recv().await?;
+            match res.r#type {
+                MessageType::Acknowledgement if res.message_id == req.message_id => {
+                    if res.code == Code::EMPTY {
+                        return Ok(None);
+                    }
+                    return Ok(Some(res));
+                }
+                MessageType::Reset if res.message_id == req.message_id => {
+                    return Err(CoapError::Reset.into());
+                }
+                _ if res.token == req.token && res.code != Code::EMPTY => {
+                    // 确认报文丢失，直接收到了单独的响应
+                    self.ack_response(&res).await?;
+                    return Ok(Some(res));
+                }
+                _ => debug!("忽略报文 {:?}", res),
+            }
+        }
+    }
+
+    async fn wait_response(&self, req: &Message) -> crate::Result<Message> {
+        loop {
+            let res = self.recv().await?;
+            if res.token == req.token && res.code != Code::EMPTY {
+                self.ack_response(&res).await?;
+                return Ok(res);
+            }
+            debug!("忽略报文 {:?}", res);
+        }
+    }
+
+    async fn ack_response(&self, res: &Message) -> crate::Result<()> {
+        if res.r#type == MessageType::Confirmable {
+            self.transport.send(&res.ack().to_vec()?).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&self) -> crate::Result<Message> {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let n = self.transport.recv(&mut buf).await?;
+        Ok(Message::from_slice(&buf[..n])?)
+    }
+}
+
+/// 对称加密方式的密钥：`sha256(${DeviceSecret},${random})` 十六进制字符串的第 9~24 位。
+pub fn aes_key(device_secret: &str, random: &str) -> [u8; 16] {
+    let hash = crate::util::sha256(format!("{},{}", device_secret, random).as_bytes());
+    let hash = hash.to_ascii_lowercase();
+    let mut key = [0; 16];
+    key.copy_from_slice(&hash.as_bytes()[8..24]);
+    key
+}
+
+pub fn aes_encrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+    cbc::Encryptor::<aes::Aes128>::new(key.into(), AES_IV.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(data)
+}
+
+pub fn aes_decrypt(key: &[u8; 16], data: &[u8]) -> Result<Vec<u8>> {
+    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+    cbc::Decryptor::<aes::Aes128>::new(key.into(), AES_IV.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| CoapError::DecryptError)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CoapAuthBody {
+    pub product_key: String,
+    pub device_name: String,
+    pub client_id: String,
+    pub sign: String,
+    pub signmethod: String,
+    pub timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CoapAuthResponse {
+    pub token: String,
+    /// 对称加密方式下用于计算密钥的随机数
+    pub random: Option<String>,
+    /// 对称加密方式下 seq 的起始值
+    pub seq_offset: Option<u64>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CoapError {
+    #[error("报文格式错误 {0}")]
+    FormatError(String),
+    #[error("设备认证失败 {0}")]
+    AuthCheckError(String),
+    #[error("解析失败")]
+    ParseError,
+    #[error("解密失败")]
+    DecryptError,
+    #[error("未认证，没有 token")]
+    TokenIsNull,
+    #[error("token 失效 {0}")]
+    Unauthorized(String),
+    #[error("请求失败 {0}")]
+    ResponseCode(String),
+    #[error("对端拒绝报文")]
+    Reset,
+    #[error("等待响应超时")]
+    Timeout,
+}
+
+#[test]
+fn test_aes() {
+    let key = aes_key("secret", "ad2b3a5eb51d64f7");
+    let data = b"{\"id\":\"1\",\"params\":{\"LightSwitch\":1}}";
+    let encrypted = aes_encrypt(&key, data);
+    assert_eq!(encrypted.len() % 16, 0);
+    assert_eq!(aes_decrypt(&key, &encrypted).unwrap(), data.to_vec());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::clock::ManualClock;
+    use serde_json::json;
+    use tokio::sync::{mpsc, Mutex};
+
+    /// 通过内存通道连接到测试云端的数据报通道
+    struct FakeTransport {
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        rx: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl CoapTransport for FakeTransport {
+        async fn send(&self, data: &[u8]) -> std::io::Result<()> {
+            self.tx.send(data.to_vec()).ok();
+            Ok(())
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let data = self.rx.lock().await.recv().await.unwrap_or_default();
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    /// 测试云端，按脚本逐个读取设备的报文并回复
+    struct Cloud {
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+    }
+
+    impl Cloud {
+        async fn next(&mut self) -> Message {
+            Message::from_slice(&self.rx.recv().await.unwrap()).unwrap()
+        }
+
+        fn send(&self, msg: Message) {
+            self.tx.send(msg.to_vec().unwrap()).unwrap();
+        }
+
+        /// 在确认报文中携带响应
+        fn reply(&self, req: &Message, code: Code, payload: &[u8]) {
+            let mut res = Message::new(
+                MessageType::Acknowledgement,
+                code,
+                req.message_id,
+                req.token.clone(),
+            );
+            res.payload = payload.to_vec();
+            self.send(res);
+        }
+
+        /// 回复认证请求，返回请求体
+        async fn auth(&mut self, token: &str) -> CoapAuthBody {
+            let req = self.next().await;
+            assert_eq!(req.uri_path(), "/auth");
+            let res = json!({ "token": token, "random": RANDOM, "seqOffset": 10 });
+            self.reply(&req, Code::CONTENT, res.to_string().as_bytes());
+            serde_json::from_slice(&req.payload).unwrap()
+        }
+    }
+
+    const HOST: &str = "pk.coap.cn-shanghai.link.aliyuncs.com";
+    const RANDOM: &str = "ad2b3a5eb51d64f7";
+    const TOPIC: &str = "/sys/pk/dn/thing/event/property/post";
+
+    fn coap(mode: CoapMode) -> (Coap, Cloud) {
+        let (device_tx, cloud_rx) = mpsc::unbounded_channel();
+        let (cloud_tx, device_rx) = mpsc::unbounded_channel();
+        let transport = FakeTransport {
+            tx: device_tx,
+            rx: Mutex::new(device_rx),
+        };
+        let three = ThreeTuple {
+            product_key: "pk".to_string(),
+            device_name: "dn".to_string(),
+            device_secret: "ds".to_string(),
+        };
+        let mut coap = Coap::with_transport(HOST, &three, mode, Box::new(transport));
+        coap.options = CoapOptions {
+            ack_timeout: Duration::from_millis(50),
+            max_retransmit: 2,
+            response_timeout: Duration::from_secs(1),
+            clock: ManualClock::new(1_600_000_000_000),
+            ..Default::default()
+        };
+        let cloud = Cloud {
+            rx: cloud_rx,
+            tx: cloud_tx,
+        };
+        (coap, cloud)
+    }
+
+    #[tokio::test]
+    async fn 对称加密上报() {
+        let (mut coap, mut cloud) = coap(CoapMode::Aes);
+        let cloud = async move {
+            let body = cloud.auth("t1").await;
+            assert_eq!(body.client_id, "pk&dn");
+            assert_eq!(body.timestamp, "1600000000000");
+            let seq = body.seq.clone().unwrap();
+            let text = format!(
+                "clientIdpk&dndeviceNamednproductKeypkseq{}timestamp1600000000000",
+                seq
+            );
+            assert_eq!(body.sign, auth::sign(&text, "ds"));
+
+            // 密钥为 sha256("ds,random") 的第 9~24 位，seq 从 seqOffset 开始递增
+            let key = *b"6a4ef56f04e34642";
+            let req = cloud.next().await;
+            assert_eq!(req.uri_path(), format!("/topic{}", TOPIC));
+            assert_eq!(req.option(OPTION_AUTH_TOKEN), Some(&b"t1"[..]));
+            let seq = aes_decrypt(&key, req.option(OPTION_SEQ).unwrap()).unwrap();
+            assert_eq!(seq, b"11");
+            assert_eq!(aes_decrypt(&key, &req.payload).unwrap(), b"{}");
+
+            // 先回复空确认，再单独下发响应，设备需要确认这个响应
+            cloud.send(req.ack());
+            let mut res = Message::new(
+                MessageType::Confirmable,
+                Code::CONTENT,
+                0x4321,
+                req.token.clone(),
+            );
+            res.payload = aes_encrypt(&key, b"ok");
+            cloud.send(res);
+            let ack = cloud.next().await;
+            assert_eq!(ack.r#type, MessageType::Acknowledgement);
+            assert_eq!(ack.message_id, 0x4321);
+        };
+        let (res, _) = tokio::join!(coap.send(TOPIC, b"{}"), cloud);
+        assert_eq!(res.unwrap(), b"ok");
+    }
+
+    #[tokio::test]
+    async fn 重新认证() {
+        let (mut coap, mut cloud) = coap(CoapMode::Dtls);
+        let cloud = async move {
+            let body = cloud.auth("t1").await;
+            assert!(body.seq.is_none());
+            let req = cloud.next().await;
+            assert_eq!(req.option(OPTION_AUTH_TOKEN), Some(&b"t1"[..]));
+            cloud.reply(&req, Code::UNAUTHORIZED, b"");
+
+            // 认证一次后重发
+            cloud.auth("t2").await;
+            let req = cloud.next().await;
+            assert_eq!(req.option(OPTION_AUTH_TOKEN), Some(&b"t2"[..]));
+            assert_eq!(req.payload, b"{}");
+            cloud.reply(&req, Code::CONTENT, b"ok");
+
+            // 重新认证后仍然失败时返回错误，不再认证
+            let req = cloud.next().await;
+            cloud.reply(&req, Code::FORBIDDEN, b"");
+            cloud.auth("t3").await;
+            let req = cloud.next().await;
+            cloud.reply(&req, Code::FORBIDDEN, b"");
+            cloud
+        };
+        let client = async {
+            assert_eq!(coap.send(TOPIC, b"{}").await.unwrap(), b"ok");
+            coap.send(TOPIC, b"{}").await
+        };
+        let (res, mut cloud) = tokio::join!(client, cloud);
+        assert!(matches!(
+            res,
+            Err(crate::Error::CoapError(CoapError::Unauthorized(_)))
+        ));
+        assert_eq!(coap.token.as_deref(), Some("t3"));
+        assert!(cloud.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn 超时重传() {
+        let (mut coap, mut cloud) = coap(CoapMode::Dtls);
+        let cloud = async move {
+            cloud.auth("t1").await;
+            // 确认丢失后用相同的报文 ID 重传
+            let first = cloud.next().await;
+            let second = cloud.next().await;
+            assert_eq!(first, second);
+            cloud.reply(&second, Code::CONTENT, b"ok");
+
+            // 一直没有确认时，重传 max_retransmit 次后超时
+            let first = cloud.next().await;
+            for _ in 0..2 {
+                assert_eq!(cloud.next().await, first);
+            }
+            cloud
+        };
+        let client = async {
+            assert_eq!(coap.send(TOPIC, b"{}").await.unwrap(), b"ok");
+            coap.send(TOPIC, b"{}").await
+        };
+        let (res, mut cloud) = tokio::join!(client, cloud);
+        assert!(matches!(
+            res,
+            Err(crate::Error::CoapError(CoapError::Timeout))
+        ));
+        assert!(cloud.rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::alink::AlinkRequest;
+use crate::mqtt::MqttStats;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 无线信号状态
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SignalStatus {
+    /// 信号强度，单位 dBm
+    pub rssi: i32,
+    /// 信噪比，单位 dB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snr: Option<i32>,
+    /// 丢包率
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per: Option<u32>,
+    /// 错误统计，格式为 `类型,错误码,次数;...`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub err_stats: Option<String>,
+}
+
+/// 网卡地址
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddr {
+    pub name: String,
+    pub addr: String,
+}
+
+/// MQTT 连接状态
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MqttStatus {
+    /// 心跳往返时间，单位毫秒
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<u64>,
+    /// 重连次数
+    pub reconnects: u64,
+}
+
+/// 设备网络状态
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct NetworkStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wifi: Option<SignalStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cellular: Option<SignalStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub interfaces: Vec<InterfaceAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttStatus>,
+    /// 采集时间，Unix 毫秒
+    #[serde(rename = "_time")]
+    pub time: i64,
+}
+
+/// 网络状态上报参数
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DiagPost {
+    pub p: NetworkStatus,
+    /// 数据格式描述，见 [`DIAG_MODEL`](super::push::DIAG_MODEL)
+    pub model: String,
+}
+
+// 设备上报网络状态
+// /sys/${productKey}/${deviceName}/_thing/diag/post
+pub type DiagPostRequest = AlinkRequest<DiagPost>;
+
+/// 获取蜂窝网络信号，Linux 上没有统一的接口，需要由应用通过调制解调器查询
+pub type CellularProvider = Arc<dyn Fn() -> Option<SignalStatus> + Send + Sync>;
+
+/// 网络诊断的参数
+#[derive(Clone)]
+pub struct DiagOptions {
+    /// 上报间隔
+    pub interval: Duration,
+    /// 无线网卡名，`None` 时使用 `/proc/net/wireless` 中的第一个网卡
+    pub wifi_interface: Option<String>,
+    pub cellular: Option<CellularProvider>,
+    /// MQTT 连接的统计信息，通过 [`MqttConnection::stats`](crate::MqttConnection::stats) 获取
+    pub mqtt: Option<Arc<MqttStats>>,
+}
+
+impl Default for DiagOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(300),
+            wifi_interface: None,
+            cellular: None,
+            mqtt: None,
+        }
+    }
+}
+
+impl fmt::Debug for DiagOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiagOptions")
+            .field("interval", &self.interval)
+            .field("wifi_interface", &self.wifi_interface)
+            .field("cellular", &self.cellular.is_some())
+            .field("mqtt", &self.mqtt)
+            .finish()
+    }
+}
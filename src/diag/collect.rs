@@ -0,0 +1,118 @@
+//! 在 Linux 上采集网络状态，其他平台只返回 MQTT 统计信息。
+
+use super::base::*;
+
+/// 采集当前的网络状态，`time` 为采集时间
+pub fn collect(options: &DiagOptions, time: i64) -> NetworkStatus {
+    NetworkStatus {
+        wifi: wifi_status(options.wifi_interface.as_deref()),
+        cellular: options.cellular.as_ref().and_then(|f| f()),
+        interfaces: interfaces(),
+        mqtt: options.mqtt.as_ref().map(|stats| MqttStatus {
+            rtt: stats.rtt().map(|rtt| rtt.as_millis() as u64),
+            reconnects: stats.reconnects(),
+        }),
+        time,
+    }
+}
+
+/// 从 `/proc/net/wireless` 读取无线网卡的信号强度和信噪比
+pub fn wifi_status(interface: Option<&str>) -> Option<SignalStatus> {
+    let content = std::fs::read_to_string("/proc/net/wireless").ok()?;
+    parse_wireless(&content, interface)
+}
+
+/// 解析 `/proc/net/wireless`，格式为：
+///
+/// ```text
+/// Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+///  face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+/// wlan0: 0000   70.  -40.  -256        0      0      0      0      0        0
+/// ```
+pub(crate) fn parse_wireless(content: &str, interface: Option<&str>) -> Option<SignalStatus> {
+    let number = |s: &str| s.trim_end_matches('.').parse::<i32>().ok();
+    content.lines().skip(2).find_map(|line| {
+        let (name, rest) = line.split_once(':')?;
+        if interface.is_some_and(|i| i != name.trim()) {
+            return None;
+        }
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let level = number(fields.get(2)?)?;
+        let noise = number(fields.get(3)?)?;
+        // 驱动不提供噪声时为 -256
+        let snr = (noise > -256 && noise < 0).then(|| level - noise);
+        Some(SignalStatus {
+            rssi: level,
+            snr,
+            ..Default::default()
+        })
+    })
+}
+
+/// 所有网卡的 IPv4 和 IPv6 地址
+#[cfg(target_os = "linux")]
+pub fn interfaces() -> Vec<InterfaceAddr> {
+    use std::ffi::CStr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let mut addrs = Vec::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return addrs;
+    }
+    let mut cur = ifap;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        let addr = match unsafe { (*ifa.ifa_addr).sa_family } as i32 {
+            libc::AF_INET => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sa = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sa.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        addrs.push(InterfaceAddr {
+            name: name.to_string_lossy().into_owned(),
+            addr: addr.to_string(),
+        });
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    addrs
+}
+
+/// 所有网卡的 IPv4 和 IPv6 地址，当前平台不支持
+#[cfg(not(target_os = "linux"))]
+pub fn interfaces() -> Vec<InterfaceAddr> {
+    Vec::new()
+}
+
+#[test]
+fn test_parse_wireless() {
+    let content = "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+wlan0: 0000   70.  -40.  -95.        0      0      0      0      0        0
+wlan1: 0000   50.  -60.  -256        0      0      0      0      0        0
+";
+    let wlan0 = parse_wireless(content, None).unwrap();
+    assert_eq!((wlan0.rssi, wlan0.snr), (-40, Some(55)));
+    let wlan1 = parse_wireless(content, Some("wlan1")).unwrap();
+    assert_eq!((wlan1.rssi, wlan1.snr), (-60, None));
+    assert!(parse_wireless(content, Some("eth0")).is_none());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_interfaces() {
+    let addrs = interfaces();
+    assert!(addrs
+        .iter()
+        .any(|a| a.name == "lo" && a.addr == "127.0.0.1"));
+}
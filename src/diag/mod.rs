@@ -0,0 +1,96 @@
+//! 设备网络诊断。
+
+use crate::alink::aiot_module::ChannelConnection;
+use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
+use crate::{Error, Result, ThreeTuple};
+use rumqttc::AsyncClient;
+use std::sync::Arc;
+
+use self::recv::*;
+
+pub use self::base::{DiagOptions, InterfaceAddr, MqttStatus, NetworkStatus, SignalStatus};
+
+pub mod base;
+pub mod collect;
+pub mod push;
+pub mod recv;
+
+pub type Recv = DiagRecv;
+pub type RecvKind = DiagRecvKind;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
+
+impl<C: Channel> Module<C> {
+    pub async fn init(&self) -> Result<()> {
+        self.sub_all::<RecvKind>().await
+    }
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    pub fn diag(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
+        let executor = Executor {
+            tx,
+            three: self.three.clone(),
+        };
+        self.module(Box::new(executor), rx, ())
+    }
+}
+
+pub struct Executor {
+    three: Arc<ThreeTuple>,
+    tx: QueueSender<Recv>,
+}
+
+#[async_trait::async_trait]
+impl crate::Executor for Executor {
+    async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
+        let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::three;
+    use crate::mock::MockCloud;
+    use crate::util::clock::ManualClock;
+    use crate::DeviceAuthInfo;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn 网络诊断() {
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        conn.set_clock(ManualClock::new(1_574_753_513_000));
+        let diag = conn.diag().unwrap();
+        diag.init().await.unwrap();
+        let options = DiagOptions {
+            cellular: Some(Arc::new(|| {
+                Some(SignalStatus {
+                    rssi: -85,
+                    snr: Some(12),
+                    ..Default::default()
+                })
+            })),
+            ..Default::default()
+        };
+        diag.post(diag.collect(&options)).await.unwrap();
+
+        let (topic, payload) = cloud.published().pop().unwrap();
+        assert!(topic.ends_with("/_thing/diag/post"));
+        let payload: Value = serde_json::from_slice(&payload).unwrap();
+        let params = &payload["params"];
+        assert_eq!(params["model"], "format=simple|specVersion=1.0");
+        assert_eq!(params["p"]["_time"], 1_574_753_513_000u64);
+        assert_eq!(params["p"]["cellular"], json!({ "rssi": -85, "snr": 12 }));
+        assert!(cloud
+            .subscriptions()
+            .iter()
+            .any(|t| t.ends_with("/_thing/diag/post_reply")));
+    }
+}
@@ -0,0 +1,52 @@
+use super::base::*;
+use super::recv::DiagRecv;
+use crate::alink::channel::Channel;
+use crate::alink::AlinkRequest;
+use log::*;
+
+/// 上报网络状态的 `model`，与云端设备诊断示例一致，
+/// 参考 [Alink 协议](https://help.aliyun.com/document_detail/90459.html)
+pub const DIAG_MODEL: &str = "format=simple|specVersion=1.0";
+
+impl<C: Channel> super::Module<C> {
+    /// 上报网络状态
+    pub async fn post(&self, status: NetworkStatus) -> crate::Result<()> {
+        let post = DiagPost {
+            p: status,
+            model: DIAG_MODEL.to_string(),
+        };
+        let payload: DiagPostRequest = AlinkRequest::new_no_ack("thing.diag.post", post);
+        let topic = format!(
+            "/sys/{}/{}/_thing/diag/post",
+            self.three.product_key, self.three.device_name
+        );
+        self.publish(topic, &payload).await
+    }
+
+    /// 按模块的时钟采集当前网络状态
+    pub fn collect(&self, options: &DiagOptions) -> NetworkStatus {
+        super::collect::collect(options, self.clock.now_millis())
+    }
+
+    /// 订阅上报响应并按 [`DiagOptions::interval`] 持续上报
+    pub async fn run(&mut self, options: DiagOptions) -> crate::Result<()> {
+        self.init().await?;
+        let mut interval = tokio::time::interval(options.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let status = self.collect(&options);
+                    if let Err(err) = self.post(status).await {
+                        warn!("diag post error: {}", err);
+                    }
+                }
+                recv = self.poll() => {
+                    let DiagRecv::DiagPostReply(reply) = recv?;
+                    if reply.code != 200 {
+                        warn!("diag post reply: {:?}", reply);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use crate::alink::aiot_module::{get_aiot_json, ModuleRecvKind};
+use crate::alink::alink_topic::ALinkSubscribeTopic;
+use crate::alink::SimpleResponse;
+use enum_iterator::IntoEnumIterator;
+use enum_kinds::EnumKind;
+use serde::{Deserialize, Serialize};
+
+// 设备上报网络状态响应
+// /sys/${productKey}/${deviceName}/_thing/diag/post_reply
+pub type DiagPostReply = SimpleResponse;
+
+#[derive(Debug, EnumKind)]
+#[enum_kind(DiagRecvKind, derive(Serialize, IntoEnumIterator, Deserialize))]
+pub enum DiagRecv {
+    /// 设备上报网络状态响应
+    DiagPostReply(DiagPostReply),
+}
+
+impl ModuleRecvKind for super::RecvKind {
+    type Recv = super::Recv;
+
+    fn to_payload(&self, payload: &[u8], _: &Vec<String>) -> crate::Result<DiagRecv> {
+        let s = get_aiot_json(payload);
+        match *self {
+            Self::DiagPostReply => Ok(Self::Recv::DiagPostReply(serde_json::from_str(&s)?)),
+        }
+    }
+
+    fn get_topic(&self) -> ALinkSubscribeTopic {
+        match *self {
+            Self::DiagPostReply => ALinkSubscribeTopic::new("/sys/+/+/_thing/diag/post_reply"),
+        }
+    }
+}
@@ -1,8 +1,10 @@
 //! 物模型
 
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::alink::{AlinkRequest, AlinkResponse};
-use crate::mqtt::MqttConnection;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::{debug, info};
@@ -10,8 +12,6 @@ use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
@@ -22,20 +22,20 @@ pub use base::*;
 
 pub type Recv = RecvEnum;
 pub type RecvKind = RecvEnumKind;
-pub type Module = AiotModule<Recv, DataModelOptions>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, DataModelOptions, C>;
 
-impl Module {
+impl<C: Channel> Module<C> {
     pub async fn init(&self) -> Result<()> {
         self.sub_all::<RecvKind>().await
     }
 }
 
-impl MqttConnection {
-    pub fn data_model(&mut self, options: DataModelOptions) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn data_model(&mut self, options: DataModelOptions) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
         self.module(Box::new(executor), rx, options)
     }
@@ -43,14 +43,14 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
     }
 }
 
@@ -1,4 +1,5 @@
 use super::base::*;
+use crate::alink::channel::Channel;
 use crate::alink::{AlinkRequest, AlinkResponse, SysAck};
 use crate::{Error, Result, ThreeTuple};
 use regex::Regex;
@@ -10,7 +11,7 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     pub async fn send(&self, data: DataModelMsg) -> crate::Result<()> {
         let mut data = data;
         if data.product_key.is_none() {
@@ -23,4 +24,22 @@ impl super::Module {
         let (topic, payload) = data.to_payload(ack)?;
         self.publish_raw(topic, payload).await
     }
+
+    /// 用模块的时钟给属性加上时间戳，生成 [`DataModelMsg::history_post`] 的一项。
+    ///
+    /// `properties` 为属性标识符到值的映射，如 `{"Power": "on"}`。
+    pub fn history_properties(&self, properties: &serde_json::Map<String, Value>) -> Value {
+        let time = self.clock.now_millis();
+        let properties: serde_json::Map<String, Value> = properties
+            .iter()
+            .map(|(k, v)| (k.clone(), serde_json::json!({ "value": v, "time": time })))
+            .collect();
+        serde_json::json!({
+            "identity": {
+                "productKey": self.three.product_key,
+                "deviceName": self.three.device_name,
+            },
+            "properties": [properties],
+        })
+    }
 }
@@ -116,7 +116,7 @@ impl ModuleRecvKind for super::RecvKind {
             }
             Self::Service => ALinkSubscribeTopic::new_with_regex(
                 "/sys/+/+/thing/service/+",
-                Regex::new(r"/sys/(.*)/(.*)/thing/service/(.*)").unwrap(),
+                Regex::new(r"^/sys/(.*)/(.*)/thing/service/(.*)").unwrap(),
             ),
             Self::RrpcService => ALinkSubscribeTopic {
                 topic: "/ext/rrpc/+/sys/+/+/thing/service/+",
@@ -4,7 +4,10 @@ use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 
@@ -77,13 +80,57 @@ pub struct InitExtraParams {
     pub oss_owner_type: OssOwnerType,
     /// 文件上传相关的业务ID。该ID需要在物联网平台控制台预先定义。具体操作，请参见配置设备文件上传至Bucket。
     /// 仅ossOwnerType为device-user时，serviceId有效。
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub service_id: String,
     /// 文件保存到OSS存储空间携带的标签，最多包含5个。标签定义规则，请参见对象标签。
     /// 标签Key不能以2个下划线（_）开头。
     /// 仅ossOwnerType为device-user时，fileTag有效。
+    #[serde(default, skip_serializing_if = "Value::is_null")]
     pub file_tag: Value,
 }
 
+/// OSS 对象标签的最大数量
+pub const MAX_FILE_TAGS: usize = 5;
+
+impl InitExtraParams {
+    /// 上传到物联网平台的存储空间
+    pub fn iot_platform() -> Self {
+        Self {
+            oss_owner_type: OssOwnerType::IotPlatform,
+            service_id: String::new(),
+            file_tag: Value::Null,
+        }
+    }
+
+    /// 上传到设备所属用户自己的 OSS 存储空间。
+    /// Bucket 和存储路径由控制台中配置的 `service_id` 决定。
+    pub fn device_user(service_id: &str) -> Self {
+        Self {
+            oss_owner_type: OssOwnerType::DeviceUser,
+            service_id: service_id.to_string(),
+            file_tag: Value::Null,
+        }
+    }
+
+    /// 添加文件标签
+    pub fn tag(mut self, key: &str, value: &str) -> Result<Self> {
+        if key.is_empty() || key.starts_with("__") {
+            return Err(Error::FileValidateFailed(format!("无效的标签 {key}")));
+        }
+        if !self.file_tag.is_object() {
+            self.file_tag = Value::Object(Default::default());
+        }
+        let tags = self.file_tag.as_object_mut().unwrap();
+        tags.insert(key.to_string(), Value::String(value.to_string()));
+        if tags.len() > MAX_FILE_TAGS {
+            return Err(Error::FileValidateFailed(format!(
+                "标签最多 {MAX_FILE_TAGS} 个"
+            )));
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct InitData {
@@ -178,3 +225,123 @@ pub struct UploadId {
     /// 设备请求上传文件时返回的文件上传任务标识ID。
     pub upload_id: String,
 }
+
+/// 上传选项
+#[derive(Clone)]
+pub struct UploadOptions {
+    /// 分片大小，范围为256 B~131072 B
+    pub chunk_size: usize,
+    /// 同时等待应答的分片数，分片仍按顺序发送
+    pub window: usize,
+    /// 分片失败或应答超时后，重新请求上传（append 模式）并从云端记录的位置续传的最大次数
+    pub retries: u32,
+    /// 等待分片应答的超时时间。HTTP 上传时用于连接、请求体的每次发送和等待响应
+    pub timeout: Duration,
+    /// 保存上传进度的文件。上传中断后，下次上传同一个文件时使用 append 模式续传。
+    pub state_path: Option<PathBuf>,
+    /// 每个分片上传成功后发送进度，消费不及时时丢弃
+    pub progress: Option<mpsc::Sender<UploadProgress>>,
+    /// 上传到指定的 OSS 存储空间
+    pub extra_params: Option<InitExtraParams>,
+    /// 获取 HTTP 上传签名地址，设置后超过 `http_threshold` 的文件通过 HTTP 上传
+    pub signed_url: Option<Arc<dyn super::http::SignedUrlProvider>>,
+    /// 通过 HTTP 上传的文件大小阈值，不超过 [`MAX_FILE_SIZE`](super::MAX_FILE_SIZE)
+    pub http_threshold: u64,
+}
+
+impl fmt::Debug for UploadOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UploadOptions")
+            .field("chunk_size", &self.chunk_size)
+            .field("window", &self.window)
+            .field("retries", &self.retries)
+            .field("timeout", &self.timeout)
+            .field("state_path", &self.state_path)
+            .field("extra_params", &self.extra_params)
+            .field("signed_url", &self.signed_url.is_some())
+            .field("http_threshold", &self.http_threshold)
+            .finish()
+    }
+}
+
+impl UploadOptions {
+    /// 设置了 `signed_url` 且 `size` 超过 `http_threshold` 时返回签名地址的提供者
+    pub(crate) fn http_provider(
+        &self,
+        size: Option<u64>,
+    ) -> Option<&dyn super::http::SignedUrlProvider> {
+        let threshold = self.http_threshold.min(super::MAX_FILE_SIZE);
+        match (&self.signed_url, size) {
+            (Some(provider), Some(size)) if size > threshold => Some(provider.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: super::MAX_CHUNK_SIZE,
+            window: 1,
+            retries: 3,
+            timeout: Duration::from_secs(10),
+            state_path: None,
+            progress: None,
+            extra_params: None,
+            signed_url: None,
+            http_threshold: super::MAX_FILE_SIZE,
+        }
+    }
+}
+
+/// 上传进度
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadProgress {
+    pub file_name: String,
+    pub upload_id: String,
+    /// 已上传的字节数
+    pub offset: u64,
+    /// 文件大小，未知时为 `None`
+    pub size: Option<u64>,
+}
+
+/// 保存在 [`UploadOptions::state_path`] 中的上传进度
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadState {
+    pub file_name: String,
+    pub upload_id: String,
+    /// 文件大小，未知时为 `None`
+    pub size: Option<u64>,
+    /// 文件的 CRC64，文件大小未知时不校验
+    pub fic_value: Option<String>,
+    pub offset: u64,
+}
+
+impl UploadState {
+    /// 新的上传任务，检查文件大小
+    pub fn new(file_name: String, size: Option<u64>, fic_value: Option<String>) -> Result<Self> {
+        match size {
+            Some(0) => return Err(Error::FileValidateFailed("文件为空".to_string())),
+            Some(size) if size > super::MAX_FILE_SIZE => return Err(Error::FileTooLarge(size)),
+            _ => {}
+        }
+        Ok(Self {
+            file_name,
+            upload_id: String::new(),
+            size,
+            fic_value: fic_value.filter(|_| size.is_some()),
+            offset: 0,
+        })
+    }
+
+    /// 读取上传进度，文件不存在或格式错误时返回 `None`
+    pub async fn load(path: &Path) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        crate::util::write_atomic(path, &serde_json::to_vec(self)?).await
+    }
+}
@@ -0,0 +1,347 @@
+//! 通过 HTTP 上传超过 MQTT 限制的大文件。
+//!
+//! 设备先通过 [`SignedUrlProvider`] 获得 OSS 的签名地址（例如由业务服务器使用 OSS SDK 生成后，
+//! 通过自定义 Topic 或 RRPC 下发），再用 HTTP PUT 把文件直接上传到 OSS。
+
+use super::base::{InitExtraParams, UploadOptions, UploadProgress};
+use super::util;
+use crate::{Error, Result};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Body, Client};
+use std::future::Future;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
+
+/// 请求签名地址的参数
+#[derive(Debug, Clone)]
+pub struct SignedUrlRequest {
+    pub file_name: String,
+    /// 文件大小，单位字节
+    pub size: u64,
+    /// 文件的 CRC64，16 位 Hex 字符串，上传数据流时为 `None`
+    pub fic_value: Option<String>,
+    /// 上传的目标 OSS 存储空间
+    pub extra_params: Option<InitExtraParams>,
+}
+
+/// OSS 的签名地址
+#[derive(Debug, Clone)]
+pub struct SignedUrl {
+    /// 使用 PUT 方法签名的地址
+    pub url: String,
+    /// 签名时指定的请求头，如 `Content-Type`
+    pub headers: Vec<(String, String)>,
+}
+
+/// 获取 HTTP 上传的签名地址，通过 [`UploadOptions::signed_url`] 设置。
+///
+/// 闭包 `Fn(SignedUrlRequest) -> impl Future<Output = Result<SignedUrl>>` 也实现了这个 trait。
+#[async_trait::async_trait]
+pub trait SignedUrlProvider: Send + Sync + 'static {
+    async fn signed_url(&self, request: &SignedUrlRequest) -> Result<SignedUrl>;
+}
+
+#[async_trait::async_trait]
+impl<F, Fut> SignedUrlProvider for F
+where
+    F: Fn(SignedUrlRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<SignedUrl>> + Send,
+{
+    async fn signed_url(&self, request: &SignedUrlRequest) -> Result<SignedUrl> {
+        self(request.clone()).await
+    }
+}
+
+/// OSS 返回的对象 CRC64，十进制
+const OSS_CRC64_HEADER: &str = "x-oss-hash-crc64ecma";
+
+/// HTTP 上传的数据来源
+pub(crate) enum Source<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+/// 把文件或内存中的数据 PUT 到签名地址，失败或超时后重新获取签名地址重传。
+/// OSS 返回 CRC64 时与本地计算的值比较。
+pub(crate) async fn put(
+    source: Source<'_>,
+    request: SignedUrlRequest,
+    provider: &dyn SignedUrlProvider,
+    options: &UploadOptions,
+) -> Result<String> {
+    let client = Client::builder().connect_timeout(options.timeout).build()?;
+    let mut attempt = 0;
+    loop {
+        let res = match source {
+            Source::File(path) => match File::open(path).await {
+                Ok(file) => put_once(&client, file, &request, provider, options).await,
+                Err(err) => Err(err.into()),
+            },
+            Source::Bytes(data) => put_once(&client, data, &request, provider, options).await,
+        };
+        match res {
+            Ok(()) => return Ok(request.file_name),
+            Err(err @ Error::FicNotMatch(..)) => return Err(err),
+            Err(err) => {
+                attempt += 1;
+                if attempt > options.retries {
+                    return Err(err);
+                }
+                log::warn!(
+                    "http upload {} error: {:?}, retry {}",
+                    request.file_name,
+                    err,
+                    attempt
+                );
+            }
+        }
+    }
+}
+
+/// 把数据流 PUT 到签名地址。数据流无法回退，失败时不重传。
+pub(crate) async fn put_reader(
+    reader: impl AsyncRead + Send + Unpin,
+    request: SignedUrlRequest,
+    provider: &dyn SignedUrlProvider,
+    options: &UploadOptions,
+) -> Result<String> {
+    let client = Client::builder().connect_timeout(options.timeout).build()?;
+    put_once(&client, reader, &request, provider, options).await?;
+    Ok(request.file_name)
+}
+
+/// 发送一次请求。请求体超过 `options.timeout` 没有进展，
+/// 或者数据发送完后超过 `options.timeout` 没有响应时返回超时错误。
+async fn put_once(
+    client: &Client,
+    reader: impl AsyncRead + Unpin,
+    request: &SignedUrlRequest,
+    provider: &dyn SignedUrlProvider,
+    options: &UploadOptions,
+) -> Result<()> {
+    let signed = provider.signed_url(request).await?;
+    let (tx, rx) = mpsc::channel(4);
+    let mut builder = client.put(&signed.url).header(CONTENT_LENGTH, request.size);
+    for (key, value) in &signed.headers {
+        builder = builder.header(key, value);
+    }
+    let send = async {
+        let response = builder
+            .body(Body::wrap_stream(rx))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok::<_, Error>(
+            response
+                .headers()
+                .get(OSS_CRC64_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok()),
+        )
+    };
+    tokio::pin!(send);
+    let body = read_body(reader, tx, request, options);
+    tokio::pin!(body);
+    let (server, crc64) = tokio::select! {
+        // 请求提前结束，剩余的数据不再发送
+        server = &mut send => (server?, body.await?),
+        crc64 = &mut body => {
+            let crc64 = crc64?;
+            let server = time::timeout(options.timeout, send)
+                .await
+                .map_err(|_| timeout_error(request))??;
+            (server, crc64)
+        }
+    };
+    match server {
+        Some(server) if server != crc64 => Err(Error::FicNotMatch(
+            util::fic_value(crc64),
+            util::fic_value(server),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 读取数据交给请求体并发送进度，返回数据的 CRC64
+async fn read_body(
+    mut reader: impl AsyncRead + Unpin,
+    mut tx: mpsc::Sender<std::io::Result<Vec<u8>>>,
+    request: &SignedUrlRequest,
+    options: &UploadOptions,
+) -> Result<u64> {
+    let mut digest = util::CRC64.digest();
+    let mut offset = 0u64;
+    loop {
+        let mut buf = vec![0; 64 * 1024];
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        buf.truncate(n);
+        digest.update(&buf);
+        offset += n as u64;
+        if offset > request.size {
+            return Err(Error::SizeNotMatch(request.size as usize, offset as usize));
+        }
+        // 请求已经结束时由发送请求的一方返回错误
+        match time::timeout(options.timeout, tx.send(Ok(buf))).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => break,
+            Err(_) => return Err(timeout_error(request)),
+        }
+        if let Some(progress) = &options.progress {
+            progress
+                .try_send(UploadProgress {
+                    file_name: request.file_name.clone(),
+                    upload_id: String::new(),
+                    offset,
+                    size: Some(request.size),
+                })
+                .ok();
+        }
+    }
+    Ok(digest.finalize())
+}
+
+fn timeout_error(request: &SignedUrlRequest) -> Error {
+    Error::WaitResponseTimeout(format!("http put {}", request.file_name))
+}
+
+/// 接受 PUT 请求并返回 `crc64` 的 HTTP 服务器，返回上传地址和收到的请求体
+#[cfg(test)]
+async fn serve_put(crc64: u64) -> (String, std::sync::Arc<std::sync::Mutex<Vec<u8>>>) {
+    use crate::mock::testing::{serve_http, HttpResponse};
+
+    let body = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received = body.clone();
+    let host = serve_http(move |request| {
+        assert_eq!(request.method, "PUT");
+        *received.lock().unwrap() = request.body;
+        HttpResponse::ok(Vec::new()).header(OSS_CRC64_HEADER, crc64)
+    })
+    .await;
+    (format!("http://{}/bucket/", host), body)
+}
+
+#[tokio::test]
+async fn test_http_upload() {
+    use crate::mock::MockCloud;
+    use crate::{DeviceAuthInfo, ThreeTuple};
+    use std::sync::Arc;
+
+    let dir = tempdir::TempDir::new("upload").unwrap();
+    let path = dir.path().join("big.bin");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 7) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+    let (url, received) = serve_put(util::CRC64.checksum(&data)).await;
+    let url = format!("{}big.bin", url);
+
+    let three = ThreeTuple {
+        product_key: "pk".to_string(),
+        device_name: "dn".to_string(),
+        device_secret: "ds".to_string(),
+    };
+    let mut cloud = MockCloud::new(&three);
+    let mut conn = cloud.connect(&DeviceAuthInfo::from_tuple(&three)).unwrap();
+    let uploader = conn.file_uploader().unwrap();
+    let options = UploadOptions {
+        signed_url: Some(Arc::new(move |request: SignedUrlRequest| {
+            let url = url.clone();
+            async move {
+                assert_eq!(request.size, 300_000);
+                Ok(SignedUrl {
+                    url,
+                    headers: vec![],
+                })
+            }
+        })),
+        http_threshold: 100_000,
+        ..Default::default()
+    };
+
+    // 超过阈值的文件通过 HTTP 上传，不经过 MQTT
+    let name = uploader.upload_with(&path, options).await.unwrap();
+    assert_eq!(name, "big.bin");
+    assert!(*received.lock().unwrap() == data);
+    assert!(cloud.published().is_empty());
+
+    // 内存中的数据和长度已知的数据流同样按大小选择 HTTP 上传
+    for reader in [false, true] {
+        let (url, received) = serve_put(util::CRC64.checksum(&data)).await;
+        let url = format!("{}snapshot.bin", url);
+        let options = UploadOptions {
+            signed_url: Some(Arc::new(move |request: SignedUrlRequest| {
+                let url = url.clone();
+                async move {
+                    assert_eq!(request.fic_value.is_none(), reader);
+                    Ok(SignedUrl {
+                        url,
+                        headers: vec![],
+                    })
+                }
+            })),
+            http_threshold: 100_000,
+            ..Default::default()
+        };
+        let name = if reader {
+            let size = data.len() as u64;
+            let upload =
+                uploader.upload_reader_with("snapshot.bin", &data[..], Some(size), options);
+            upload.await.unwrap()
+        } else {
+            let upload = uploader.upload_bytes_with("snapshot.bin", data.clone(), options);
+            upload.await.unwrap()
+        };
+        assert_eq!(name, "snapshot.bin");
+        assert!(*received.lock().unwrap() == data);
+    }
+    assert!(cloud.published().is_empty());
+}
+
+/// 签名地址的服务器不响应时按超时失败并重传
+#[tokio::test]
+async fn test_http_upload_timeout() {
+    use crate::mock::MockCloud;
+    use crate::{DeviceAuthInfo, ThreeTuple};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let host =
+        crate::mock::testing::serve_http(|_| crate::mock::testing::HttpResponse::hang()).await;
+    let url = format!("http://{}/bucket/big.bin", host);
+
+    let three = ThreeTuple {
+        product_key: "pk".to_string(),
+        device_name: "dn".to_string(),
+        device_secret: "ds".to_string(),
+    };
+    let mut cloud = MockCloud::new(&three);
+    let mut conn = cloud.connect(&DeviceAuthInfo::from_tuple(&three)).unwrap();
+    let uploader = conn.file_uploader().unwrap();
+    let attempts = Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let counter = attempts.clone();
+    let options = UploadOptions {
+        signed_url: Some(Arc::new(move |_: SignedUrlRequest| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let url = url.clone();
+            async move {
+                Ok(SignedUrl {
+                    url,
+                    headers: vec![],
+                })
+            }
+        })),
+        http_threshold: 1,
+        retries: 1,
+        timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let upload = uploader.upload_bytes_with("big.bin", vec![0; 1024], options);
+    let res = time::timeout(Duration::from_secs(5), upload).await.unwrap();
+    assert!(matches!(res, Err(Error::WaitResponseTimeout(_))));
+    assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
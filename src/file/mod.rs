@@ -1,92 +1,326 @@
 //! 文件上传
 
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::alink::{AlinkRequest, AlinkResponse};
-use crate::mqtt::MqttConnection;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
+use futures::stream::{FuturesOrdered, StreamExt};
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
 use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeekExt};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time;
 
 use self::recv::*;
 
 pub mod base;
+pub mod http;
 pub mod push;
 pub mod recv;
 pub mod util;
 
 pub use base::*;
+pub use http::{SignedUrl, SignedUrlProvider, SignedUrlRequest};
 
 pub type Recv = FileRecv;
 pub type RecvKind = FileRecvKind;
-pub type Module = AiotModule<Recv, Sender<(String, oneshot::Sender<Recv>)>>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, Sender<(String, oneshot::Sender<Recv>)>, C>;
 
-pub const CHUNK_SIZE: usize = 4096;
+/// 非最后一个分片的最小长度
+pub const MIN_CHUNK_SIZE: usize = 256;
+/// 分片的最大长度
+pub const MAX_CHUNK_SIZE: usize = 131072;
+/// 通过 MQTT 上传的文件大小上限
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
 
-impl Module {
+impl<C: Channel> Module<C> {
     pub async fn init(&self) -> Result<()> {
         self.sub_all::<RecvKind>().await
     }
 
-    pub async fn upload(&self, path: impl AsRef<std::path::Path>) -> Result<String> {
-        use tokio::fs::File;
-        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    /// 使用默认的 [`UploadOptions`] 上传文件，返回云端的文件名
+    pub async fn upload(&self, path: impl AsRef<Path>) -> Result<String> {
+        self.upload_with(path, UploadOptions::default()).await
+    }
+
+    /// 上传文件，返回云端的文件名。
+    ///
+    /// 上传前计算文件的 CRC64，由云端在上传完成后校验完整性。
+    /// 设置了 `state_path` 时保存上传进度，中断后再次上传同一个文件会从云端记录的位置续传。
+    ///
+    /// 设置了 `signed_url` 时，超过 `http_threshold` 的文件通过 HTTP 上传到签名地址，
+    /// 不受 MQTT 上传 16 MB 的限制。
+    pub async fn upload_with(
+        &self,
+        path: impl AsRef<Path>,
+        options: UploadOptions,
+    ) -> Result<String> {
+        let path = path.as_ref();
+        let file_name = util::filename_for_path(path)?;
+        let (size, crc64) = util::file_crc64(path).await?;
+        if let Some(provider) = options.http_provider(Some(size)) {
+            let request = SignedUrlRequest {
+                file_name,
+                size,
+                fic_value: Some(util::fic_value(crc64)),
+                extra_params: options.extra_params.clone(),
+            };
+            return http::put(http::Source::File(path), request, provider, &options).await;
+        }
+        let mut state = UploadState::new(file_name, Some(size), Some(util::fic_value(crc64)))?;
+        let resume = Self::resumable(&state, &options).await;
+        self.upload_start(&mut state, resume, &options).await?;
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(state.offset)).await?;
+        let reader = util::ChunkReader::new(file, state.offset, Some(size));
+        self.upload_all(state, reader, &options).await
+    }
+
+    /// 上传内存中的数据，返回云端的文件名。`name` 需要符合 [`InitParams::file_name`] 的限制。
+    pub async fn upload_bytes(&self, name: &str, data: Vec<u8>) -> Result<String> {
+        self.upload_bytes_with(name, data, UploadOptions::default())
+            .await
+    }
 
+    /// 按 `options` 上传内存中的数据，返回云端的文件名。
+    /// 与 [`upload_with`](Self::upload_with) 相同，超过 `http_threshold` 时通过 HTTP 上传。
+    pub async fn upload_bytes_with(
+        &self,
+        name: &str,
+        data: Vec<u8>,
+        options: UploadOptions,
+    ) -> Result<String> {
+        util::validate_filename(name)?;
+        let size = data.len() as u64;
+        let fic_value = util::fic_value(util::CRC64.checksum(&data));
+        if let Some(provider) = options.http_provider(Some(size)) {
+            let request = SignedUrlRequest {
+                file_name: name.to_string(),
+                size,
+                fic_value: Some(fic_value),
+                extra_params: options.extra_params.clone(),
+            };
+            return http::put(http::Source::Bytes(&data), request, provider, &options).await;
+        }
+        let mut state = UploadState::new(name.to_string(), Some(size), Some(fic_value))?;
+        let resume = Self::resumable(&state, &options).await;
+        self.upload_start(&mut state, resume, &options).await?;
+        let mut reader = std::io::Cursor::new(data);
+        reader.set_position(state.offset);
+        let reader = util::ChunkReader::new(reader, state.offset, Some(size));
+        self.upload_all(state, reader, &options).await
+    }
+
+    /// 上传数据流，返回云端的文件名。`name` 需要符合 [`InitParams::file_name`] 的限制。
+    ///
+    /// `size_hint` 为数据流的长度，未知时按文件大小未知（`fileSize` 为 -1）上传，
+    /// 此时云端不支持完整性校验。数据流的实际长度与 `size_hint` 不一致时返回错误。
+    pub async fn upload_reader(
+        &self,
+        name: &str,
+        reader: impl AsyncRead + Send + Unpin,
+        size_hint: Option<u64>,
+    ) -> Result<String> {
+        self.upload_reader_with(name, reader, size_hint, UploadOptions::default())
+            .await
+    }
+
+    /// 按 `options` 上传数据流，返回云端的文件名。数据流无法回退，中断后不会续传上次的进度。
+    ///
+    /// 只有 `size_hint` 已知且超过 `http_threshold` 时才通过 HTTP 上传，此时失败不重传。
+    pub async fn upload_reader_with(
+        &self,
+        name: &str,
+        reader: impl AsyncRead + Send + Unpin,
+        size_hint: Option<u64>,
+        options: UploadOptions,
+    ) -> Result<String> {
+        util::validate_filename(name)?;
+        if let (Some(provider), Some(size)) = (options.http_provider(size_hint), size_hint) {
+            let request = SignedUrlRequest {
+                file_name: name.to_string(),
+                size,
+                fic_value: None,
+                extra_params: options.extra_params.clone(),
+            };
+            return http::put_reader(reader, request, provider, &options).await;
+        }
+        let mut state = UploadState::new(name.to_string(), size_hint, None)?;
+        self.upload_start(&mut state, false, &options).await?;
+        let reader = util::ChunkReader::new(reader, state.offset, size_hint);
+        self.upload_all(state, reader, &options).await
+    }
+
+    /// 上次没有传完的同一个文件使用 append 模式续传
+    async fn resumable(state: &UploadState, options: &UploadOptions) -> bool {
+        match &options.state_path {
+            Some(path) => UploadState::load(path).await.is_some_and(|saved| {
+                saved.file_name == state.file_name
+                    && saved.size == state.size
+                    && saved.fic_value == state.fic_value
+            }),
+            None => false,
+        }
+    }
+
+    /// 上传 `reader` 中剩余的数据，失败时重新请求上传（append 模式）并从云端记录的位置续传
+    async fn upload_all<R: AsyncRead + Unpin>(
+        &self,
+        mut state: UploadState,
+        mut reader: util::ChunkReader<R>,
+        options: &UploadOptions,
+    ) -> Result<String> {
+        let mut attempt = 0;
+        let mut last_offset = state.offset;
+        loop {
+            match self.upload_chunks(&mut reader, &mut state, options).await {
+                Ok(()) => break,
+                Err(err @ (Error::FicNotMatch(..) | Error::FileTooLarge(_))) => {
+                    if let Some(path) = &options.state_path {
+                        tokio::fs::remove_file(path).await.ok();
+                    }
+                    return Err(err);
+                }
+                Err(err) => {
+                    // 有进展时重新计数
+                    if state.offset > last_offset {
+                        attempt = 0;
+                        last_offset = state.offset;
+                    }
+                    attempt += 1;
+                    if attempt > options.retries {
+                        return Err(err);
+                    }
+                    log::warn!(
+                        "upload {} error: {:?}, retry {}",
+                        state.file_name,
+                        err,
+                        attempt
+                    );
+                    self.upload_start(&mut state, true, options).await?;
+                }
+            }
+        }
+        if let Some(path) = &options.state_path {
+            tokio::fs::remove_file(path).await.ok();
+        }
+        Ok(state.file_name)
+    }
+
+    /// 请求上传文件，`append` 时从云端记录的位置续传
+    async fn upload_start(
+        &self,
+        state: &mut UploadState,
+        append: bool,
+        options: &UploadOptions,
+    ) -> Result<()> {
         let params = InitParams {
-            file_name: util::filename_for_path(&path)?,
-            file_size: -1,
-            conflict_strategy: Some(ConflictStrategy::Overwrite),
+            file_name: state.file_name.clone(),
+            file_size: state.size.map_or(-1, |size| size as i32),
+            conflict_strategy: Some(if append {
+                ConflictStrategy::Append
+            } else {
+                ConflictStrategy::Overwrite
+            }),
+            fic_mode: state.fic_value.as_ref().map(|_| FicMode::Crc64),
+            fic_value: state.fic_value.clone(),
+            extra_params: options.extra_params.clone(),
             ..Default::default()
         };
-        let info = self.upload_init(params).await?;
+        let info = time::timeout(options.timeout, self.upload_init(params))
+            .await
+            .map_err(|_| Error::WaitResponseTimeout("upload_init".to_string()))??;
         log::info!("upload_init: {:?}", info);
-
-        let mut file = File::open(path).await?;
-
-        let mut buf = [0; CHUNK_SIZE];
-        let mut offset = 0;
-        let mut is_complete = Some(false);
-        if let Some(seek) = info.offset {
-            file.seek(std::io::SeekFrom::Start(seek as u64)).await?;
-            offset = seek as usize;
+        state.file_name = info.file_name;
+        state.upload_id = info.upload_id;
+        state.offset = info.offset.unwrap_or(0) as u64;
+        if let Some(path) = &options.state_path {
+            state.save(path).await?;
         }
+        Ok(())
+    }
+
+    /// 从 `state.offset` 开始按顺序发送分片，最多 `window` 个分片同时等待应答，
+    /// 每个分片成功后更新 `state`
+    async fn upload_chunks<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut util::ChunkReader<R>,
+        state: &mut UploadState,
+        options: &UploadOptions,
+    ) -> Result<()> {
+        let chunk_size = options.chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        let timeout = options.timeout;
+        reader.rewind(state.offset)?;
+        let mut pending = FuturesOrdered::new();
         loop {
-            let b_size = file.read(&mut buf).await?;
-            if b_size < CHUNK_SIZE {
-                is_complete = Some(true);
+            while pending.len() < options.window.max(1) {
+                let (offset, buf, last) = match reader.next(chunk_size).await? {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
+                let end = offset + buf.len() as u64;
+                if end > MAX_FILE_SIZE {
+                    return Err(Error::FileTooLarge(end));
+                }
+                let params = SendHeaderParams {
+                    upload_id: state.upload_id.clone(),
+                    offset: offset as usize,
+                    b_size: buf.len(),
+                    // 文件大小未知时需要标记最后一个分片
+                    is_complete: state.size.is_none().then_some(last),
+                };
+                pending.push_back(async move {
+                    let reply = time::timeout(timeout, self.upload_send(params, &buf))
+                        .await
+                        .map_err(|_| Error::WaitResponseTimeout(format!("upload_send {end}")))??;
+                    Ok::<_, Error>((end, reply))
+                });
             }
-            // log::debug!("read: {} [{}]", b_size, String::from_utf8_lossy(&buf));
-            let params = SendHeaderParams {
-                upload_id: info.upload_id.clone(),
-                offset,
-                b_size,
-                is_complete,
+            let (end, reply) = match pending.next().await {
+                Some(res) => res?,
+                None => break,
             };
-            offset += b_size;
-            if let Err(err) = self.upload_send(params.clone(), buf[..b_size].into()).await {
-                log::error!("upload_send: {:?}", err);
-                self.upload_send(params, buf[..b_size].into()).await?;
+            state.offset = end;
+            reader.ack(end);
+            if let Some(path) = &options.state_path {
+                state.save(path).await?;
             }
-            if is_complete.unwrap_or(false) {
-                break;
+            if let Some(tx) = &options.progress {
+                tx.try_send(UploadProgress {
+                    file_name: state.file_name.clone(),
+                    upload_id: state.upload_id.clone(),
+                    offset: state.offset,
+                    size: state.size,
+                })
+                .ok();
+            }
+            if let (Some(client), Some(server)) = (reply.fic_value_client, reply.fic_value_server) {
+                if !client.eq_ignore_ascii_case(&server) {
+                    return Err(Error::FicNotMatch(client, server));
+                }
             }
         }
-        Ok(info.file_name)
+        Ok(())
     }
 }
 
-impl MqttConnection {
-    pub fn file_uploader(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn file_uploader(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let (tx_, rx_) = mpsc::channel(64);
         let executor = Executor {
             tx,
             rx_,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
             map: HashMap::new(),
         };
         self.module(Box::new(executor), rx, tx_)
@@ -95,7 +329,7 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
     rx_: Receiver<(String, oneshot::Sender<Recv>)>,
     map: HashMap<String, oneshot::Sender<Recv>>,
 }
@@ -114,19 +348,142 @@ impl crate::Executor for Executor {
             Recv::SendReply(item) => item.id.clone(),
             Recv::CancelReply(item) => item.id.clone(),
         };
-        if let Some(id) = id {
-            if let Some(item) = self.map.remove(&id) {
-                item.send(data.clone());
-            }
-        } else {
-            if let Some((id, _)) = self.map.iter().next() {
-                let id = id.clone();
-                if let Some(item) = self.map.remove(&id) {
-                    item.send(data.clone());
-                }
-            }
+        let waiter = match id {
+            Some(id) => self.map.remove(&id),
+            None => match self.map.keys().next().cloned() {
+                Some(id) => self.map.remove(&id),
+                None => None,
+            },
+        };
+        // 正在上传时应答只交给等待的请求，否则大文件的分片应答会填满模块的接收队列
+        let data = match waiter {
+            Some(item) => match item.send(data) {
+                Ok(()) => return Ok(()),
+                Err(data) => data,
+            },
+            None => data,
+        };
+
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{drive, three};
+    use crate::mock::MockCloud;
+    use crate::DeviceAuthInfo;
+
+    #[tokio::test]
+    async fn 文件续传() {
+        use crate::file::{UploadOptions, UploadState};
+        use std::time::Duration;
+
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let uploader = conn.file_uploader().unwrap();
+        uploader.init().await.unwrap();
+
+        let dir = tempdir::TempDir::new("upload").unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let state_path = dir.path().join("data.bin.upload");
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut options = UploadOptions {
+            window: 2,
+            retries: 0,
+            timeout: Duration::from_millis(100),
+            state_path: Some(state_path.clone()),
+            progress: Some(tx),
+            ..Default::default()
+        };
+
+        // 第二个分片丢失，上传中断，进度保存在 state_path
+        cloud.drop_file_chunks(1, 10);
+        let res = drive(&mut conn, uploader.upload_with(&path, options.clone())).await;
+        assert!(matches!(res, Err(Error::WaitResponseTimeout(_))));
+        let state = UploadState::load(&state_path).await.unwrap();
+        assert_eq!(state.offset, 131072);
+        // 进度先写入临时文件再改名
+        assert!(!dir.path().join("data.bin.upload.tmp").exists());
+        assert_eq!(rx.recv().await.unwrap().offset, 131072);
+
+        // 使用 append 模式续传，最后一个分片丢失后重新请求上传并重传
+        cloud.drop_file_chunks(1, 1);
+        options.retries = 1;
+        let name = drive(&mut conn, uploader.upload_with(&path, options))
+            .await
+            .unwrap();
+        assert_eq!(name, "data.bin");
+        assert_eq!(cloud.uploaded("data.bin").unwrap(), data);
+        assert!(!state_path.exists());
+        let appends = cloud
+            .published()
+            .iter()
+            .filter(|(_, payload)| String::from_utf8_lossy(payload).contains(r#""append""#))
+            .count();
+        assert_eq!(appends, 2);
+        let mut last = 0;
+        while let Ok(progress) = rx.try_recv() {
+            last = progress.offset;
+        }
+        assert_eq!(last, 300_000);
+    }
+
+    #[tokio::test]
+    async fn 数据流上传() {
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let uploader = conn.file_uploader().unwrap();
+        uploader.init().await.unwrap();
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 13) as u8).collect();
+        let name = drive(
+            &mut conn,
+            uploader.upload_bytes("snapshot.jpg", data.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cloud.uploaded(&name).unwrap(), data);
+
+        // 长度未知时用 isComplete 标记最后一个分片
+        let name = drive(
+            &mut conn,
+            uploader.upload_reader("diag.tar.gz", &data[..1000], None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cloud.uploaded(&name).unwrap(), data[..1000].to_vec());
+        let (_, payload) = cloud.published().pop().unwrap();
+        assert!(String::from_utf8_lossy(&payload).contains(r#""isComplete":true"#));
+
+        // 按 options 的分片大小上传并发送进度
+        let (tx, mut rx) = mpsc::channel(16);
+        let options = UploadOptions {
+            chunk_size: 1024,
+            progress: Some(tx),
+            ..Default::default()
+        };
+        let name = drive(
+            &mut conn,
+            uploader.upload_reader_with("diag.log", &data[..4000], Some(4000), options),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cloud.uploaded(&name).unwrap(), data[..4000].to_vec());
+        let mut offsets = vec![];
+        while let Ok(progress) = rx.try_recv() {
+            offsets.push(progress.offset);
         }
+        assert_eq!(offsets, [1024, 2048, 3072, 4000]);
 
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        let res = uploader.upload_bytes("../etc/passwd", data).await;
+        assert!(matches!(res, Err(Error::InvalidFileName(_))));
     }
 }
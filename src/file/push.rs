@@ -1,5 +1,6 @@
 use super::base::*;
 use super::recv::*;
+use crate::alink::channel::Channel;
 use crate::alink::{AlinkRequest, AlinkResponse, ParamsRequest, SysAck};
 use crate::{Error, Result, ThreeTuple};
 use regex::Regex;
@@ -10,7 +11,7 @@ use std::fs;
 use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     pub async fn upload_init(&self, params: InitParams) -> crate::Result<InitData> {
         let payload: InitRequest = ParamsRequest::new(params);
         let topic = format!(
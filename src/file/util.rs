@@ -1,9 +1,21 @@
 use crate::{Error, Result, ThreeTuple};
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::collections::VecDeque;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 lazy_static! {
     static ref REPLACE: Regex = Regex::new(r"[^a-zA-Z0-9_\.]").unwrap();
+    static ref FILE_NAME: Regex = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_\.]{0,99}$").unwrap();
+}
+
+/// 检查上传的文件名：只包含数字、英文字母、下划线和英文句点，首字符为数字或英文字母，不超过100字节
+pub fn validate_filename(name: &str) -> Result<()> {
+    if FILE_NAME.is_match(name) {
+        Ok(())
+    } else {
+        Err(Error::InvalidFileName(name.to_string()))
+    }
 }
 
 pub fn filename_for_path(path: impl AsRef<std::path::Path>) -> Result<String> {
@@ -23,6 +35,119 @@ pub fn filename_for_path(path: impl AsRef<std::path::Path>) -> Result<String> {
     Ok(filename)
 }
 
+/// 按分片读取上传的数据，保留云端还没有确认的分片用于重传
+pub(crate) struct ChunkReader<R> {
+    reader: R,
+    /// 数据总长度，未知时为 `None`
+    size: Option<u64>,
+    /// 已经从 `reader` 读出的字节数（含起始偏移）
+    read: u64,
+    /// 下一个新分片的偏移
+    offset: u64,
+    /// 预读的分片，用于判断当前分片是否是最后一个
+    ahead: Option<Vec<u8>>,
+    done: bool,
+    /// 已经读出但云端还没有确认的分片
+    retained: VecDeque<(u64, Vec<u8>, bool)>,
+    /// 下一个发送的分片在 `retained` 中的位置
+    cursor: usize,
+}
+
+impl<R: AsyncRead + Unpin> ChunkReader<R> {
+    /// `reader` 从 `offset` 处开始读取
+    pub fn new(reader: R, offset: u64, size: Option<u64>) -> Self {
+        Self {
+            reader,
+            size,
+            read: offset,
+            offset,
+            ahead: None,
+            done: false,
+            retained: VecDeque::new(),
+            cursor: 0,
+        }
+    }
+
+    /// 下一个分片 `(偏移, 数据, 是否最后一个分片)`，没有更多数据时返回 `None`
+    pub async fn next(&mut self, chunk_size: usize) -> Result<Option<(u64, Vec<u8>, bool)>> {
+        if let Some(chunk) = self.retained.get(self.cursor) {
+            self.cursor += 1;
+            return Ok(Some(chunk.clone()));
+        }
+        if self.done {
+            return Ok(None);
+        }
+        let data = match self.ahead.take() {
+            Some(data) => data,
+            None => self.fill(chunk_size).await?,
+        };
+        if data.is_empty() {
+            self.done = true;
+            return match self.read {
+                0 => Err(Error::FileValidateFailed("文件为空".to_string())),
+                _ => Ok(None),
+            };
+        }
+        let ahead = self.fill(chunk_size).await?;
+        let last = ahead.is_empty();
+        if last {
+            self.done = true;
+        } else {
+            self.ahead = Some(ahead);
+        }
+        let chunk = (self.offset, data, last);
+        self.offset += chunk.1.len() as u64;
+        self.retained.push_back(chunk.clone());
+        self.cursor = self.retained.len();
+        Ok(Some(chunk))
+    }
+
+    /// 云端已经确认 `end` 之前的数据
+    pub fn ack(&mut self, end: u64) {
+        while let Some((offset, data, _)) = self.retained.front() {
+            if offset + data.len() as u64 > end {
+                break;
+            }
+            self.retained.pop_front();
+            self.cursor = self.cursor.saturating_sub(1);
+        }
+    }
+
+    /// 从云端记录的 `offset` 处重新发送
+    pub fn rewind(&mut self, offset: u64) -> Result<()> {
+        self.ack(offset);
+        let start = self.retained.front().map_or(self.offset, |chunk| chunk.0);
+        if start != offset {
+            return Err(Error::FileValidateFailed(format!(
+                "无法从 {offset} 续传，本地数据从 {start} 开始"
+            )));
+        }
+        self.cursor = 0;
+        Ok(())
+    }
+
+    /// 读满一个分片，读到结尾时可能不满
+    async fn fill(&mut self, chunk_size: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; chunk_size];
+        let mut len = 0;
+        while len < chunk_size {
+            let n = self.reader.read(&mut buf[len..]).await?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        buf.truncate(len);
+        self.read += len as u64;
+        if let Some(size) = self.size {
+            if self.read > size || (len < chunk_size && self.read != size) {
+                return Err(Error::SizeNotMatch(size as usize, self.read as usize));
+            }
+        }
+        Ok(buf)
+    }
+}
+
 #[test]
 fn test1() {
     assert_eq!(
@@ -53,6 +178,39 @@ fn test4() {
     );
 }
 
+#[test]
+fn test_validate_filename() {
+    assert!(validate_filename("camera_01.jpg").is_ok());
+    assert!(validate_filename("2024.log").is_ok());
+    assert!(validate_filename("_a.log").is_err());
+    assert!(validate_filename("a b.log").is_err());
+    assert!(validate_filename("").is_err());
+    assert!(validate_filename(&"a".repeat(101)).is_err());
+}
+
+#[tokio::test]
+async fn test_chunk_reader() {
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let mut reader = ChunkReader::new(&data[..], 0, None);
+    let (offset, chunk, last) = reader.next(400).await.unwrap().unwrap();
+    assert_eq!((offset, chunk.len(), last), (0, 400, false));
+    reader.next(400).await.unwrap().unwrap();
+    let (offset, chunk, last) = reader.next(400).await.unwrap().unwrap();
+    assert_eq!((offset, chunk.len(), last), (800, 200, true));
+    assert!(reader.next(400).await.unwrap().is_none());
+
+    // 云端确认了第一个分片，从 400 处重传
+    reader.ack(400);
+    reader.rewind(400).unwrap();
+    assert_eq!(reader.next(400).await.unwrap().unwrap().0, 400);
+    assert!(reader.rewind(0).is_err());
+
+    // 长度与 size_hint 不一致
+    let mut reader = ChunkReader::new(&data[..], 0, Some(900));
+    reader.next(400).await.unwrap();
+    assert!(reader.next(400).await.is_err());
+}
+
 #[tokio::test]
 async fn test_file_read() {
     let path = "./README.md";
@@ -109,8 +267,34 @@ pub fn crc_ibm(buffer: &[u8]) -> u16 {
     return crc;
 }
 
-use crc::{Algorithm, Crc, CRC_16_IBM_SDLC};
+use crc::{Algorithm, Crc, CRC_16_IBM_SDLC, CRC_64_XZ};
 pub const X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+/// 文件完整性校验使用的 CRC64，与 OSS 的 CRC64 一致
+pub const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+
+/// `ficValue` 格式的 CRC64，16 位 Hex 字符串
+pub fn fic_value(crc64: u64) -> String {
+    format!("{:016x}", crc64)
+}
+
+/// 读取整个文件，返回文件大小和 CRC64
+pub async fn file_crc64(path: impl AsRef<std::path::Path>) -> Result<(u64, u64)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut digest = CRC64.digest();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, digest.finalize()))
+}
 
 #[inline]
 pub fn crc_ibm2(buf: &[u8]) -> u16 {
@@ -123,4 +307,5 @@ fn test_crc() {
     let c1 = crc_ibm(buf);
     let c2 = crc_ibm2(buf);
     // assert_eq!(c1, c1);
+    assert_eq!(fic_value(CRC64.checksum(buf)), "995dc9bbdf1939fa");
 }
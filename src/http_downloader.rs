@@ -1,29 +1,42 @@
 //! HTTP 下载器
+//!
+//! 服务器支持 Range 请求时，文件按 `block_size` 分块，最多 `concurrency` 个分块同时下载，
+//! 每个分块边接收边写入预先分配好大小的临时文件 `<path>.part` 的对应位置，失败的分块从已写入的位置重试。
+//! 全部完成后临时文件改名为目标文件。
+//!
+//! 开启 `resume` 后，失败或取消时保留临时文件，并把各分块的进度和文件的 ETag（或 Last-Modified）
+//! 保存到 `<path>.part.json`。下次下载时只有文件没有变化才继续，之后的请求都带上 `If-Range`，
+//! 文件在下载过程中变化时返回 [`Error::ObjectChanged`]。
 
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use log::*;
-use reqwest::header::ToStrError;
-use reqwest::{Body, Client, Method, Response};
-use std::fs;
-use std::fs::{DirEntry, File, OpenOptions};
-use std::io::{Seek, Write};
+use reqwest::header::{ToStrError, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
 use std::num::ParseIntError;
-use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::{mpsc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::time::Instant;
+pub use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct DownloadProcess {
+    /// 下载进度，0 到 1
     pub percent: f64,
+    /// 文件大小，未知时为 0
     pub size: u64,
+    /// 已下载的字节数
     pub current: u64,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error(transparent)]
-    DownloadProcessSendError(#[from] SendError<DownloadProcess>),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
@@ -42,210 +55,918 @@ pub enum Error {
     InconsistentData,
     #[error("文件路径错误")]
     FilePathError,
+    #[error("下载已取消")]
+    Cancelled,
+    #[error("读取数据超时")]
+    ReadTimeout,
+    #[error("文件大小不一致，期望 {0}，实际 {1}")]
+    LengthMismatch(u64, u64),
+    #[error("文件摘要校验失败")]
+    DigestMismatch,
+    #[error("不支持的摘要算法 {0}")]
+    UnsupportedDigest(String),
+    #[error("下载过程中文件已变化")]
+    ObjectChanged,
+}
+
+/// 期望的文件摘要
+#[derive(Debug, Clone)]
+pub struct ExpectedDigest {
+    /// 摘要算法，支持 md5 和 sha256，不区分大小写
+    pub method: String,
+    /// 十六进制的摘要，不区分大小写
+    pub value: String,
 }
 
 pub struct HttpDownloadConfig {
     pub block_size: u64,
     pub uri: String,
     pub file_path: String,
+    /// 同时下载的分块数
+    pub concurrency: usize,
+    /// 每个分块失败后的重试次数
+    pub retries: u32,
+    /// 重试前等待的时间
+    pub retry_delay: Duration,
+    /// 建立连接的超时
+    pub connect_timeout: Option<Duration>,
+    /// 两次收到数据之间的最长间隔
+    pub read_timeout: Option<Duration>,
+    /// 代理地址，如 `http://127.0.0.1:8080`
+    pub proxy: Option<String>,
+    /// 额外信任的 PEM 格式根证书
+    pub root_certificates: Vec<Vec<u8>>,
+    /// 期望的文件大小，与服务器返回的不一致时不下载
+    pub expected_length: Option<u64>,
+    /// 期望的文件摘要，下载过程中按顺序计算
+    pub expected_digest: Option<ExpectedDigest>,
+    /// 下载速度上限，单位字节每秒，用于按流量计费的网络
+    pub bandwidth_limit: Option<u64>,
+    /// 失败或取消后保留临时文件，下次从断点继续
+    pub resume: bool,
+}
+
+impl HttpDownloadConfig {
+    pub fn new(url: &str, path: impl AsRef<Path>) -> Self {
+        Self {
+            block_size: 8000000,
+            uri: url.to_string(),
+            file_path: path.as_ref().to_string_lossy().to_string(),
+            concurrency: 4,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            connect_timeout: Some(Duration::from_secs(10)),
+            read_timeout: Some(Duration::from_secs(30)),
+            proxy: None,
+            root_certificates: Vec::new(),
+            expected_length: None,
+            expected_digest: None,
+            bandwidth_limit: None,
+            resume: false,
+        }
+    }
+
+    pub fn builder(url: &str, path: impl AsRef<Path>) -> HttpDownloadConfigBuilder {
+        HttpDownloadConfigBuilder {
+            config: Self::new(url, path),
+        }
+    }
+}
+
+/// [`HttpDownloadConfig`] 的构造器
+pub struct HttpDownloadConfigBuilder {
+    config: HttpDownloadConfig,
+}
+
+impl HttpDownloadConfigBuilder {
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.config.concurrency = concurrency;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.config.retries = retries;
+        self.config.retry_delay = delay;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.config.proxy = Some(proxy.to_string());
+        self
+    }
+
+    pub fn add_root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.config.root_certificates.push(pem.into());
+        self
+    }
+
+    pub fn expected_length(mut self, length: u64) -> Self {
+        self.config.expected_length = Some(length);
+        self
+    }
+
+    pub fn expected_digest(mut self, method: &str, value: &str) -> Self {
+        self.config.expected_digest = Some(ExpectedDigest {
+            method: method.to_string(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    pub fn bandwidth_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.bandwidth_limit = Some(bytes_per_second);
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.config.resume = resume;
+        self
+    }
+
+    pub fn build(self) -> HttpDownloadConfig {
+        self.config
+    }
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// 断点续传保存的状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResumeState {
+    /// 下载时文件的 ETag 或 Last-Modified
+    validator: String,
+    size: u64,
+    /// 各分块的起始位置、已写入的位置和结束位置
+    blocks: Vec<(u64, u64, u64)>,
+}
+
+enum Digester {
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
+}
+
+impl Digester {
+    fn new(method: &str) -> Result<Self> {
+        use sha2::Digest;
+        match method.to_ascii_lowercase().as_str() {
+            "md5" => Ok(Self::Md5(md5::Md5::new())),
+            "sha256" => Ok(Self::Sha256(sha2::Sha256::new())),
+            _ => Err(Error::UnsupportedDigest(method.to_string())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        use sha2::Digest;
+        match self {
+            Self::Md5(hasher) => crate::util::hex2str(&hasher.finalize()),
+            Self::Sha256(hasher) => crate::util::hex2str(&hasher.finalize()),
+        }
+    }
+}
+
+/// 按文件顺序计算摘要，分块完成的顺序不确定，只处理已经连续的部分
+struct Verifier {
+    digester: Digester,
+    offset: u64,
+    /// 已完成但还没有计算的分块，起始位置到结束位置
+    done: BTreeMap<u64, u64>,
+}
+
+/// 所有分块共享的限速器
+struct RateLimiter {
+    rate: u64,
+    next: std::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// 按速度上限等待到可以继续接收的时间
+    async fn acquire(&self, len: u64) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            *next = (*next).max(Instant::now());
+            let at = *next;
+            *next += Duration::from_secs_f64(len as f64 / self.rate as f64);
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+}
+
 pub struct HttpDownloader {
     config: HttpDownloadConfig,
     client: Client,
-    process_sender: mpsc::Sender<DownloadProcess>,
-    process_receiver: Arc<Mutex<mpsc::Receiver<DownloadProcess>>>,
+    cancel: CancellationToken,
+    downloaded: AtomicU64,
+    limiter: Option<RateLimiter>,
+    state: std::sync::Mutex<Option<ResumeState>>,
+    verifier: tokio::sync::Mutex<Option<Verifier>>,
+    process_sender: watch::Sender<DownloadProcess>,
+    process_receiver: watch::Receiver<DownloadProcess>,
 }
 
 impl HttpDownloader {
     pub fn new(url: &str, path: impl AsRef<Path>) -> Self {
-        let file_path = path.as_ref().to_string_lossy().to_string();
-        let config = HttpDownloadConfig {
-            block_size: 8000000,
-            uri: url.to_string(),
-            file_path,
-        };
-        let (tx, rx) = mpsc::channel(10);
-        Self {
+        Self::with_config(HttpDownloadConfig::new(url, path)).expect("创建 HTTP 客户端失败")
+    }
+
+    /// 代理地址、证书或摘要算法错误时返回错误
+    pub fn with_config(config: HttpDownloadConfig) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        for pem in &config.root_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        if let Some(digest) = &config.expected_digest {
+            Digester::new(&digest.method)?;
+        }
+        let limiter = config.bandwidth_limit.map(|rate| RateLimiter {
+            rate: rate.max(1),
+            next: std::sync::Mutex::new(Instant::now()),
+        });
+        let (tx, rx) = watch::channel(DownloadProcess::default());
+        Ok(Self {
             config,
-            client: Client::new(),
-            process_receiver: Arc::new(Mutex::new(rx)),
+            client: builder.build()?,
+            cancel: CancellationToken::new(),
+            downloaded: AtomicU64::new(0),
+            limiter,
+            state: std::sync::Mutex::new(None),
+            verifier: tokio::sync::Mutex::new(None),
+            process_receiver: rx,
             process_sender: tx,
+        })
+    }
+
+    /// 下载进度，只保留最新的值，不读取也不会阻塞下载
+    pub fn get_process_receiver(&self) -> watch::Receiver<DownloadProcess> {
+        self.process_receiver.clone()
+    }
+
+    /// 取消下载的令牌，取消后 [`start`](Self::start) 返回 [`Error::Cancelled`]，
+    /// 没有开启 `resume` 时删除临时文件
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// 使用外部的取消令牌，例如同时取消多个下载
+    pub fn set_cancel_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    /// 下载文件，返回文件路径
+    pub async fn start(&self) -> Result<String> {
+        let part = self.part_path();
+        self.downloaded.store(0, Ordering::Relaxed);
+        let result = tokio::select! {
+            result = self.run(&part) => result,
+            _ = self.cancel.cancelled() => Err(Error::Cancelled),
+        };
+        let state = self.state.lock().unwrap().take();
+        match result {
+            Ok(()) => {
+                fs::remove_file(self.state_path()).await.ok();
+                fs::rename(&part, &self.config.file_path).await?;
+                Ok(self.config.file_path.clone())
+            }
+            Err(err) => {
+                // 还没有开始下载分块时保留上次的进度，
+                // 没有 ETag 和 Last-Modified 时无法判断文件是否变化，不保留
+                let keep = self.config.resume
+                    && resumable(&err)
+                    && match state {
+                        Some(state) if state.validator.is_empty() => false,
+                        Some(state) => self.save_state(&state).await,
+                        None => true,
+                    };
+                if !keep {
+                    fs::remove_file(&part).await.ok();
+                    fs::remove_file(self.state_path()).await.ok();
+                }
+                Err(err)
+            }
         }
     }
 
-    async fn download_block(&self, start: u64, end: u64) -> Result<Response> {
-        let request = self
-            .client
-            .request(Method::GET, &self.config.uri)
-            .header("range", format!("bytes={}-{}", start, end))
-            .build()?;
-        let response = self.client.execute(request).await?;
-        Ok(response)
+    fn part_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.part", self.config.file_path))
     }
-    async fn download_block_and_write(
-        &self,
-        size: u64,
-        index: u64,
-        start: u64,
-        end: u64,
-    ) -> Result<()> {
+
+    fn state_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.part.json", self.config.file_path))
+    }
+
+    async fn save_state(&self, state: &ResumeState) -> bool {
+        let data = match serde_json::to_vec(state) {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        match fs::write(self.state_path(), data).await {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("save download state error: {}", err);
+                false
+            }
+        }
+    }
+
+    /// 读取上次保存的状态，文件已变化或临时文件不完整时返回 `None`
+    async fn load_state(&self, part: &Path, validator: &str, size: u64) -> Option<ResumeState> {
+        let data = fs::read(self.state_path()).await.ok()?;
+        let state: ResumeState = serde_json::from_slice(&data).ok()?;
+        if state.validator != validator || state.size != size {
+            info!("文件已变化，重新下载");
+            return None;
+        }
+        let len = fs::metadata(part).await.ok()?.len();
+        (len == size).then_some(state)
+    }
+
+    async fn run(&self, part: &Path) -> Result<()> {
+        // 部分服务器不允许 HEAD 请求，用只请求第一个字节的 GET 代替
+        let request = self.client.get(&self.config.uri).header(RANGE, "bytes=0-0");
+        let response = self.send(request).await?;
+        let size = match total_size(&response)? {
+            Some(size) => size,
+            None => {
+                debug!("不支持分块下载");
+                return self.download(response, part).await;
+            }
+        };
+        if size == 0 {
+            return Err(Error::EmptyData);
+        }
+        if let Some(expected) = self.config.expected_length {
+            if expected != size {
+                return Err(Error::LengthMismatch(expected, size));
+            }
+        }
+        let validator = validator(&response);
+        drop(response);
+
+        let resumed = match (&validator, self.config.resume) {
+            (Some(validator), true) => self.load_state(part, validator, size).await,
+            _ => None,
+        };
+        let state = match resumed {
+            Some(state) => {
+                info!("从断点继续下载");
+                state
+            }
+            None => {
+                let file = File::create(part).await?;
+                file.set_len(size).await?;
+                let block_size = self.config.block_size.max(1);
+                ResumeState {
+                    validator: validator.clone().unwrap_or_default(),
+                    size,
+                    blocks: (0..size)
+                        .step_by(block_size as usize)
+                        .map(|start| (start, start, (start + block_size).min(size)))
+                        .collect(),
+                }
+            }
+        };
+        let written: u64 = state.blocks.iter().map(|(s, o, _)| o - s).sum();
+        self.progress(size, written);
+        if let Some(digest) = &self.config.expected_digest {
+            *self.verifier.lock().await = Some(Verifier {
+                digester: Digester::new(&digest.method)?,
+                offset: 0,
+                done: state
+                    .blocks
+                    .iter()
+                    .filter(|(_, offset, end)| offset == end)
+                    .map(|(start, _, end)| (*start, *end))
+                    .collect(),
+            });
+        }
+        let pending: Vec<_> = state
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, offset, end))| offset < end)
+            .map(|(index, _)| index)
+            .collect();
         debug!(
-            "download_block_and_write start {} start:{},end:{}",
-            index + 1,
-            start,
-            end
+            "文件大小 {}，分为 {} 块，需要下载 {} 块",
+            size,
+            state.blocks.len(),
+            pending.len()
         );
-        let response = self.download_block(start, end).await?;
-        let file_name = format!("{}{}", &self.config.file_path, index);
-
-        let bytes = response.bytes().await?;
-        fs::write(&file_name, bytes.iter())?;
-        debug!("write {}", file_name);
-
-        self.process_sender
-            .send(DownloadProcess {
-                percent: (end / size) as f64,
-                size,
-                current: end,
+        *self.state.lock().unwrap() = Some(state);
+        self.verify(part).await?;
+
+        let if_range = validator.as_deref();
+        futures_util::stream::iter(pending.into_iter().map(Ok))
+            .try_for_each_concurrent(self.config.concurrency.max(1), |index| {
+                self.download_block(part, size, index, if_range)
             })
             .await?;
+        if self.downloaded.load(Ordering::Relaxed) != size {
+            return Err(Error::InconsistentData);
+        }
+        self.check_digest().await
+    }
 
-        debug!(
-            "download_block_and_write end {} start:{},end:{}",
-            index + 1,
-            start,
-            end
-        );
+    /// 下载一个分块，失败后从已写入的位置继续
+    async fn download_block(
+        &self,
+        part: &Path,
+        size: u64,
+        index: usize,
+        if_range: Option<&str>,
+    ) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(part).await?;
+        let mut attempt = 0;
+        loop {
+            match self.write_range(&mut file, size, index, if_range).await {
+                Ok(()) => break,
+                Err(err) if attempt < self.config.retries && resumable(&err) => {
+                    attempt += 1;
+                    warn!("download block {} error: {}, retry {}", index, err, attempt);
+                    tokio::time::sleep(self.config.retry_delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let (start, _, end) = self.block(index);
+        if let Some(verifier) = self.verifier.lock().await.as_mut() {
+            verifier.done.insert(start, end);
+        }
+        self.verify(part).await
+    }
+
+    /// 分块的起始位置、已写入的位置和结束位置
+    fn block(&self, index: usize) -> (u64, u64, u64) {
+        self.state.lock().unwrap().as_ref().unwrap().blocks[index]
+    }
+
+    async fn write_range(
+        &self,
+        file: &mut File,
+        size: u64,
+        index: usize,
+        if_range: Option<&str>,
+    ) -> Result<()> {
+        let (_, mut offset, end) = self.block(index);
+        let mut request = self
+            .client
+            .get(&self.config.uri)
+            .header(RANGE, format!("bytes={}-{}", offset, end - 1));
+        if let Some(if_range) = if_range {
+            request = request.header(IF_RANGE, if_range);
+        }
+        let response = self.send(request).await?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // 带 If-Range 时返回整个文件，说明文件已变化
+            StatusCode::OK if if_range.is_some() => return Err(Error::ObjectChanged),
+            _ => return Err(Error::InconsistentData),
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = self.next_chunk(&mut stream).await? {
+            if offset + chunk.len() as u64 > end {
+                return Err(Error::InconsistentData);
+            }
+            file.write_all(&chunk).await?;
+            offset += chunk.len() as u64;
+            if let Some(state) = self.state.lock().unwrap().as_mut() {
+                state.blocks[index].1 = offset;
+            }
+            self.progress(size, chunk.len() as u64);
+        }
+        file.flush().await?;
+        if offset != end {
+            return Err(Error::InconsistentData);
+        }
         Ok(())
     }
 
-    async fn download(&self) -> Result<String> {
-        let request = self.client.get(&self.config.uri).build()?;
-        let response = self.client.execute(request).await?;
-        let file_name = &self.config.file_path;
+    /// 发送请求，超过 `read_timeout` 没有响应时返回错误
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = match self.config.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request.send())
+                .await
+                .map_err(|_| Error::ReadTimeout)??,
+            None => request.send().await?,
+        };
+        Ok(response.error_for_status()?)
+    }
 
-        let bytes = response.bytes().await?;
-        let bytes = bytes.to_vec();
+    /// 读取下一段数据，超过 `read_timeout` 没有数据时返回错误，开启限速时等待
+    async fn next_chunk<S, T>(&self, stream: &mut S) -> Result<Option<T>>
+    where
+        S: Stream<Item = reqwest::Result<T>> + Unpin,
+        T: AsRef<[u8]>,
+    {
+        let chunk = match self.config.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                .await
+                .map_err(|_| Error::ReadTimeout)?,
+            None => stream.next().await,
+        };
+        let chunk = chunk.transpose()?;
+        if let (Some(limiter), Some(chunk)) = (&self.limiter, &chunk) {
+            limiter.acquire(chunk.as_ref().len() as u64).await;
+        }
+        Ok(chunk)
+    }
 
-        let mut result_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(file_name)?;
-        result_file.write_all(&bytes);
-        result_file.flush();
-
-        let size = bytes.len() as u64;
-        self.process_sender
-            .send(DownloadProcess {
-                percent: 1f64,
-                size,
-                current: size,
-            })
-            .await?;
-        Ok(file_name.clone())
+    /// 计算已经连续完成的部分的摘要
+    async fn verify(&self, part: &Path) -> Result<()> {
+        let mut verifier = self.verifier.lock().await;
+        let verifier = match verifier.as_mut() {
+            Some(verifier) => verifier,
+            None => return Ok(()),
+        };
+        let mut buf = vec![0; 64 * 1024];
+        while let Some(end) = verifier.done.remove(&verifier.offset) {
+            let mut file = File::open(part).await?;
+            file.seek(SeekFrom::Start(verifier.offset)).await?;
+            let mut remaining = (end - verifier.offset) as usize;
+            while remaining > 0 {
+                let len = remaining.min(buf.len());
+                file.read_exact(&mut buf[..len]).await?;
+                verifier.digester.update(&buf[..len]);
+                remaining -= len;
+            }
+            verifier.offset = end;
+        }
+        Ok(())
     }
 
-    /// 合并文件
-    fn merge(&self, size: u64) -> Result<String> {
-        let file_path = std::path::Path::new(&self.config.file_path);
-        let dirs = fs::read_dir(&file_path.parent().ok_or(Error::FilePathError)?)?;
+    async fn check_digest(&self) -> Result<()> {
+        let verifier = match self.verifier.lock().await.take() {
+            Some(verifier) => verifier,
+            None => return Ok(()),
+        };
+        let expected = self.config.expected_digest.as_ref().unwrap();
+        if !verifier.done.is_empty()
+            || !verifier
+                .digester
+                .finish()
+                .eq_ignore_ascii_case(&expected.value)
+        {
+            return Err(Error::DigestMismatch);
+        }
+        Ok(())
+    }
 
-        let file_name = &self.config.file_path;
-        let mut result_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(file_path)?;
-        let count = dirs
-            .filter(|n| n.is_ok())
-            .map(|n| n.unwrap())
-            .filter(|n| n.path().to_str().unwrap().starts_with(file_name))
-            .count();
-        let mut result_size = 0;
-        for i in 0..count {
-            let path = format!("{}{}", file_name, i);
-            let bytes = &fs::read(&path)?;
-            result_size += bytes.len();
-            result_file.write(bytes);
-            // 删除临时文件
-            fs::remove_file(&path)?;
-            debug!("remove {}", path)
-        }
-        result_file.flush()?;
-        if size as usize == result_size {
-            debug!("合并文件完成");
-        } else {
+    /// 不支持分块时，直接读取整个响应
+    async fn download(&self, response: Response, part: &Path) -> Result<()> {
+        let size = response.content_length().unwrap_or(0);
+        if let Some(expected) = self.config.expected_length {
+            if size != 0 && size != expected {
+                return Err(Error::LengthMismatch(expected, size));
+            }
+        }
+        let mut digester = match &self.config.expected_digest {
+            Some(digest) => Some(Digester::new(&digest.method)?),
+            None => None,
+        };
+        let mut file = File::create(part).await?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = self.next_chunk(&mut stream).await? {
+            file.write_all(&chunk).await?;
+            if let Some(digester) = digester.as_mut() {
+                digester.update(&chunk);
+            }
+            self.progress(size, chunk.len() as u64);
+        }
+        file.flush().await?;
+        let current = self.downloaded.load(Ordering::Relaxed);
+        if current == 0 {
+            return Err(Error::EmptyData);
+        }
+        if size != 0 && current != size {
             return Err(Error::InconsistentData);
         }
-        Ok(file_name.clone())
+        if let Some(expected) = self.config.expected_length {
+            if current != expected {
+                return Err(Error::LengthMismatch(expected, current));
+            }
+        }
+        if let (Some(digester), Some(expected)) = (digester, &self.config.expected_digest) {
+            if !digester.finish().eq_ignore_ascii_case(&expected.value) {
+                return Err(Error::DigestMismatch);
+            }
+        }
+        self.process_sender.send_replace(DownloadProcess {
+            percent: 1.0,
+            size: current,
+            current,
+        });
+        Ok(())
     }
 
-    pub fn get_process_receiver(&self) -> Arc<Mutex<Receiver<DownloadProcess>>> {
-        self.process_receiver.clone()
+    fn progress(&self, size: u64, len: u64) {
+        let current = self.downloaded.fetch_add(len, Ordering::Relaxed) + len;
+        let percent = if size == 0 {
+            0.0
+        } else {
+            current as f64 / size as f64
+        };
+        self.process_sender.send_replace(DownloadProcess {
+            percent,
+            size,
+            current,
+        });
     }
+}
 
-    // pub async fn run(self: Arc<Self>) -> Result<()> {
-    pub async fn start(&self) -> Result<String> {
-        // 403
-        // let request = self.client.request(Method::HEAD, &self.config.uri)
-        // 	.build()?;
-        let request = self
-            .client
-            .request(Method::GET, &self.config.uri)
-            .header("range", "bytes=0-0")
-            .build()?;
-        let response = self.client.execute(request).await?;
-        let headers = response.headers();
-        let accept_ranges = headers.get("accept-ranges");
-        let content_length = headers.get("content-length");
-        let accept_ranges_flag = match accept_ranges {
-            None => false,
-            Some(v) => v.to_str()?.eq("bytes"),
+/// 出错后已下载的数据还可以继续使用
+fn resumable(err: &Error) -> bool {
+    !matches!(
+        err,
+        Error::InconsistentData
+            | Error::LengthMismatch(..)
+            | Error::DigestMismatch
+            | Error::UnsupportedDigest(_)
+            | Error::ObjectChanged
+    )
+}
+
+/// 从 `Content-Range: bytes 0-0/size` 中读取文件大小，服务器忽略 Range 时返回 `None`
+fn total_size(response: &Response) -> Result<Option<u64>> {
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+    let range = match response.headers().get(CONTENT_RANGE) {
+        Some(range) => range.to_str()?,
+        None => return Ok(None),
+    };
+    match range.rsplit_once('/') {
+        Some((_, "*")) | None => Ok(None),
+        Some((_, size)) => Ok(Some(size.trim().parse()?)),
+    }
+}
+
+/// 用于 `If-Range` 的强 ETag，没有时使用 Last-Modified
+fn validator(response: &Response) -> Option<String> {
+    let headers = response.headers();
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"))
+        .or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{serve_http, HttpResponse};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Server {
+        body: Vec<u8>,
+        etag: String,
+        /// 每个分块的第一次请求只返回一半数据后断开
+        fail: bool,
+        failed: HashSet<usize>,
+        /// 只回复探测请求，分块请求不回复
+        hang: bool,
+        requests: usize,
+    }
+
+    /// 支持 Range 和 If-Range 的 HTTP 服务器
+    async fn serve(server: Arc<Mutex<Server>>) -> String {
+        let host = serve_http(move |request| {
+            let range = request.header("range").map(|value| {
+                let value = value.trim_start_matches("bytes=");
+                let (start, end) = value.split_once('-').unwrap();
+                (
+                    start.parse::<usize>().unwrap(),
+                    end.parse::<usize>().unwrap(),
+                )
+            });
+            let mut server = server.lock().unwrap();
+            if server.hang && range != Some((0, 0)) {
+                return HttpResponse::hang();
+            }
+            server.requests += 1;
+            let len = server.body.len();
+            // If-Range 不匹配时返回整个文件
+            let if_range = request.header("if-range");
+            let range = range.filter(|_| if_range.iter().all(|v| *v == server.etag));
+            let (start, end) = range.unwrap_or((0, len - 1));
+            let mut response = HttpResponse::ok(&server.body[start..=end])
+                .header("etag", &server.etag)
+                .header("connection", "close");
+            if range.is_some() {
+                response = response
+                    .status("206 Partial Content")
+                    .header("content-range", format!("bytes {}-{}/{}", start, end, len));
+            }
+            let size = response.body.len();
+            if server.fail && size > 1 && server.failed.insert(end) {
+                response.truncate = Some(size / 2);
+            }
+            response
+        })
+        .await;
+        format!("http://{}/file", host)
+    }
+
+    fn body(seed: u32) -> Vec<u8> {
+        (0..100_000u32).map(|i| ((i + seed) % 251) as u8).collect()
+    }
+
+    fn server(fail: bool) -> Arc<Mutex<Server>> {
+        Arc::new(Mutex::new(Server {
+            body: body(0),
+            etag: "\"v1\"".to_string(),
+            fail,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_download_blocks() {
+        let dir = tempdir::TempDir::new("download").unwrap();
+        let path = dir.path().join("file.bin");
+        let server = server(true);
+        let url = serve(server.clone()).await;
+
+        let config = HttpDownloadConfig::builder(&url, &path)
+            .block_size(30_000)
+            .concurrency(2)
+            .retries(3, Duration::ZERO)
+            .expected_length(100_000)
+            .expected_digest("SHA256", &crate::util::sha256(&body(0)))
+            .build();
+        let downloader = HttpDownloader::with_config(config).unwrap();
+        let progress = downloader.get_process_receiver();
+        let result = downloader.start().await.unwrap();
+        assert_eq!(std::fs::read(result).unwrap(), body(0));
+        assert!(!downloader.part_path().exists());
+        // 探测请求 + 4 个分块，每个分块重试一次
+        assert_eq!(server.lock().unwrap().requests, 9);
+        let progress = *progress.borrow();
+        assert_eq!(progress.current, 100_000);
+        assert_eq!(progress.percent, 1.0);
+
+        // 摘要或大小不一致时删除临时文件
+        let config = HttpDownloadConfig::builder(&url, dir.path().join("digest.bin"))
+            .expected_digest("md5", "00")
+            .build();
+        let downloader = HttpDownloader::with_config(config).unwrap();
+        assert!(matches!(
+            downloader.start().await,
+            Err(Error::DigestMismatch)
+        ));
+        assert!(!downloader.part_path().exists());
+        let config = HttpDownloadConfig::builder(&url, &path)
+            .expected_length(10)
+            .build();
+        let downloader = HttpDownloader::with_config(config).unwrap();
+        assert!(matches!(
+            downloader.start().await,
+            Err(Error::LengthMismatch(10, 100_000))
+        ));
+        let config = HttpDownloadConfig::builder(&url, &path)
+            .expected_digest("crc32", "00")
+            .build();
+        assert!(matches!(
+            HttpDownloader::with_config(config),
+            Err(Error::UnsupportedDigest(_))
+        ));
+
+        // 取消后删除临时文件
+        let downloader = HttpDownloader::new(&url, dir.path().join("cancel.bin"));
+        downloader.cancel_token().cancel();
+        assert!(matches!(downloader.start().await, Err(Error::Cancelled)));
+        assert!(!downloader.part_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_resume() {
+        let dir = tempdir::TempDir::new("download").unwrap();
+        let path = dir.path().join("file.bin");
+        let server = server(true);
+        let url = serve(server.clone()).await;
+        let config = || {
+            HttpDownloadConfig::builder(&url, &path)
+                .block_size(50_000)
+                .retries(0, Duration::ZERO)
+                .expected_digest("md5", &crate::util::md5(&body(0)))
+                .resume(true)
+                .build()
         };
-        if accept_ranges_flag && content_length.is_some() {
-            debug!("支持并发下载");
-            let size = content_length.unwrap().to_str()?.parse::<u64>()?;
-            if size == 0 {
-                debug!("数据为空");
-                return Err(Error::EmptyData);
-            }
-            let t_size = size / self.config.block_size;
-            if t_size <= 1 {
-                debug!("数据分片 <= 1，单线程下载");
-                return self.download().await;
-            }
-            let first_attach = size % self.config.block_size;
-            debug!("数据块长度 {}", size);
-            info!("启用 {} 个线程下载", t_size);
-
-            let mut futures = vec![Box::pin(self.download_block_and_write(
-                size,
-                0,
-                0,
-                self.config.block_size - 1 + first_attach,
-            ))];
-            for i in 1..t_size {
-                let start = i * self.config.block_size + first_attach;
-
-                let t = self.download_block_and_write(
-                    size,
-                    i,
-                    start,
-                    start + self.config.block_size - 1,
-                );
-                futures.push(Box::pin(t))
-            }
-            let results = futures_util::future::join_all(futures.into_iter()).await;
-            for result in results {
-                result?
-            }
-            debug!("下载完成，开始合并文件");
-            Ok(self.merge(size)?)
-        } else {
-            debug!("不支持并发下载");
-            Ok(self.download().await?)
+
+        // 分块只下载了一部分，保留临时文件
+        let downloader = HttpDownloader::with_config(config()).unwrap();
+        assert!(downloader.start().await.is_err());
+        assert!(downloader.part_path().exists());
+        assert!(downloader.state_path().exists());
+
+        // 从断点继续
+        {
+            let mut server = server.lock().unwrap();
+            server.fail = false;
+            server.requests = 0;
         }
+        let downloader = HttpDownloader::with_config(config()).unwrap();
+        let progress = downloader.get_process_receiver();
+        let result = downloader.start().await.unwrap();
+        assert_eq!(std::fs::read(&result).unwrap(), body(0));
+        assert!(!downloader.state_path().exists());
+        assert_eq!(progress.borrow().current, 100_000);
+        assert!(server.lock().unwrap().requests <= 3);
+
+        // 文件变化后重新下载，限速 1MB/s
+        let mut state = ResumeState {
+            validator: "\"v1\"".to_string(),
+            size: 100_000,
+            blocks: vec![(0, 50_000, 50_000), (50_000, 50_000, 100_000)],
+        };
+        let downloader = HttpDownloader::with_config(config()).unwrap();
+        downloader.save_state(&state).await;
+        std::fs::write(downloader.part_path(), body(0)).unwrap();
+        {
+            let mut server = server.lock().unwrap();
+            server.body = body(1);
+            server.etag = "\"v2\"".to_string();
+        }
+        let mut config = config();
+        config.expected_digest = None;
+        config.bandwidth_limit = Some(1_000_000);
+        let downloader = HttpDownloader::with_config(config).unwrap();
+        let start = Instant::now();
+        downloader.start().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body(1));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // 下载过程中文件变化
+        state.validator = "\"v2\"".to_string();
+        std::fs::write(downloader.part_path(), body(1)).unwrap();
+        *downloader.state.lock().unwrap() = Some(state);
+        server.lock().unwrap().etag = "\"v3\"".to_string();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(downloader.part_path())
+            .await
+            .unwrap();
+        assert!(matches!(
+            downloader
+                .write_range(&mut file, 100_000, 1, Some("\"v2\""))
+                .await,
+            Err(Error::ObjectChanged)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let dir = tempdir::TempDir::new("download").unwrap();
+        let config = |url: &str, name: &str| {
+            HttpDownloadConfig::builder(url, dir.path().join(name))
+                .retries(0, Duration::ZERO)
+                .read_timeout(Duration::from_millis(100))
+                .build()
+        };
+
+        // 接受连接后不回复探测请求
+        let host = serve_http(|_| HttpResponse::hang()).await;
+        let url = format!("http://{}/file", host);
+        let downloader = HttpDownloader::with_config(config(&url, "probe.bin")).unwrap();
+        assert!(matches!(downloader.start().await, Err(Error::ReadTimeout)));
+
+        // 不回复分块请求
+        let server = server(false);
+        server.lock().unwrap().hang = true;
+        let url = serve(server).await;
+        let downloader = HttpDownloader::with_config(config(&url, "block.bin")).unwrap();
+        assert!(matches!(downloader.start().await, Err(Error::ReadTimeout)));
     }
 }
@@ -1,10 +1,13 @@
 //! HTTPS 接入。
 
 use crate::util::auth;
-use crate::ThreeTuple;
+use crate::util::clock::{default_clock, Clock};
+use crate::{DataModelMsg, ThreeTuple};
 use log::*;
 use reqwest::{Certificate, ClientBuilder};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
 
 type Result<T> = core::result::Result<T, HttpError>;
 
@@ -13,8 +16,40 @@ pub struct Http {
     pub host: String,
     pub three: ThreeTuple,
     pub token: Option<String>,
+    /// 获取 token 时时钟的单调时间
+    pub token_time: Duration,
     client: reqwest::Client,
+    /// 请求使用的协议，测试时使用 `http`
+    scheme: &'static str,
     pub extend: Option<String>,
+    pub options: HttpOptions,
+}
+
+/// HTTP 上行通道设置
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// token 的有效时长，单位秒。云端 token 有效期为 48 小时，超过该时长后会在发送前重新鉴权。
+    pub token_lifetime: u64,
+    /// 触发流控限制（`RequestTooMany`）后的最大重试次数
+    pub max_retries: u32,
+    /// 流控重试的初始等待时间，之后每次重试翻倍
+    pub backoff: Duration,
+    /// 流控重试的最长等待时间
+    pub max_backoff: Duration,
+    /// token 有效期和签名时间戳使用的时钟
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            token_lifetime: 47 * 3600,
+            max_retries: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            clock: default_clock(),
+        }
+    }
 }
 
 impl Http {
@@ -28,8 +63,11 @@ impl Http {
             host: host.to_string(),
             three: three.clone(),
             token: None,
+            token_time: Duration::ZERO,
             client,
+            scheme: "https",
             extend: None,
+            options: HttpOptions::default(),
         })
     }
 
@@ -43,7 +81,8 @@ impl Http {
 
     pub async fn auth(&mut self) -> crate::Result<()> {
         let url = format!(
-            "https://{}/auth?_v={}&{}",
+            "{}://{}/auth?_v={}&{}",
+            self.scheme,
             self.host,
             *crate::util::CORE_SDK_VERSION,
             self.extend_devinfo()
@@ -68,13 +107,93 @@ impl Http {
         // let res: InfoToken = res.try_into()?;
         // debug!("{:?}", res);
         self.token = Some(res.get(true, "token")?);
+        self.token_time = self.options.clock.monotonic();
         Ok(())
     }
 
-    // 返回 messageId
+    /// token 不存在或已超过有效时长
+    pub fn token_expired(&self) -> bool {
+        self.token.is_none()
+            || self.options.clock.monotonic()
+                >= self.token_time + Duration::from_secs(self.options.token_lifetime)
+    }
+
+    /// 上报数据到指定 topic，返回 messageId。
+    ///
+    /// 没有 token 或 token 过期时会自动调用 [`Http::auth`]；
+    /// 云端返回 token 失效（20001/20003）时重新鉴权后重发一次；
+    /// 触发流控限制时按 [`HttpOptions`] 退避重试。
     pub async fn send(&mut self, topic: &str, data: &[u8]) -> crate::Result<String> {
+        let mut refreshed = false;
+        let mut retries = 0;
+        let mut backoff = self.options.backoff;
+        loop {
+            if self.token_expired() {
+                self.auth().await?;
+                refreshed = true;
+            }
+            match self.send_once(topic, data).await {
+                Err(crate::Error::HttpError(
+                    HttpError::TokenIsExpired | HttpError::CheckTokenError | HttpError::TokenIsNull,
+                )) if !refreshed => {
+                    debug!("token 失效，重新鉴权");
+                    self.token = None;
+                }
+                Err(crate::Error::HttpError(HttpError::RequestTooMany))
+                    if retries < self.options.max_retries =>
+                {
+                    retries += 1;
+                    warn!("流控限制，{:?} 后第 {} 次重试", backoff, retries);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.options.max_backoff);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// 依次上报多条数据，返回每条数据各自的结果。
+    pub async fn send_batch(
+        &mut self,
+        messages: &[(String, Vec<u8>)],
+    ) -> Vec<crate::Result<String>> {
+        let mut results = Vec::with_capacity(messages.len());
+        for (topic, data) in messages {
+            results.push(self.send(topic, data).await);
+        }
+        results
+    }
+
+    /// 通过 HTTP 发送物模型消息，返回 messageId。
+    ///
+    /// HTTP 通道没有下行消息，所以消息总是以不需要云端应答的方式发送。
+    pub async fn send_dm(&mut self, data: DataModelMsg) -> crate::Result<String> {
+        let (topic, payload) = self.dm_payload(data)?;
+        self.send(&topic, &payload).await
+    }
+
+    /// 依次发送多条物模型消息，返回每条消息各自的结果。
+    pub async fn send_dm_batch(&mut self, data: Vec<DataModelMsg>) -> Vec<crate::Result<String>> {
+        let mut results = Vec::with_capacity(data.len());
+        for msg in data {
+            results.push(self.send_dm(msg).await);
+        }
+        results
+    }
+
+    fn dm_payload(&self, mut data: DataModelMsg) -> crate::Result<(String, Vec<u8>)> {
+        if data.product_key.is_none() {
+            data.product_key = Some(self.three.product_key.to_string());
+        }
+        if data.device_name.is_none() {
+            data.device_name = Some(self.three.device_name.to_string());
+        }
+        data.to_payload(0)
+    }
+
+    async fn send_once(&self, topic: &str, data: &[u8]) -> crate::Result<String> {
         let token = self.token.clone().ok_or(HttpError::TokenIsNull)?;
-        let url = format!("https://{}/topic{}", self.host, topic);
+        let url = format!("{}://{}/topic{}", self.scheme, self.host, topic);
         let res = self
             .client
             .post(url)
@@ -169,3 +288,146 @@ pub enum HttpError {
     #[error("未知错误 {1} 错误码 {0}")]
     Unknown(i32, String),
 }
+
+#[test]
+fn test_token_expired() {
+    use crate::util::clock::ManualClock;
+
+    let clock = ManualClock::new(0);
+    let mut http = test_http("localhost");
+    http.options.clock = clock.clone();
+    http.token = Some("token".to_string());
+    http.token_time = clock.monotonic();
+    assert!(!http.token_expired());
+    // 墙上时间回拨不影响 token 有效期
+    clock.set_millis(-1_000_000);
+    clock.advance(Duration::from_secs(http.options.token_lifetime - 1));
+    assert!(!http.token_expired());
+    clock.advance(Duration::from_secs(1));
+    assert!(http.token_expired());
+}
+
+/// 按顺序返回 `replies` 的 HTTP 服务器，记录每个请求的路径和 `password` 头
+#[cfg(test)]
+async fn serve_replies(
+    replies: Vec<serde_json::Value>,
+) -> (String, Arc<std::sync::Mutex<Vec<(String, String)>>>) {
+    use crate::mock::testing::{serve_http, HttpResponse};
+
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let replies = std::sync::Mutex::new(
+        replies
+            .into_iter()
+            .collect::<std::collections::VecDeque<_>>(),
+    );
+    let log = requests.clone();
+    let host = serve_http(move |request| {
+        let path = request.path.split('?').next().unwrap().to_string();
+        let password = request.header("password").unwrap_or_default().to_string();
+        log.lock().unwrap().push((path, password));
+        let reply = replies.lock().unwrap().pop_front().unwrap().to_string();
+        HttpResponse::ok(reply).header("content-type", "application/json")
+    })
+    .await;
+    (host, requests)
+}
+
+#[cfg(test)]
+fn test_http(host: &str) -> Http {
+    let three = ThreeTuple {
+        product_key: "pk".to_string(),
+        device_name: "dn".to_string(),
+        device_secret: "ds".to_string(),
+    };
+    let mut http = Http::new_tls(host, &three).unwrap();
+    http.scheme = "http";
+    http
+}
+
+#[tokio::test]
+async fn test_send_auth() {
+    use serde_json::json;
+
+    let token =
+        |token: &str| json!({ "code": 0, "message": "success", "info": { "token": token } });
+    let message =
+        |id: &str| json!({ "code": 0, "message": "success", "info": { "messageId": id } });
+    let error = |code: i32| json!({ "code": code, "message": "error" });
+    let (host, requests) = serve_replies(vec![
+        // 没有 token 时先鉴权
+        token("t1"),
+        message("m1"),
+        // token 失效时重新鉴权后重发
+        error(20001),
+        token("t2"),
+        message("m2"),
+        // 重新鉴权后仍然失效时不再重试
+        error(20003),
+        token("t3"),
+        error(20003),
+    ])
+    .await;
+    let mut http = test_http(&host);
+    let take = || std::mem::take(&mut *requests.lock().unwrap());
+    let request = |path: &str, password: &str| (path.to_string(), password.to_string());
+
+    assert_eq!(http.send("/a", b"1").await.unwrap(), "m1");
+    assert_eq!(take(), [request("/auth", ""), request("/topic/a", "t1")]);
+
+    assert_eq!(http.send("/a", b"2").await.unwrap(), "m2");
+    assert_eq!(
+        take(),
+        [
+            request("/topic/a", "t1"),
+            request("/auth", ""),
+            request("/topic/a", "t2")
+        ]
+    );
+
+    assert!(matches!(
+        http.send("/a", b"3").await,
+        Err(crate::Error::HttpError(HttpError::CheckTokenError))
+    ));
+    assert_eq!(
+        take(),
+        [
+            request("/topic/a", "t2"),
+            request("/auth", ""),
+            request("/topic/a", "t3")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_send_backoff() {
+    use serde_json::json;
+
+    let too_many = json!({ "code": 40000, "message": "request too many" });
+    let (host, requests) = serve_replies(vec![
+        json!({ "code": 0, "message": "success", "info": { "token": "t1" } }),
+        too_many.clone(),
+        too_many.clone(),
+        json!({ "code": 0, "message": "success", "info": { "messageId": "m1" } }),
+        too_many.clone(),
+        too_many.clone(),
+        too_many.clone(),
+    ])
+    .await;
+    let mut http = test_http(&host);
+    http.options.max_retries = 2;
+    http.options.backoff = Duration::from_millis(20);
+    http.options.max_backoff = Duration::from_millis(30);
+
+    // 每次重试的等待时间翻倍，不超过 max_backoff
+    let start = std::time::Instant::now();
+    assert_eq!(http.send("/a", b"1").await.unwrap(), "m1");
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(requests.lock().unwrap().len(), 4);
+
+    // 超过最大重试次数后返回流控错误
+    assert!(matches!(
+        http.send("/a", b"2").await,
+        Err(crate::Error::HttpError(HttpError::RequestTooMany))
+    ));
+    assert_eq!(requests.lock().unwrap().len(), 7);
+}
@@ -7,26 +7,35 @@
 //!
 //! 遵循阿里云物联网平台定义的 [Alink 协议](https://help.aliyun.com/document_detail/90459.html)。
 
-pub use alink::aiot_module::{AiotModule, ModuleRecvKind};
+pub use alink::aiot_module::{AiotModule, ChannelConnection, ModuleRecvKind};
+pub use alink::channel::{Channel, LoopbackChannel, LoopbackPeer};
+pub use alink::event::{AiotEvent, EventStream};
+pub use alink::queue::OverflowPolicy;
 pub use alink::ThreeTuple;
+pub use coap::{Coap, CoapOptions};
 pub use dm::{DataModelMsg, DataModelOptions};
 pub use dynregmq::{DynamicRegister, DynamicRegisterResult};
 pub use http_downloader::HttpDownloader;
-pub use https::Http;
-pub use mqtt::{DeviceAuthInfo, MqttClient, MqttConnection, MqttInstance};
+pub use https::{Http, HttpOptions};
+pub use mqtt::{DeviceAuthInfo, MqttClient, MqttConnection, MqttInstance, MqttStats};
 pub use ra::base::SecureTunnelNotify;
 pub use tunnel::protocol::Service as LocalService;
-pub use tunnel::proxy::{TunnelAction, TunnelParams, TunnelProxy};
+pub use tunnel::proxy::{TunnelAction, TunnelInfo, TunnelOptions, TunnelParams, TunnelProxy};
+pub use tunnel::source::{SourceClient, SourceParams};
 pub use util::error::{Error, Result};
 
 pub mod alink;
 pub mod bootstrap;
+pub mod coap;
+pub mod diag;
 pub mod dm;
 pub mod dynregmq;
 pub mod file;
 pub mod http_downloader;
 pub mod https;
 pub mod logpost;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod mqtt;
 pub mod ntp;
 pub mod ota;
@@ -0,0 +1,397 @@
+//! 把 `log` 宏输出的日志批量上报到云端。
+//!
+//! [`LogPostLogger`] 实现了 [`log::Log`]，通过 [`log::set_logger`] 注册（可以先 `Box::leak`），
+//! 并用 [`log::set_max_level`] 设置级别；
+//! [`LogUploader::run`] 负责攒批上报，并根据云端下发的日志配置开关上报。
+//! 设置了 [`LoggerOptions::spool`] 时，关闭上报、连接断开或上报失败期间的日志缓存到本地，
+//! 恢复后补传。连接状态来自 [`ChannelConnection::set_online`]，重连后自动补传。
+
+use super::base::LogItem;
+use super::recv::LogPostRecv;
+use super::spool::{LogSpool, SpoolOptions};
+use crate::alink::aiot_module::ChannelConnection;
+use crate::alink::channel::Channel;
+use crate::alink::global_id_next;
+use crate::util::clock::Clock;
+use crate::{Error, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rumqttc::AsyncClient;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+/// 云端限制单次上报的最大日志条数
+pub const MAX_BATCH_SIZE: usize = 40;
+
+/// 日志上报的参数
+#[derive(Debug, Clone)]
+pub struct LoggerOptions {
+    /// 上报的最低日志级别
+    pub level: LevelFilter,
+    /// 单次上报的最大条数，不超过 [`MAX_BATCH_SIZE`]
+    pub batch_size: usize,
+    /// 未攒满一批时的最长等待时间
+    pub flush_interval: Duration,
+    /// 等待上报的日志队列长度，队列满时丢弃新日志
+    pub queue_size: usize,
+    /// 每秒最多接受的日志条数，`None` 表示不限制
+    pub max_per_second: Option<u32>,
+    /// 收到云端日志配置前是否上报
+    pub enabled: bool,
+    /// 不上报的日志 target 前缀。默认忽略本 SDK 和 MQTT 客户端的日志，避免上报过程产生新的日志。
+    pub ignore_targets: Vec<String>,
+    /// 本地缓存，`None` 表示不缓存
+    pub spool: Option<SpoolOptions>,
+}
+
+impl Default for LoggerOptions {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            batch_size: MAX_BATCH_SIZE,
+            flush_interval: Duration::from_secs(5),
+            queue_size: 1024,
+            max_per_second: Some(100),
+            enabled: false,
+            ignore_targets: vec![env!("CARGO_CRATE_NAME").to_string(), "rumqttc".to_string()],
+            spool: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    enabled: AtomicBool,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+enum Control {
+    Flush(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<()>),
+    Replay(oneshot::Sender<()>),
+}
+
+/// `log` 的后端，把日志交给 [`LogUploader`] 上报
+pub struct LogPostLogger {
+    level: LevelFilter,
+    ignore_targets: Vec<String>,
+    max_per_second: Option<u32>,
+    /// 关闭上报期间是否缓存
+    spool: bool,
+    /// 当前秒和这一秒内已接受的条数
+    window: Mutex<(u64, u32)>,
+    clock: Arc<dyn Clock>,
+    tx: Sender<LogItem>,
+    handle: LogPostHandle,
+}
+
+/// 查询上报状态，刷新或停止上报
+#[derive(Debug, Clone)]
+pub struct LogPostHandle {
+    ctrl: Sender<Control>,
+    shared: Arc<Shared>,
+}
+
+/// 日志上报任务
+pub struct LogUploader<C = AsyncClient> {
+    module: super::Module<C>,
+    rx: Receiver<LogItem>,
+    ctrl: Receiver<Control>,
+    shared: Arc<Shared>,
+    batch: Vec<LogItem>,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool_options: Option<SpoolOptions>,
+    spool: Option<LogSpool>,
+    /// 有等待补传的缓存
+    replay_pending: bool,
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    /// 创建 `log` 后端和对应的上报任务
+    pub fn log_backend(
+        &mut self,
+        options: LoggerOptions,
+    ) -> Result<(LogPostLogger, LogUploader<C>)> {
+        let module = self.log_post()?;
+        let (tx, rx) = mpsc::channel(options.queue_size.max(1));
+        let (ctrl_tx, ctrl_rx) = mpsc::channel(8);
+        let shared = Arc::new(Shared::default());
+        shared.enabled.store(options.enabled, Ordering::Relaxed);
+        let logger = LogPostLogger {
+            level: options.level,
+            ignore_targets: options.ignore_targets,
+            max_per_second: options.max_per_second,
+            spool: options.spool.is_some(),
+            window: Mutex::new((0, 0)),
+            clock: module.clock.clone(),
+            tx,
+            handle: LogPostHandle {
+                ctrl: ctrl_tx,
+                shared: shared.clone(),
+            },
+        };
+        let uploader = LogUploader {
+            module,
+            rx,
+            ctrl: ctrl_rx,
+            shared,
+            batch: Vec::new(),
+            batch_size: options.batch_size.clamp(1, MAX_BATCH_SIZE),
+            flush_interval: options.flush_interval,
+            spool_options: options.spool,
+            spool: None,
+            replay_pending: false,
+        };
+        Ok((logger, uploader))
+    }
+}
+
+impl LogPostLogger {
+    /// 注册为全局 logger 前保存句柄
+    pub fn handle(&self) -> LogPostHandle {
+        self.handle.clone()
+    }
+
+    fn acquire(&self) -> bool {
+        let max = match self.max_per_second {
+            Some(max) => max,
+            None => return true,
+        };
+        let second = self.clock.monotonic().as_secs();
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        if window.0 != second {
+            *window = (second, 0);
+        }
+        window.1 += 1;
+        window.1 <= max
+    }
+
+    fn drop_one(&self) {
+        self.handle.shared.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Log for LogPostLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && (self.spool || self.handle.enabled())
+            && !self
+                .ignore_targets
+                .iter()
+                .any(|t| metadata.target().starts_with(t.as_str()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if !self.acquire() {
+            self.drop_one();
+            return;
+        }
+        let log_level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug | Level::Trace => "DEBUG",
+        };
+        let item = LogItem {
+            utc_time: self.clock.utc_time(),
+            log_level: log_level.to_string(),
+            module: record
+                .module_path()
+                .unwrap_or_else(|| record.target())
+                .to_string(),
+            code: String::new(),
+            trace_context: None,
+            log_content: record.args().to_string(),
+        };
+        if self.tx.try_send(item).is_err() {
+            self.drop_one();
+        }
+    }
+
+    /// 通知上报任务立即上报，不等待完成。需要等待时使用 [`LogPostHandle::flush`]。
+    fn flush(&self) {
+        let (tx, _) = oneshot::channel();
+        self.handle.ctrl.try_send(Control::Flush(tx)).ok();
+    }
+}
+
+impl LogPostHandle {
+    /// 云端是否开启了日志上报
+    pub fn enabled(&self) -> bool {
+        self.shared.enabled.load(Ordering::Relaxed)
+    }
+
+    /// 因限流、队列满或上报失败丢弃的日志条数
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// 上报队列中的所有日志
+    pub async fn flush(&self) -> Result<()> {
+        self.request(Control::Flush).await
+    }
+
+    /// 上报队列中的所有日志后结束上报任务
+    pub async fn shutdown(&self) -> Result<()> {
+        self.request(Control::Shutdown).await
+    }
+
+    /// 立即补传本地缓存的日志，重连后会自动补传
+    pub async fn replay(&self) -> Result<()> {
+        self.request(Control::Replay).await
+    }
+
+    async fn request(&self, f: fn(oneshot::Sender<()>) -> Control) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .send(f(tx))
+            .await
+            .map_err(|_| Error::MpscSendError)?;
+        rx.await.map_err(|_| Error::MpscSendError)
+    }
+}
+
+impl<C: Channel> LogUploader<C> {
+    /// 订阅日志配置并持续上报，直到 [`LogPostHandle::shutdown`] 或 logger 被释放。
+    pub async fn run(mut self) -> Result<()> {
+        if let Some(options) = &self.spool_options {
+            let spool = LogSpool::open(&options.path, options.max_size).await?;
+            self.replay_pending = !spool.is_empty();
+            self.spool = Some(spool);
+        }
+        self.module.init().await?;
+        self.module.get_default().await?;
+        let mut ticker = tokio::time::interval(self.flush_interval);
+        let mut online = self.module.online.clone();
+        loop {
+            tokio::select! {
+                Some(item) = self.rx.recv() => {
+                    self.batch.push(item);
+                    if self.batch.len() >= self.batch_size {
+                        self.flush().await;
+                    }
+                }
+                _ = ticker.tick() => self.flush().await,
+                recv = self.module.poll() => self.handle(recv?),
+                Ok(()) = online.changed() => {
+                    // 断线期间的日志在重连后补传
+                    if *online.borrow_and_update() {
+                        self.flush().await;
+                    }
+                }
+                ctrl = self.ctrl.recv() => {
+                    while let Ok(item) = self.rx.try_recv() {
+                        self.batch.push(item);
+                    }
+                    if matches!(ctrl, Some(Control::Replay(_))) {
+                        self.replay_pending = self.spool.is_some();
+                    }
+                    self.flush().await;
+                    match ctrl {
+                        Some(Control::Flush(tx) | Control::Replay(tx)) => {
+                            tx.send(()).ok();
+                        }
+                        Some(Control::Shutdown(tx)) => {
+                            tx.send(()).ok();
+                            return Ok(());
+                        }
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, recv: LogPostRecv) {
+        let mode = match recv {
+            LogPostRecv::ConfigLogGetReply(reply) if reply.code == 200 => reply.data.content.mode,
+            LogPostRecv::ConfigLogPush(push) => push.params.content.mode,
+            _ => return,
+        };
+        let enabled = mode == 1;
+        let was_enabled = self.shared.enabled.swap(enabled, Ordering::Relaxed);
+        if enabled && !was_enabled && self.spool.is_some() {
+            self.replay_pending = true;
+        }
+        if !enabled && self.spool.is_none() {
+            self.batch.clear();
+        }
+    }
+
+    /// 开启上报且在线时先补传缓存再上报新日志，否则写入缓存
+    async fn flush(&mut self) {
+        if !self.shared.enabled.load(Ordering::Relaxed) || !*self.module.online.borrow() {
+            let logs = std::mem::take(&mut self.batch);
+            self.spool(logs).await;
+            return;
+        }
+        if self.replay_pending && !self.replay().await {
+            let logs = std::mem::take(&mut self.batch);
+            self.spool(logs).await;
+            return;
+        }
+        while !self.batch.is_empty() {
+            let n = self.batch.len().min(self.batch_size);
+            let logs: Vec<LogItem> = self.batch.drain(..n).collect();
+            if self.module.post(logs.clone()).await.is_err() {
+                let mut logs = logs;
+                logs.append(&mut self.batch);
+                self.spool(logs).await;
+                return;
+            }
+        }
+    }
+
+    async fn spool(&mut self, logs: Vec<LogItem>) {
+        if logs.is_empty() {
+            return;
+        }
+        let spooled = match &mut self.spool {
+            Some(spool) => spool.push(&logs).await.is_ok(),
+            None => false,
+        };
+        if spooled {
+            self.replay_pending = true;
+        } else {
+            self.shared
+                .dropped
+                .fetch_add(logs.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// 按顺序补传最近的缓存日志，每批使用同一个 `trace_context`。
+    /// 失败时保留未上报的部分，返回 false。
+    async fn replay(&mut self) -> bool {
+        let (spool, window) = match (&mut self.spool, &self.spool_options) {
+            (Some(spool), Some(options)) => (spool, options.replay_window),
+            _ => return true,
+        };
+        let mut items = match spool.recent(window).await {
+            Ok(items) => items,
+            Err(_) => return false,
+        };
+        let mut sent = 0;
+        for chunk in items.chunks_mut(self.batch_size) {
+            let trace = format!("replay-{}", global_id_next());
+            for item in chunk.iter_mut() {
+                item.trace_context.get_or_insert_with(|| trace.clone());
+            }
+            if self.module.post(chunk.to_vec()).await.is_err() {
+                break;
+            }
+            sent += chunk.len();
+        }
+        if spool.reset(&items[sent..]).await.is_err() {
+            return false;
+        }
+        self.replay_pending = sent < items.len();
+        !self.replay_pending
+    }
+}
@@ -1,8 +1,10 @@
 //! 日志上报。
 
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::alink::{AlinkRequest, AlinkResponse};
-use crate::mqtt::MqttConnection;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
@@ -10,31 +12,34 @@ use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
+pub use self::logger::{LogPostHandle, LogPostLogger, LogUploader, LoggerOptions};
+pub use self::spool::{LogSpool, SpoolOptions};
+
 pub mod base;
+pub mod logger;
 pub mod push;
 pub mod recv;
+pub mod spool;
 
 pub type Recv = LogPostRecv;
 pub type RecvKind = LogPostRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
 
-impl Module {
+impl<C: Channel> Module<C> {
     pub async fn init(&self) -> Result<()> {
         self.sub_all::<RecvKind>().await
     }
 }
 
-impl MqttConnection {
-    pub fn log_post(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn log_post(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
         self.module(Box::new(executor), rx, ())
     }
@@ -42,13 +47,207 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{drive, three};
+    use crate::mock::MockCloud;
+    use crate::DeviceAuthInfo;
+    use log::{Level, Log, Record};
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn 日志上报() {
+        let mut cloud = MockCloud::new(&three());
+        cloud.set_log_mode(1);
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        // 固定的时钟，保证限流窗口不变
+        conn.set_clock(crate::util::clock::ManualClock::new(0));
+        let options = LoggerOptions {
+            max_per_second: Some(3),
+            ..Default::default()
+        };
+        let (logger, uploader) = conn.log_backend(options).unwrap();
+        let handle = logger.handle();
+        let log = |level, target: &str, text: &str| {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("{}", text))
+                    .level(level)
+                    .target(target)
+                    .module_path(Some(target))
+                    .build(),
+            )
+        };
+        let script = async {
+            // 等待云端的日志配置
+            while !handle.enabled() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            log(Level::Info, "app::main", "started");
+            log(Level::Debug, "app::main", "filtered by level");
+            log(Level::Warn, "aiot::mqtt", "sdk log is ignored");
+            log(Level::Error, "app::io", "disk full");
+            log(Level::Warn, "app::io", "retry");
+            log(Level::Warn, "app::io", "rate limited");
+            handle.flush().await.unwrap();
+
+            // 云端关闭日志上报
+            cloud.log_config_push(0).unwrap();
+            while handle.enabled() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            log(Level::Error, "app::io", "not posted");
+            handle.shutdown().await.unwrap();
+        };
+        let (res, _) = drive(&mut conn, async { tokio::join!(uploader.run(), script) }).await;
+        res.unwrap();
+        assert_eq!(handle.dropped(), 1);
+
+        let posts: Vec<Value> = cloud
+            .published()
+            .iter()
+            .filter(|(topic, _)| topic.ends_with("/thing/log/post"))
+            .map(|(_, payload)| serde_json::from_slice(payload).unwrap())
+            .collect();
+        assert_eq!(posts.len(), 1);
+        let items = posts[0]["params"].as_array().unwrap();
+        let contents: Vec<_> = items.iter().map(|i| i["logContent"].clone()).collect();
+        assert_eq!(contents, vec!["started", "disk full", "retry"]);
+        assert_eq!(items[1]["logLevel"], "ERROR");
+        assert_eq!(items[1]["module"], "app::io");
+    }
+
+    #[tokio::test]
+    async fn 日志缓存补传() {
+        let dir = tempdir::TempDir::new("spool").unwrap();
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let options = LoggerOptions {
+            batch_size: 2,
+            spool: Some(SpoolOptions {
+                replay_window: 3,
+                ..SpoolOptions::new(dir.path().join("log.jsonl"))
+            }),
+            ..Default::default()
+        };
+        let (logger, uploader) = conn.log_backend(options).unwrap();
+        let handle = logger.handle();
+        let log = |text: &str| {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("{}", text))
+                    .level(Level::Info)
+                    .target("app")
+                    .build(),
+            )
+        };
+        let script = async {
+            // 云端未开启上报，日志写入缓存
+            for text in ["a", "b", "c", "d"] {
+                log(text);
+            }
+            handle.flush().await.unwrap();
+            cloud.log_config_push(1).unwrap();
+            while !handle.enabled() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            log("live");
+            handle.shutdown().await.unwrap();
+        };
+        let (res, _) = drive(&mut conn, async { tokio::join!(uploader.run(), script) }).await;
+        res.unwrap();
+
+        let items: Vec<Value> = cloud
+            .published()
+            .iter()
+            .filter(|(topic, _)| topic.ends_with("/thing/log/post"))
+            .flat_map(|(_, payload)| {
+                let post: Value = serde_json::from_slice(payload).unwrap();
+                post["params"].as_array().unwrap().clone()
+            })
+            .collect();
+        // 补传最近的 3 条，每批使用同一个 traceContext
+        let contents: Vec<_> = items.iter().map(|i| i["logContent"].clone()).collect();
+        assert_eq!(contents, vec!["b", "c", "d", "live"]);
+        let traces: Vec<_> = items.iter().map(|i| i["traceContext"].clone()).collect();
+        assert_eq!(traces[0], traces[1]);
+        assert_ne!(traces[1], traces[2]);
+        assert!(traces[2].as_str().unwrap().starts_with("replay-"));
+        assert!(traces[3].is_null());
+        assert_eq!(handle.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn 断线缓存日志() {
+        let dir = tempdir::TempDir::new("spool").unwrap();
+        let mut cloud = MockCloud::new(&three());
+        cloud.set_log_mode(1);
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let options = LoggerOptions {
+            spool: Some(SpoolOptions::new(dir.path().join("log.jsonl"))),
+            ..Default::default()
+        };
+        let (logger, uploader) = conn.log_backend(options).unwrap();
+        let handle = logger.handle();
+        let log = |text: &str| {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("{}", text))
+                    .level(Level::Info)
+                    .target("app")
+                    .build(),
+            )
+        };
+        let posted = || {
+            cloud
+                .published()
+                .iter()
+                .filter(|(topic, _)| topic.ends_with("/thing/log/post"))
+                .flat_map(|(_, payload)| {
+                    let post: Value = serde_json::from_slice(payload).unwrap();
+                    post["params"].as_array().unwrap().clone()
+                })
+                .map(|item| item["logContent"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let script = async {
+            while !handle.enabled() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            // 断线期间写入缓存，不上报
+            cloud.set_online(false);
+            log("a");
+            log("b");
+            handle.flush().await.unwrap();
+            assert!(posted().is_empty());
+            // 重连后自动补传
+            cloud.set_online(true);
+            while posted().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            log("live");
+            handle.shutdown().await.unwrap();
+        };
+        let (res, _) = drive(&mut conn, async { tokio::join!(uploader.run(), script) }).await;
+        res.unwrap();
+        assert_eq!(posted(), vec!["a", "b", "live"]);
     }
 }
@@ -1,4 +1,5 @@
 use super::base::*;
+use crate::alink::channel::Channel;
 use crate::alink::{AlinkRequest, AlinkResponse, SysAck};
 use crate::{Error, Result, ThreeTuple};
 use regex::Regex;
@@ -10,7 +11,7 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 设备获取日志配置
     ///
     /// # 参数
@@ -37,6 +38,18 @@ impl super::Module {
         self.get("device", "content").await
     }
 
+    /// 以模块时钟的当前时间生成一条日志
+    pub fn log_item(&self, log_level: &str, module: &str, code: &str, content: String) -> LogItem {
+        LogItem {
+            utc_time: self.clock.utc_time(),
+            log_level: log_level.to_string(),
+            module: module.to_string(),
+            code: code.to_string(),
+            trace_context: None,
+            log_content: content,
+        }
+    }
+
     pub async fn post(&self, logs: Vec<LogItem>) -> crate::Result<()> {
         let payload: LogPostRequest = AlinkRequest::new_no_ack("thing.log.post", logs);
         let topic = format!(
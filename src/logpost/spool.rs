@@ -0,0 +1,180 @@
+//! 日志的本地缓存。
+//!
+//! 云端关闭日志上报或设备离线时，日志以 JSON Lines 格式追加到文件中。
+//! 文件超过容量的一半时改名为 `<path>.1`，覆盖更早的日志，总大小不超过设置的容量。
+
+use super::base::LogItem;
+use crate::Result;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// 日志缓存的参数
+#[derive(Debug, Clone)]
+pub struct SpoolOptions {
+    /// 缓存文件路径
+    pub path: PathBuf,
+    /// 缓存文件的总大小上限，单位字节
+    pub max_size: u64,
+    /// 恢复上报时补传的最近日志条数
+    pub replay_window: usize,
+}
+
+impl SpoolOptions {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_size: 1024 * 1024,
+            replay_window: 400,
+        }
+    }
+}
+
+/// 有容量上限的日志缓存文件
+#[derive(Debug)]
+pub struct LogSpool {
+    path: PathBuf,
+    old_path: PathBuf,
+    max_size: u64,
+    /// 当前文件的大小
+    size: u64,
+}
+
+impl LogSpool {
+    pub async fn open(path: &Path, max_size: u64) -> Result<Self> {
+        let mut old_path = path.as_os_str().to_owned();
+        old_path.push(".1");
+        let size = match fs::metadata(path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            old_path: old_path.into(),
+            max_size,
+            size,
+        })
+    }
+
+    /// 追加日志，超过容量时丢弃最早的一半。
+    /// 一次追加超过容量一半的日志时只保留最近的部分。
+    pub async fn push(&mut self, items: &[LogItem]) -> Result<()> {
+        let mut lines = Vec::new();
+        for item in items {
+            let mut line = serde_json::to_vec(item)?;
+            line.push(b'\n');
+            lines.push(line);
+        }
+        let mut len = 0;
+        let keep = lines
+            .iter()
+            .rev()
+            .take_while(|line| {
+                len += line.len() as u64;
+                len <= self.max_size / 2
+            })
+            .count();
+        let data = lines[lines.len() - keep..].concat();
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.size > 0 && self.size + data.len() as u64 > self.max_size / 2 {
+            fs::rename(&self.path, &self.old_path).await?;
+            self.size = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&data).await?;
+        file.flush().await?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0 && !self.old_path.exists()
+    }
+
+    /// 按时间顺序读取最近的 `window` 条日志，无法解析的行被跳过
+    pub async fn recent(&self, window: usize) -> Result<Vec<LogItem>> {
+        let mut items = Vec::new();
+        for path in [&self.old_path, &self.path] {
+            let data = match fs::read(path).await {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            items.extend(
+                data.split(|c| *c == b'\n')
+                    .filter_map(|line| serde_json::from_slice::<LogItem>(line).ok()),
+            );
+        }
+        let skip = items.len().saturating_sub(window);
+        items.drain(..skip);
+        Ok(items)
+    }
+
+    /// 清空缓存，只保留 `items`
+    pub async fn reset(&mut self, items: &[LogItem]) -> Result<()> {
+        fs::remove_file(&self.old_path).await.ok();
+        fs::remove_file(&self.path).await.ok();
+        self.size = 0;
+        if !items.is_empty() {
+            self.push(items).await?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_log_spool() {
+    let dir = tempdir::TempDir::new("spool").unwrap();
+    let path = dir.path().join("log.jsonl");
+    let item = |i: usize| LogItem {
+        utc_time: String::new(),
+        log_level: "INFO".to_string(),
+        module: "app".to_string(),
+        code: String::new(),
+        trace_context: None,
+        log_content: format!("{:04}", i),
+    };
+    let mut spool = LogSpool::open(&path, 2000).await.unwrap();
+    assert!(spool.is_empty());
+    for i in 0..100 {
+        spool.push(&[item(i)]).await.unwrap();
+    }
+    let size = |p: &Path| std::fs::metadata(p).map(|m| m.len()).unwrap_or(0);
+    assert!(size(&path) + size(&spool.old_path) <= 2000);
+
+    // 最早的日志被覆盖，剩下的按顺序读出
+    let items = spool.recent(usize::MAX).await.unwrap();
+    assert!(items.len() < 100);
+    assert_eq!(items.last().unwrap().log_content, "0099");
+    let contents: Vec<_> = items.iter().map(|i| i.log_content.clone()).collect();
+    let mut sorted = contents.clone();
+    sorted.sort();
+    assert_eq!(contents, sorted);
+    let recent = spool.recent(3).await.unwrap();
+    assert_eq!(recent[0].log_content, "0097");
+
+    // 重新打开后继续追加
+    let mut spool = LogSpool::open(&path, 2000).await.unwrap();
+    spool.reset(&recent[2..]).await.unwrap();
+    spool.push(&[item(100)]).await.unwrap();
+    let items = spool.recent(10).await.unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[1].log_content, "0100");
+
+    // 超过容量一半的一批日志只保留最近的部分
+    let batch: Vec<_> = (200..300).map(item).collect();
+    spool.push(&batch).await.unwrap();
+    assert!(size(&path) <= 1000);
+    assert!(size(&path) + size(&spool.old_path) <= 2000);
+    let items = spool.recent(usize::MAX).await.unwrap();
+    assert_eq!(items.last().unwrap().log_content, "0299");
+    assert!(items.len() < 100);
+    assert!(items
+        .windows(2)
+        .all(|w| w[0].log_content < w[1].log_content));
+}
@@ -0,0 +1,785 @@
+//! 模拟云端，用于在没有真实产品的情况下进行集成测试。
+//!
+//! [`MockCloud`] 在内存中模拟阿里云物联网平台：校验设备的连接签名，记录设备发布的消息，
+//! 自动应答属性/事件上报、OTA 升级包查询、远程配置获取、标签上报、文件上传和 NTP 请求，
+//! 并可以向设备注入属性设置、服务调用、OTA 推送、配置推送和远程登录通知。
+//!
+//! 需要开启 `mock` feature。
+//!
+//! # Examples
+//!
+//! ```
+//! use aiot::mock::MockCloud;
+//! use aiot::{DataModelMsg, DataModelOptions, DeviceAuthInfo, ThreeTuple};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> aiot::Result<()> {
+//! let three = ThreeTuple {
+//!     product_key: "pk".to_string(),
+//!     device_name: "dn".to_string(),
+//!     device_secret: "ds".to_string(),
+//! };
+//! let mut cloud = MockCloud::new(&three);
+//! let mut conn = cloud.connect(&DeviceAuthInfo::from_tuple(&three))?;
+//! let mut dm = conn.data_model(DataModelOptions::new())?;
+//! dm.init().await?;
+//!
+//! dm.send(DataModelMsg::property_post(serde_json::json!({"LightSwitch": 1}))).await?;
+//! assert_eq!(cloud.properties()["LightSwitch"], 1);
+//!
+//! conn.poll().await?; // 云端应答 post_reply
+//! dm.poll().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::alink::alink_topic::topic_matches;
+use crate::alink::channel::{Channel, Incoming};
+use crate::alink::{global_id_next, AlinkRequest, AlinkResponse, ParamsRequest, ALINK_VERSION};
+use crate::file::base::{ConflictStrategy, InitData, InitParams, SendHeader, SendReplyData};
+use crate::ota::base::PackageData;
+use crate::ra::base::ConnectOrUpdate;
+use crate::remote_config::recv::RemoteConfigFileInfo;
+use crate::{ChannelConnection, DeviceAuthInfo, Error, Result, ThreeTuple};
+use log::*;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+
+type Item = (String, Vec<u8>);
+
+#[derive(Default)]
+struct MockState {
+    subscriptions: Vec<String>,
+    published: Vec<Item>,
+    properties: Map<String, Value>,
+    firmware: Option<PackageData>,
+    config: Option<RemoteConfigFileInfo>,
+    uploads: HashMap<String, MockUpload>,
+    /// 跳过的分片数和之后丢弃的分片数
+    drop_chunks: (usize, usize),
+    /// 日志上报模式
+    log_mode: i32,
+}
+
+/// 云端的文件上传任务
+#[derive(Default)]
+struct MockUpload {
+    upload_id: String,
+    size: u64,
+    fic_value: Option<String>,
+    data: Vec<u8>,
+    complete: bool,
+}
+
+/// 模拟的云端
+pub struct MockCloud {
+    three: ThreeTuple,
+    state: Arc<Mutex<MockState>>,
+    up_tx: UnboundedSender<Item>,
+    up_rx: UnboundedReceiver<Item>,
+    down_tx: UnboundedSender<Item>,
+    down_rx: Option<UnboundedReceiver<Item>>,
+    /// 设备连接的在线状态
+    online: Option<Arc<watch::Sender<bool>>>,
+}
+
+/// 设备连接到 [`MockCloud`] 的通道
+pub struct MockChannel {
+    three: ThreeTuple,
+    state: Arc<Mutex<MockState>>,
+    up_tx: UnboundedSender<Item>,
+    down_tx: UnboundedSender<Item>,
+    down_rx: Mutex<Option<UnboundedReceiver<Item>>>,
+}
+
+impl MockCloud {
+    pub fn new(three: &ThreeTuple) -> Self {
+        let (up_tx, up_rx) = mpsc::unbounded_channel();
+        let (down_tx, down_rx) = mpsc::unbounded_channel();
+        Self {
+            three: three.clone(),
+            state: Arc::new(Mutex::new(MockState::default())),
+            up_tx,
+            up_rx,
+            down_tx,
+            down_rx: Some(down_rx),
+            online: None,
+        }
+    }
+
+    /// 模拟设备连接。连接信息与三元组计算出的签名不一致时拒绝连接。
+    ///
+    /// 每个模拟云端只接受一个连接。
+    pub fn connect(&mut self, info: &DeviceAuthInfo) -> Result<ChannelConnection<MockChannel>> {
+        let expect = DeviceAuthInfo::from_tuple(&self.three);
+        if info.client_id != expect.client_id {
+            return Err(Error::ConnectRefused(format!(
+                "clientId {}",
+                info.client_id
+            )));
+        }
+        if info.username != expect.username || info.password != expect.password {
+            return Err(Error::ConnectRefused(format!("username {}", info.username)));
+        }
+        let down_rx = self
+            .down_rx
+            .take()
+            .ok_or_else(|| Error::ConnectRefused("重复连接".to_string()))?;
+        let channel = MockChannel {
+            three: self.three.clone(),
+            state: self.state.clone(),
+            up_tx: self.up_tx.clone(),
+            down_tx: self.down_tx.clone(),
+            down_rx: Mutex::new(Some(down_rx)),
+        };
+        let conn = ChannelConnection::new(&self.three, channel);
+        self.online = Some(conn.online.clone());
+        Ok(conn)
+    }
+
+    /// 模拟设备断线和重连，更新设备连接的在线状态
+    pub fn set_online(&self, online: bool) {
+        if let Some(tx) = &self.online {
+            tx.send_replace(online);
+        }
+    }
+
+    /// 设备发布的所有消息
+    pub fn published(&self) -> Vec<Item> {
+        self.lock().published.clone()
+    }
+
+    /// 等待设备发布的下一条消息
+    pub async fn next_publish(&mut self) -> Option<Item> {
+        self.up_rx.recv().await
+    }
+
+    /// 设备订阅的 topic
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.lock().subscriptions.clone()
+    }
+
+    /// 设备通过属性上报的最新属性值
+    pub fn properties(&self) -> Value {
+        Value::Object(self.lock().properties.clone())
+    }
+
+    /// 设置设备请求升级包时返回的升级包信息
+    pub fn set_firmware(&self, package: Option<PackageData>) {
+        self.lock().firmware = package;
+    }
+
+    /// 设置设备请求远程配置时返回的配置信息
+    pub fn set_config(&self, config: Option<RemoteConfigFileInfo>) {
+        self.lock().config = config;
+    }
+
+    /// 设置设备请求日志配置时返回的上报模式
+    pub fn set_log_mode(&self, mode: i32) {
+        self.lock().log_mode = mode;
+    }
+
+    /// 推送日志配置
+    pub fn log_config_push(&self, mode: i32) -> Result<()> {
+        let config = json!({ "getType": "content", "content": { "mode": mode } });
+        let payload = AlinkRequest::new_id(global_id_next(), "thing.config.log.push", config, None);
+        self.send_json(&self.sys_topic("thing/config/log/push"), &payload)
+    }
+
+    /// 向设备下发任意消息
+    pub fn send(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.down_tx
+            .send((topic.to_string(), payload.to_vec()))
+            .map_err(|_| Error::MpscSendError)
+    }
+
+    /// 下发属性设置，返回消息ID
+    pub fn property_set(&self, params: Value) -> Result<u64> {
+        let id = global_id_next();
+        let payload = AlinkRequest::new_id(id, "thing.service.property.set", params, None);
+        self.send_json(&self.sys_topic("thing/service/property/set"), &payload)?;
+        Ok(id)
+    }
+
+    /// 下发异步服务调用，返回消息ID
+    pub fn service_call(&self, service_id: &str, params: Value) -> Result<u64> {
+        let id = global_id_next();
+        let method = format!("thing.service.{}", service_id);
+        let payload = AlinkRequest::new_id(id, &method, params, None);
+        let topic = self.sys_topic(&format!("thing/service/{}", service_id));
+        self.send_json(&topic, &payload)?;
+        Ok(id)
+    }
+
+    /// 下发同步服务调用，返回 RRPC 标识符
+    pub fn rrpc_call(&self, service_id: &str, params: Value) -> Result<String> {
+        let id = global_id_next();
+        let rrpc_id = crate::util::rand_u64().to_string();
+        let method = format!("thing.service.{}", service_id);
+        let payload = AlinkRequest::new_id(id, &method, params, None);
+        let topic = format!(
+            "/ext/rrpc/{}{}",
+            rrpc_id,
+            self.sys_topic(&format!("thing/service/{}", service_id))
+        );
+        self.send_json(&topic, &payload)?;
+        Ok(rrpc_id)
+    }
+
+    /// 推送升级包
+    pub fn ota_push(&self, package: PackageData) -> Result<()> {
+        let payload = AlinkResponse::<_, u64, String> {
+            id: global_id_next(),
+            code: "1000".to_string(),
+            data: Some(package),
+            message: Some("success".to_string()),
+            method: None,
+            version: None,
+        };
+        let topic = format!(
+            "/ota/device/upgrade/{}/{}",
+            self.three.product_key, self.three.device_name
+        );
+        self.send_json(&topic, &payload)
+    }
+
+    /// 推送远程配置
+    pub fn config_push(&self, config: RemoteConfigFileInfo) -> Result<()> {
+        let payload = AlinkResponse::new(global_id_next(), 200, Some(config));
+        self.send_json(&self.sys_topic("thing/config/push"), &payload)
+    }
+
+    /// 推送远程登录通知
+    pub fn tunnel_notify(&self, notify: ConnectOrUpdate) -> Result<()> {
+        self.send_json(&self.sys_topic("secure_tunnel/notify"), &notify)
+    }
+
+    /// 设备上传完成的文件
+    pub fn uploaded(&self, file_name: &str) -> Option<Vec<u8>> {
+        let state = self.lock();
+        let upload = state.uploads.get(file_name)?;
+        upload.complete.then(|| upload.data.clone())
+    }
+
+    /// 设备接下来上传的文件分片中，跳过 `skip` 个之后丢弃 `count` 个，不保存也不应答，模拟网络中断
+    pub fn drop_file_chunks(&self, skip: usize, count: usize) {
+        self.lock().drop_chunks = (skip, count);
+    }
+
+    fn send_json<T: Serialize>(&self, topic: &str, payload: &T) -> Result<()> {
+        self.send(topic, &serde_json::to_vec(payload)?)
+    }
+
+    fn sys_topic(&self, suffix: &str) -> String {
+        format!(
+            "/sys/{}/{}/{}",
+            self.three.product_key, self.three.device_name, suffix
+        )
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MockChannel {
+    /// 根据设备上行消息生成云端的自动应答
+    fn reply(&self, topic: &str, payload: &[u8]) -> Option<Item> {
+        let three = &self.three;
+        if topic
+            == format!(
+                "/ext/ntp/{}/{}/request",
+                three.product_key, three.device_name
+            )
+        {
+            let request: Value = serde_json::from_slice(payload).ok()?;
+            let now = chrono::Utc::now().timestamp_millis();
+            let response = json!({
+                "deviceSendTime": request["deviceSendTime"],
+                "serverRecvTime": now,
+                "serverSendTime": now,
+            });
+            let topic = format!(
+                "/ext/ntp/{}/{}/response",
+                three.product_key, three.device_name
+            );
+            return Some((topic, serde_json::to_vec(&response).ok()?));
+        }
+
+        let prefix = format!("/sys/{}/{}/", three.product_key, three.device_name);
+        let rest = topic.strip_prefix(&prefix)?;
+        match rest {
+            "thing/file/upload/mqtt/init" => return self.file_init(topic, payload),
+            "thing/file/upload/mqtt/send" => return self.file_send(topic, payload),
+            _ => {}
+        }
+        let request: AlinkRequest<Value> = serde_json::from_slice(payload).ok()?;
+        if request.sys.as_ref().map(|s| s.ack == 0).unwrap_or(false) {
+            return None;
+        }
+        let data = if rest == "thing/event/property/post" {
+            if let Value::Object(params) = &request.params {
+                let mut state = self.state.lock().ok()?;
+                for (k, v) in params {
+                    state.properties.insert(k.clone(), v.clone());
+                }
+            }
+            json!({})
+        } else if rest.starts_with("thing/event/") && rest.ends_with("/post") {
+            json!({})
+        } else if rest == "thing/ota/firmware/get" {
+            serde_json::to_value(&self.state.lock().ok()?.firmware).ok()?
+        } else if rest == "thing/config/get" {
+            serde_json::to_value(&self.state.lock().ok()?.config).ok()?
+        } else if rest == "thing/config/log/get" {
+            let mode = self.state.lock().ok()?.log_mode;
+            json!({ "getType": "content", "content": { "mode": mode } })
+        } else if rest == "thing/deviceinfo/update" || rest == "thing/deviceinfo/delete" {
+            json!({})
+        } else {
+            return None;
+        };
+        let mut response = AlinkResponse::new(request.msg_id(), 200, data);
+        response.message = Some("success".to_string());
+        response.method = request.method;
+        response.version = Some(ALINK_VERSION.to_string());
+        Some((
+            format!("{}_reply", topic),
+            serde_json::to_vec(&response).ok()?,
+        ))
+    }
+}
+
+impl MockChannel {
+    /// 请求上传文件，append 模式下返回未完成任务的已上传大小
+    fn file_init(&self, topic: &str, payload: &[u8]) -> Option<Item> {
+        let request: ParamsRequest<InitParams> = serde_json::from_slice(payload).ok()?;
+        let params = request.params;
+        let mut state = self.state.lock().ok()?;
+        let existing = state.uploads.get(&params.file_name);
+        let (code, data) = match (&params.conflict_strategy, existing) {
+            (Some(ConflictStrategy::Append), Some(upload)) if upload.complete => {
+                (6001, InitData::default())
+            }
+            (Some(ConflictStrategy::Append), Some(upload)) => (
+                200,
+                InitData {
+                    file_name: params.file_name.clone(),
+                    upload_id: upload.upload_id.clone(),
+                    offset: Some(upload.data.len()),
+                },
+            ),
+            (Some(ConflictStrategy::Reject), Some(_)) => (6002, InitData::default()),
+            _ => {
+                let upload = MockUpload {
+                    upload_id: format!("upload-{}", global_id_next()),
+                    size: params.file_size as u64,
+                    fic_value: params.fic_value,
+                    ..Default::default()
+                };
+                let data = InitData {
+                    file_name: params.file_name.clone(),
+                    upload_id: upload.upload_id.clone(),
+                    offset: None,
+                };
+                state.uploads.insert(params.file_name, upload);
+                (200, data)
+            }
+        };
+        Self::file_reply(topic, &request.id, code, &data)
+    }
+
+    /// 上传文件分片，分片必须从已上传的位置开始
+    fn file_send(&self, topic: &str, payload: &[u8]) -> Option<Item> {
+        let len = u16::from_be_bytes(payload.get(..2)?.try_into().ok()?) as usize;
+        let header: SendHeader = serde_json::from_slice(payload.get(2..2 + len)?).ok()?;
+        let bytes = payload.get(2 + len..payload.len().checked_sub(2)?)?;
+        let digest = u16::from_le_bytes(payload[payload.len() - 2..].try_into().ok()?);
+        let params = header.params;
+        let mut state = self.state.lock().ok()?;
+        match &mut state.drop_chunks {
+            (0, 0) => {}
+            (0, count) => {
+                *count -= 1;
+                return None;
+            }
+            (skip, _) => *skip -= 1,
+        }
+        let upload = state
+            .uploads
+            .values_mut()
+            .find(|u| u.upload_id == params.upload_id)?;
+        let mut data = SendReplyData {
+            upload_id: params.upload_id.clone(),
+            offset: params.offset,
+            b_size: params.b_size,
+            ..Default::default()
+        };
+        let code = if crate::file::util::crc_ibm(bytes) != digest || bytes.len() != params.b_size {
+            6003
+        } else if params.offset != upload.data.len() || upload.complete {
+            6004
+        } else {
+            upload.data.extend_from_slice(bytes);
+            if upload.data.len() as u64 == upload.size || params.is_complete == Some(true) {
+                upload.complete = true;
+                data.complete = Some(true);
+                data.fic_value_client = upload.fic_value.clone();
+                data.fic_value_server = Some(crate::file::util::fic_value(
+                    crate::file::util::CRC64.checksum(&upload.data),
+                ));
+            }
+            200
+        };
+        Self::file_reply(topic, &header.id, code, &data)
+    }
+
+    fn file_reply<T: Serialize>(topic: &str, id: &str, code: u64, data: &T) -> Option<Item> {
+        let response = json!({"id": id, "code": code, "data": data, "message": Value::Null});
+        Some((
+            format!("{}_reply", topic),
+            serde_json::to_vec(&response).ok()?,
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl Channel for MockChannel {
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<()> {
+        debug!(
+            "mock cloud recv: {} {}",
+            topic,
+            String::from_utf8_lossy(&payload)
+        );
+        self.state
+            .lock()
+            .map_err(|_| Error::Lock)?
+            .published
+            .push((topic.clone(), payload.clone()));
+        if let Some(reply) = self.reply(&topic, &payload) {
+            self.down_tx.send(reply).map_err(|_| Error::MpscSendError)?;
+        }
+        self.up_tx
+            .send((topic, payload))
+            .map_err(|_| Error::MpscSendError)
+    }
+
+    async fn subscribe(&self, topics: Vec<String>) -> Result<()> {
+        self.state
+            .lock()
+            .map_err(|_| Error::Lock)?
+            .subscriptions
+            .extend(topics);
+        Ok(())
+    }
+
+    /// 只有设备订阅过的 topic 才会下发
+    fn incoming(&self) -> Option<Incoming> {
+        let rx = self.down_rx.lock().ok()?.take()?;
+        let state = self.state.clone();
+        Some(Box::pin(futures::stream::unfold(
+            (rx, state),
+            |(mut rx, state)| async move {
+                while let Some((topic, payload)) = rx.recv().await {
+                    let subscribed = state
+                        .lock()
+                        .map(|s| s.subscriptions.iter().any(|f| topic_matches(f, &topic)))
+                        .unwrap_or(false);
+                    if subscribed {
+                        return Some(((topic, payload), (rx, state)));
+                    }
+                    debug!("mock cloud drop: {} 未订阅", topic);
+                }
+                None
+            },
+        )))
+    }
+}
+
+/// 各模块测试共用的工具
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub fn three() -> ThreeTuple {
+        ThreeTuple {
+            product_key: "a13FN5TplKq".to_string(),
+            device_name: "mqtt_basic_demo".to_string(),
+            device_secret: "jA0K15GobTDa5wgOtJPzdtcZPc4X7NYQ".to_string(),
+        }
+    }
+
+    /// 同时处理连接上的消息，直到 `fut` 完成
+    pub async fn drive<T>(
+        conn: &mut ChannelConnection<MockChannel>,
+        fut: impl std::future::Future<Output = T>,
+    ) -> T {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                res = &mut fut => return res,
+                _ = conn.poll() => {}
+            }
+        }
+    }
+    /// 测试 HTTP 服务器收到的请求，头部名称为小写
+    #[derive(Debug, Clone)]
+    pub struct HttpRequest {
+        pub method: String,
+        /// 请求路径，包含查询参数
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl HttpRequest {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// 测试 HTTP 服务器的响应，`content-length` 自动按 `body` 填写
+    #[derive(Debug, Clone)]
+    pub struct HttpResponse {
+        /// 状态码和原因，如 `200 OK`
+        pub status: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+        /// 只发送 `body` 的前几个字节后断开
+        pub truncate: Option<usize>,
+        /// 不回复，保持连接
+        pub hang: bool,
+    }
+
+    impl HttpResponse {
+        pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+            Self {
+                status: "200 OK".to_string(),
+                headers: Vec::new(),
+                body: body.into(),
+                truncate: None,
+                hang: false,
+            }
+        }
+
+        pub fn hang() -> Self {
+            Self {
+                hang: true,
+                ..Self::ok(Vec::new())
+            }
+        }
+
+        pub fn status(mut self, status: &str) -> Self {
+            self.status = status.to_string();
+            self
+        }
+
+        pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// 在本地端口上启动 HTTP/1.1 服务器，每个请求交给 `handler` 生成响应，返回 `host:port`
+    pub async fn serve_http<F>(handler: F) -> String
+    where
+        F: Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    while let Some(request) = read_request(&mut stream).await {
+                        let response = handler(request);
+                        if !write_response(stream.get_mut(), response).await {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        host
+    }
+
+    /// 读取一个请求，连接关闭或格式错误时返回 `None`
+    async fn read_request(
+        stream: &mut tokio::io::BufReader<tokio::net::TcpStream>,
+    ) -> Option<HttpRequest> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (key, value) = line.split_once(':')?;
+            headers.push((key.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let mut request = HttpRequest {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+        let len = request
+            .header("content-length")
+            .unwrap_or("0")
+            .parse()
+            .ok()?;
+        request.body = vec![0; len];
+        stream.read_exact(&mut request.body).await.ok()?;
+        Some(request)
+    }
+
+    /// 发送响应，之后需要关闭连接时返回 false
+    async fn write_response(stream: &mut tokio::net::TcpStream, response: HttpResponse) -> bool {
+        use tokio::io::AsyncWriteExt;
+
+        if response.hang {
+            futures::future::pending::<()>().await;
+        }
+        let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+        for (key, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        head.push_str(&format!("content-length: {}\r\n\r\n", response.body.len()));
+        let body = match response.truncate {
+            Some(len) => &response.body[..len],
+            None => &response.body[..],
+        };
+        let close = response.truncate.is_some()
+            || response
+                .headers
+                .iter()
+                .any(|(key, value)| key == "connection" && value == "close");
+        let res = stream.write_all(head.as_bytes()).await;
+        res.is_ok() && stream.write_all(body).await.is_ok() && !close
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use crate::DataModelOptions;
+
+    #[test]
+    fn 拒绝错误签名() {
+        let mut cloud = MockCloud::new(&three());
+        let mut other = three();
+        other.device_secret = "wrong".to_string();
+        assert!(cloud.connect(&DeviceAuthInfo::from_tuple(&other)).is_err());
+        assert!(cloud.connect(&DeviceAuthInfo::from_tuple(&three())).is_ok());
+    }
+
+    #[tokio::test]
+    async fn 物模型() {
+        use crate::dm::recv::RecvEnum;
+        use crate::DataModelMsg;
+
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let mut dm = conn.data_model(DataModelOptions::new()).unwrap();
+        dm.init().await.unwrap();
+
+        dm.send(DataModelMsg::property_post(json!({"LightSwitch": 1})))
+            .await
+            .unwrap();
+        assert_eq!(cloud.properties()["LightSwitch"], 1);
+        conn.poll().await.unwrap();
+        match dm.poll().await.unwrap() {
+            RecvEnum::EventPostReply(reply) => assert_eq!(reply.code, 200),
+            other => panic!("{:?}", other),
+        }
+
+        let id = cloud
+            .service_call("upload_file", json!({"path": "a"}))
+            .unwrap();
+        conn.poll().await.unwrap();
+        match dm.poll().await.unwrap() {
+            RecvEnum::Service(data) => {
+                assert_eq!(data.msg_id, id);
+                assert_eq!(data.service_id, "upload_file");
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let rrpc_id = cloud.rrpc_call("echo", json!({})).unwrap();
+        conn.poll().await.unwrap();
+        match dm.poll().await.unwrap() {
+            RecvEnum::RrpcService(data) => assert_eq!(data.rrpc_id, rrpc_id),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn 时间同步和升级() {
+        use crate::ota::recv::OTARecv;
+
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let mut ntp = conn.ntp_service().unwrap();
+        ntp.init().await.unwrap();
+        let mut ota = conn.ota().unwrap();
+        ota.sub_all::<crate::ota::RecvKind>().await.unwrap();
+
+        ntp.send().await.unwrap();
+        conn.poll().await.unwrap();
+        ntp.poll().await.unwrap().calc().await.unwrap();
+
+        let package = PackageData {
+            size: 1,
+            version: "1.0.1".to_string(),
+            is_diff: None,
+            url: "http://127.0.0.1/a.bin".to_string(),
+            md5: None,
+            sign: "".to_string(),
+            sign_method: "Md5".to_string(),
+            module: None,
+            ext_data: None,
+        };
+        cloud.set_firmware(Some(package.clone()));
+        ota.query_firmware(None).await.unwrap();
+        conn.poll().await.unwrap();
+        match ota.poll().await.unwrap() {
+            OTARecv::GetFirmwareReply(reply) => {
+                assert_eq!(reply.data.unwrap().version, "1.0.1")
+            }
+            other => panic!("{:?}", other),
+        }
+
+        cloud.ota_push(package).unwrap();
+        conn.poll().await.unwrap();
+        assert!(matches!(
+            ota.poll().await.unwrap(),
+            OTARecv::UpgradePackageRequest(_)
+        ));
+    }
+}
@@ -3,11 +3,13 @@
 use crate::util::auth;
 use crate::*;
 use log::*;
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Transport};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, Transport};
 use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub enum MqttInstance {
@@ -94,37 +96,109 @@ pub struct MqttConnection {
     pub event_loop: EventLoop,
     pub mqtt: Arc<AsyncClient>,
     pub mqtt_client: MqttClient,
+    pub connection: ChannelConnection<AsyncClient>,
+    stats: Arc<MqttStats>,
+    ping_sent: Option<Instant>,
+}
+
+/// MQTT 连接的统计信息，用于网络诊断
+#[derive(Debug, Default)]
+pub struct MqttStats {
+    /// 收到的 CONNACK 次数
+    connects: AtomicU64,
+    /// 最近一次 PINGREQ 到 PINGRESP 的时间，单位毫秒，0 表示还没有测量
+    rtt: AtomicU64,
+}
+
+impl MqttStats {
+    /// 最近一次心跳的往返时间
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// 首次连接之后的重连次数
+    pub fn reconnects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed).saturating_sub(1)
+    }
 }
 
 impl MqttConnection {
-    pub fn new(mqtt_client: MqttClient) -> Self {
+    pub fn new(mut mqtt_client: MqttClient) -> Self {
         let options = mqtt_client.options.clone();
         let (mqtt, event_loop) = AsyncClient::new(options, 16);
+        let mqtt = Arc::new(mqtt);
+        let mut connection = ChannelConnection::from_arc(mqtt_client.three.clone(), mqtt.clone());
+        connection.executors = std::mem::take(&mut mqtt_client.executors);
+        connection.set_online(false);
         Self {
             mqtt_client,
             event_loop,
-            mqtt: Arc::from(mqtt),
+            mqtt,
+            connection,
+            stats: Arc::new(MqttStats::default()),
+            ping_sent: None,
         }
     }
+
+    /// 连接的统计信息，随 [`MqttConnection::poll`] 更新
+    pub fn stats(&self) -> Arc<MqttStats> {
+        self.stats.clone()
+    }
+
     pub async fn poll(&mut self) -> Result<Event> {
-        let incoming = self.event_loop.poll().await?;
+        let incoming = match self.event_loop.poll().await {
+            Ok(incoming) => incoming,
+            Err(err) => {
+                self.connection.set_online(false);
+                return Err(err.into());
+            }
+        };
         match &incoming {
             Event::Incoming(packet) => match packet {
                 Packet::Publish(data) => {
-                    for e in &mut self.mqtt_client.executors {
-                        if let Err(err) = e.execute(&data.topic, &data.payload).await {
-                            debug!("{} error: {}", data.topic, err);
-                        }
+                    self.connection.dispatch(&data.topic, &data.payload).await;
+                }
+                Packet::ConnAck(_) => {
+                    self.stats.connects.fetch_add(1, Ordering::Relaxed);
+                    self.connection.set_online(true);
+                }
+                Packet::Disconnect => {
+                    self.connection.set_online(false);
+                }
+                Packet::PingResp => {
+                    if let Some(sent) = self.ping_sent.take() {
+                        let rtt = sent.elapsed().as_millis().max(1) as u64;
+                        self.stats.rtt.store(rtt, Ordering::Relaxed);
                     }
                 }
                 _ => {}
             },
+            Event::Outgoing(Outgoing::PingReq) => {
+                self.ping_sent = Some(Instant::now());
+            }
             _ => {}
         }
         Ok(incoming)
     }
 }
 
+impl Deref for MqttConnection {
+    type Target = ChannelConnection<AsyncClient>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl DerefMut for MqttConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.connection
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DeviceAuthInfo {
     pub client_id: String,
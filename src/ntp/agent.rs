@@ -0,0 +1,190 @@
+//! 周期性时间同步，过滤样本后维护 SDK 的时钟偏移。
+
+use super::base::*;
+use super::recv::NtpRecv;
+use crate::alink::aiot_module::ChannelConnection;
+use crate::alink::channel::Channel;
+use crate::{Error, Result};
+use log::*;
+use rumqttc::AsyncClient;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// 时间同步的参数
+#[derive(Debug, Clone)]
+pub struct NtpOptions {
+    /// 同步间隔
+    pub interval: Duration,
+    /// 等待应答的超时
+    pub timeout: Duration,
+    /// 参与过滤的最近样本数
+    pub samples: usize,
+    /// 往返时延超过该值的样本直接丢弃
+    pub max_rtt: Duration,
+    /// 是否通过 `clock_settime` 修改系统时间，没有权限时只维护 SDK 的时钟偏移
+    pub set_system_time: bool,
+    /// 偏移超过该值时才修改系统时间
+    pub step_threshold: Duration,
+}
+
+impl Default for NtpOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(600),
+            timeout: Duration::from_secs(5),
+            samples: 8,
+            max_rtt: Duration::from_secs(5),
+            set_system_time: false,
+            step_threshold: Duration::from_millis(500),
+        }
+    }
+}
+
+/// 最近样本的过滤器：先按中位数绝对偏差剔除离群值，再取往返时延最小的样本。
+#[derive(Debug)]
+pub(crate) struct SampleFilter {
+    samples: VecDeque<NtpSample>,
+    capacity: usize,
+    max_rtt: i64,
+}
+
+impl SampleFilter {
+    pub fn new(capacity: usize, max_rtt: Duration) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            max_rtt: max_rtt.as_millis() as i64,
+        }
+    }
+
+    /// 加入一个样本，往返时延异常的样本返回 false
+    pub fn push(&mut self, sample: NtpSample) -> bool {
+        if sample.rtt < 0 || sample.rtt > self.max_rtt {
+            return false;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        true
+    }
+
+    /// 系统时间调整后，已有样本的偏移随之平移
+    pub fn shift(&mut self, offset: i64) {
+        for sample in self.samples.iter_mut() {
+            sample.offset -= offset;
+        }
+    }
+
+    pub fn offset(&self) -> Option<i64> {
+        let median = |mut values: Vec<i64>| {
+            values.sort_unstable();
+            values[values.len() / 2]
+        };
+        if self.samples.is_empty() {
+            return None;
+        }
+        let m = median(self.samples.iter().map(|s| s.offset).collect());
+        let d = median(self.samples.iter().map(|s| (s.offset - m).abs()).collect());
+        self.samples
+            .iter()
+            .filter(|s| (s.offset - m).abs() <= 3 * d + s.rtt / 2)
+            .min_by_key(|s| s.rtt)
+            .map(|s| s.offset)
+    }
+}
+
+/// 时间同步代理，周期性地请求云端时间并校正 SDK 的时钟。
+///
+/// 校正结果通过 [`now_millis`] 获取；开启 [`NtpOptions::set_system_time`] 并且有权限时，
+/// 同时修改系统时间。
+pub struct NtpAgent<C = AsyncClient> {
+    module: super::Module<C>,
+    options: NtpOptions,
+    filter: SampleFilter,
+    set_system_time: bool,
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    pub fn ntp_agent(&mut self, options: NtpOptions) -> Result<NtpAgent<C>> {
+        let module = self.ntp_service()?;
+        Ok(NtpAgent {
+            module,
+            filter: SampleFilter::new(options.samples, options.max_rtt),
+            set_system_time: options.set_system_time,
+            options,
+        })
+    }
+}
+
+impl<C: Channel> NtpAgent<C> {
+    pub async fn init(&self) -> Result<()> {
+        self.module.init().await
+    }
+
+    /// 同步一次，返回本次的样本。
+    pub async fn sync(&mut self) -> Result<NtpSample> {
+        self.module.send().await?;
+        let sample = tokio::time::timeout(self.options.timeout, self.module.poll())
+            .await
+            .map_err(|_| Error::WaitResponseTimeout("ntp".to_string()))??;
+        let NtpRecv::NtpResponseType(response) = sample;
+        let sample = response.sample(system_millis());
+        debug!("ntp sample: {:?}", sample);
+        if self.filter.push(sample) {
+            if let Some(offset) = self.filter.offset() {
+                self.apply(offset);
+            }
+        }
+        Ok(sample)
+    }
+
+    /// 订阅并按 [`NtpOptions::interval`] 持续同步，同步失败时等待下一次。
+    pub async fn run(&mut self) -> Result<()> {
+        self.init().await?;
+        let mut interval = tokio::time::interval(self.options.interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.sync().await {
+                warn!("ntp sync error: {:?}", err);
+            }
+        }
+    }
+
+    fn apply(&mut self, offset: i64) {
+        let threshold = self.options.step_threshold.as_millis() as i64;
+        if self.set_system_time && offset.abs() >= threshold {
+            match adjust_system_time(offset) {
+                Ok(()) => {
+                    info!("system time adjusted by {}ms", offset);
+                    self.filter.shift(offset);
+                    set_clock_offset(0);
+                    return;
+                }
+                Err(err) => {
+                    warn!("{}, keep offset in sdk only", err);
+                    self.set_system_time = false;
+                }
+            }
+        }
+        set_clock_offset(offset);
+    }
+}
+
+#[test]
+fn test_sample_filter() {
+    let mut filter = SampleFilter::new(8, Duration::from_secs(5));
+    assert_eq!(filter.offset(), None);
+    // 往返时延超限的样本被丢弃
+    assert!(!filter.push(NtpSample {
+        offset: 0,
+        rtt: 6000
+    }));
+    for (offset, rtt) in [(1000, 80), (1010, 60), (990, 100), (1005, 40), (4000, 30)] {
+        assert!(filter.push(NtpSample { offset, rtt }));
+    }
+    // 4000 是离群值，虽然往返时延最小也不采用
+    assert_eq!(filter.offset(), Some(1005));
+    filter.shift(1005);
+    assert_eq!(filter.offset(), Some(0));
+}
@@ -4,6 +4,7 @@ use log::*;
 use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
@@ -22,16 +23,102 @@ pub struct NtpResponse {
     pub server_send_time: i64,
 }
 
+/// SDK 的时钟偏移，单位毫秒：精确时间 = 系统时间 + 偏移
+static CLOCK_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// 当前 SDK 使用的时钟偏移，单位毫秒
+pub fn clock_offset() -> i64 {
+    CLOCK_OFFSET.load(Ordering::Relaxed)
+}
+
+/// 设置 SDK 的时钟偏移，通常由 [`NtpAgent`](super::NtpAgent) 维护
+pub fn set_clock_offset(offset: i64) {
+    CLOCK_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// 未校正的系统时间，Unix 毫秒
+pub fn system_millis() -> i64 {
+    use std::time::SystemTime;
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+/// 经过 NTP 校正的当前时间，Unix 毫秒，不需要修改系统时间的权限
+pub fn now_millis() -> i64 {
+    system_millis() + clock_offset()
+}
+
+/// 一次时间同步的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NtpSample {
+    /// 服务端时间与设备系统时间的差，单位毫秒
+    pub offset: i64,
+    /// 往返时延，不含服务端处理时间，单位毫秒
+    pub rtt: i64,
+}
+
 impl NtpResponse {
-    /// 设备端计算出服务端当前精确的Unix时间，并设置为当前系统时间。
+    /// 根据设备收到应答的系统时间计算时钟偏移和往返时延。
+    pub fn sample(&self, device_recv_time: i64) -> NtpSample {
+        let offset = ((self.server_recv_time - self.device_send_time)
+            + (self.server_send_time - device_recv_time))
+            / 2;
+        let rtt = (device_recv_time - self.device_send_time)
+            - (self.server_send_time - self.server_recv_time);
+        NtpSample { offset, rtt }
+    }
+
+    /// 设备端计算出服务端当前精确的Unix时间。
     /// 设备端收到服务端的时间记为${deviceRecvTime}，则设备上的精确时间为：(${serverRecvTime}+${serverSendTime}+${deviceRecvTime}-${deviceSendTime})/2。
+    ///
+    /// 只计算单次结果，持续校正时钟请使用 [`NtpAgent`](super::NtpAgent)。
     pub async fn calc(&self) -> Result<chrono::NaiveDateTime> {
-        use chrono::{NaiveDateTime, Utc};
-        let now = Utc::now().timestamp_millis();
-        let utc = (self.server_recv_time + self.server_send_time + now - self.device_send_time) / 2;
+        use chrono::NaiveDateTime;
+        let now = system_millis();
+        let utc = now + self.sample(now).offset;
         let utc = NaiveDateTime::from_timestamp(utc / 1000, (utc % 1000) as u32 * 1_000_000);
         debug!("{}", utc.to_string());
-        // TODO: 设置系统时间
         Ok(utc)
     }
 }
+
+/// 把系统时间调整 `offset` 毫秒，需要 `CAP_SYS_TIME` 权限。
+#[cfg(unix)]
+pub fn adjust_system_time(offset: i64) -> Result<()> {
+    let millis = system_millis() + offset;
+    let ts = libc::timespec {
+        tv_sec: millis.div_euclid(1000) as libc::time_t,
+        tv_nsec: (millis.rem_euclid(1000) * 1_000_000) as _,
+    };
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &ts) } != 0 {
+        debug!("clock_settime: {}", std::io::Error::last_os_error());
+        return Err(Error::SetSystemTimeError);
+    }
+    Ok(())
+}
+
+/// 把系统时间调整 `offset` 毫秒，当前平台不支持。
+#[cfg(not(unix))]
+pub fn adjust_system_time(_offset: i64) -> Result<()> {
+    Err(Error::SetSystemTimeError)
+}
+
+#[test]
+fn test_ntp_sample() {
+    // 服务端比设备快 1000ms，单程 50ms，服务端处理 10ms
+    let response = NtpResponse {
+        device_send_time: 10_000,
+        server_recv_time: 11_050,
+        server_send_time: 11_060,
+    };
+    let sample = response.sample(10_110);
+    assert_eq!(
+        sample,
+        NtpSample {
+            offset: 1000,
+            rtt: 100
+        }
+    );
+}
@@ -1,8 +1,10 @@
 //! NTP 时间同步服务。
 
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::alink::{AlinkRequest, AlinkResponse};
-use crate::mqtt::MqttConnection;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
@@ -10,31 +12,33 @@ use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
+pub use self::agent::{NtpAgent, NtpOptions};
+pub use self::base::{clock_offset, now_millis, NtpSample};
+
+pub mod agent;
 pub mod base;
 pub mod push;
 pub mod recv;
 
 pub type Recv = NtpRecv;
 pub type RecvKind = NtpRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
 
-impl Module {
+impl<C: Channel> Module<C> {
     pub async fn init(&self) -> Result<()> {
         self.sub_all::<RecvKind>().await
     }
 }
 
-impl MqttConnection {
-    pub fn ntp_service(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn ntp_service(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
         self.module(Box::new(executor), rx, ())
     }
@@ -42,13 +46,37 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{drive, three};
+    use crate::mock::MockCloud;
+    use crate::DeviceAuthInfo;
+
+    #[tokio::test]
+    async fn 时钟校正() {
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let mut agent = conn.ntp_agent(NtpOptions::default()).unwrap();
+        agent.init().await.unwrap();
+        for _ in 0..3 {
+            let sample = drive(&mut conn, agent.sync()).await.unwrap();
+            // 模拟云端与设备使用同一个时钟
+            assert!(sample.offset.abs() < 1000 && sample.rtt >= 0);
+        }
+        assert!(clock_offset().abs() < 1000);
     }
 }
@@ -1,4 +1,5 @@
 use super::base::*;
+use crate::alink::channel::Channel;
 use crate::alink::{AlinkRequest, AlinkResponse, SysAck};
 use crate::{Error, Result, ThreeTuple};
 use regex::Regex;
@@ -10,13 +11,11 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 上报设备时间
     pub async fn send(&self) -> crate::Result<()> {
-        use std::time::SystemTime;
-        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
         let payload = NtpRequest {
-            device_send_time: now.as_millis() as u64,
+            device_send_time: system_millis() as u64,
         };
         let topic = format!(
             "/ext/ntp/{}/{}/request",
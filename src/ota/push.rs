@@ -1,5 +1,6 @@
 use super::base::*;
 use crate::alink::alink_topic::ALinkSubscribeTopic;
+use crate::alink::channel::Channel;
 use crate::alink::{global_id_next, SysAck, ALINK_VERSION};
 use crate::alink::{AlinkRequest, AlinkResponse};
 use crate::http_downloader::{HttpDownloadConfig, HttpDownloader};
@@ -15,7 +16,7 @@ use std::fs;
 use std::path::Path;
 use tempdir::TempDir;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 设备上报OTA模块版本
     ///
     /// # 参数
@@ -88,28 +89,38 @@ impl super::Module {
     ) -> crate::Result<String> {
         let module = package.module.clone();
         let version = package.version.clone();
-        let downloader = HttpDownloader::new(&package.url, path);
-        let results = futures_util::future::join(
-            async {
-                let process_receiver = downloader.get_process_receiver();
-                let mut mutex_guard = process_receiver.lock().await;
-                if let Some(download_process) = mutex_guard.recv().await {
-                    let report_progress = ReportProgress {
-                        module: module.clone(),
-                        desc: String::from(""),
-                        step: ((download_process.percent * 100f64) as u32).to_string(),
-                    };
-                    debug!("report_process finished {}", report_progress.step);
-                    self.report_process(report_progress);
+        // 升级包较大，失败后保留已下载的部分
+        let config = HttpDownloadConfig::builder(&package.url, path)
+            .expected_length(package.size)
+            .expected_digest(&package.sign_method, &package.sign)
+            .resume(true)
+            .build();
+        let downloader = HttpDownloader::with_config(config)?;
+        let mut process_receiver = downloader.get_process_receiver();
+        let download = downloader.start();
+        tokio::pin!(download);
+        let mut step = 0;
+        let result = loop {
+            tokio::select! {
+                result = &mut download => break result,
+                Ok(()) = process_receiver.changed() => {
+                    let percent = process_receiver.borrow().percent;
+                    let current = (percent * 100f64) as u32;
+                    // 每增加 1% 上报一次
+                    if current > step {
+                        step = current;
+                        let report_progress = ReportProgress {
+                            module: module.clone(),
+                            desc: String::from(""),
+                            step: step.to_string(),
+                        };
+                        debug!("report_process {}", report_progress.step);
+                        self.report_process(report_progress).await?;
+                    }
                 }
-            },
-            downloader.start(),
-        )
-        .await;
-        let mut ota_file_path = results.1?;
-        let mut buffer = fs::read(&ota_file_path)?;
-        crate::util::validate(&buffer, &package.sign_method, &package.sign)?;
-        Ok(ota_file_path)
+            }
+        };
+        Ok(result?)
     }
 }
 
@@ -83,6 +83,8 @@ impl From<ConnectOrUpdate> for TunnelParams {
             port: format!("{}", data.port),
             path: data.path,
             token: data.token,
+            token_expire: u64::try_from(data.token_expire).ok(),
+            schema: data.schema,
         }
     }
 }
@@ -1,8 +1,10 @@
 //! 远程登录
 
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::{AiotModule, ModuleRecvKind};
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::alink::{AlinkRequest, AlinkResponse};
-use crate::mqtt::MqttConnection;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
@@ -10,8 +12,6 @@ use regex::Regex;
 use rumqttc::{AsyncClient, QoS};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::base::RemoteAccessOptions;
 use self::recv::*;
@@ -22,9 +22,9 @@ pub mod recv;
 
 pub type Recv = RemoteAccessRecv;
 pub type RecvKind = RemoteAccessRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
 
-impl Module {
+impl<C: Channel> Module<C> {
     pub async fn init(&mut self) -> Result<()> {
         self.sub_all::<RecvKind>().await?;
         self.proxy_request().await?;
@@ -32,13 +32,13 @@ impl Module {
     }
 }
 
-impl MqttConnection {
-    pub fn remote_access(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(16);
-        let ra = RemoteAccessOptions::new(self.mqtt_client.three.clone());
+impl<C: Channel> ChannelConnection<C> {
+    pub fn remote_access(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(16);
+        let ra = RemoteAccessOptions::new(self.three.clone());
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
         let module = self.module(Box::new(executor), rx, ())?;
         Ok(module)
@@ -47,13 +47,13 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
     }
 }
@@ -1,5 +1,6 @@
 use super::base::*;
 use crate::alink::alink_topic::ALinkSubscribeTopic;
+use crate::alink::channel::Channel;
 use crate::alink::{global_id_next, SysAck, ALINK_VERSION};
 use crate::alink::{AlinkRequest, AlinkResponse};
 use crate::http_downloader::{HttpDownloadConfig, HttpDownloader};
@@ -15,7 +16,7 @@ use std::fs;
 use std::path::Path;
 use tempdir::TempDir;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 设设备请求SSH远程通道认证信息
     pub async fn proxy_request(&mut self) -> crate::Result<()> {
         let payload = ();
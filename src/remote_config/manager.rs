@@ -0,0 +1,254 @@
+//! 远程配置的本地缓存和应用。
+//!
+//! 最近一次应用成功的配置按 `config_id` 保存在目录中，启动时可以在连接前通过
+//! [`RemoteConfigManager::load`] 加载。收到新配置时下载、校验、解析后交给应用回调，
+//! 回调拒绝的配置不会保存，云端推送的配置按应用结果回复。
+
+use super::recv::{RemoteConfigFileInfo, RemoteConfigRecv};
+use crate::alink::channel::Channel;
+use crate::util::write_atomic;
+use crate::{Error, Result};
+use log::*;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// 当前配置的信息文件
+const CURRENT_FILE: &str = "current.json";
+
+/// 解析配置文件为 JSON 值，格式错误时返回 [`Error::ConfigParse`]。
+///
+/// 闭包 `Fn(&[u8]) -> Result<Value>` 也实现了这个 trait。
+pub trait ConfigParser: Send + Sync + 'static {
+    fn parse(&self, data: &[u8]) -> Result<Value>;
+}
+
+impl<F> ConfigParser for F
+where
+    F: Fn(&[u8]) -> Result<Value> + Send + Sync + 'static,
+{
+    fn parse(&self, data: &[u8]) -> Result<Value> {
+        self(data)
+    }
+}
+
+/// JSON 格式的配置
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonParser;
+
+impl ConfigParser for JsonParser {
+    fn parse(&self, data: &[u8]) -> Result<Value> {
+        serde_json::from_slice(data).map_err(|e| Error::ConfigParse(e.to_string()))
+    }
+}
+
+/// TOML 格式的配置
+#[cfg(feature = "toml")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TomlParser;
+
+#[cfg(feature = "toml")]
+impl ConfigParser for TomlParser {
+    fn parse(&self, data: &[u8]) -> Result<Value> {
+        toml::from_slice(data).map_err(|e| Error::ConfigParse(e.to_string()))
+    }
+}
+
+/// YAML 格式的配置
+#[cfg(feature = "yaml")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct YamlParser;
+
+#[cfg(feature = "yaml")]
+impl ConfigParser for YamlParser {
+    fn parse(&self, data: &[u8]) -> Result<Value> {
+        serde_yaml::from_slice(data).map_err(|e| Error::ConfigParse(e.to_string()))
+    }
+}
+
+/// 已应用的配置
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    pub info: RemoteConfigFileInfo,
+    /// 配置文件的原始内容
+    pub data: Vec<u8>,
+    /// 解析后的配置
+    pub value: Value,
+}
+
+type ApplyFn = Box<dyn Fn(&RemoteConfig) -> std::result::Result<(), String> + Send + Sync>;
+
+/// 远程配置管理
+pub struct RemoteConfigManager {
+    dir: PathBuf,
+    parser: Box<dyn ConfigParser>,
+    apply: ApplyFn,
+    current: Option<RemoteConfig>,
+}
+
+impl RemoteConfigManager {
+    /// 配置保存在 `dir` 目录，默认按 JSON 解析并接受所有配置
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            parser: Box::new(JsonParser),
+            apply: Box::new(|_| Ok(())),
+            current: None,
+        }
+    }
+
+    pub fn set_parser(&mut self, parser: impl ConfigParser) {
+        self.parser = Box::new(parser);
+    }
+
+    /// 设置应用配置的回调，返回 `Err` 时拒绝该配置
+    pub fn set_apply<F>(&mut self, apply: F)
+    where
+        F: Fn(&RemoteConfig) -> std::result::Result<(), String> + Send + Sync + 'static,
+    {
+        self.apply = Box::new(apply);
+    }
+
+    /// 当前应用的配置
+    pub fn current(&self) -> Option<&RemoteConfig> {
+        self.current.as_ref()
+    }
+
+    /// 加载并应用上次保存的配置，不需要连接。没有保存的配置或信息文件损坏时返回 `None`。
+    pub async fn load(&mut self) -> Result<Option<&RemoteConfig>> {
+        let data = match fs::read(self.dir.join(CURRENT_FILE)).await {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        let info = match serde_json::from_slice::<RemoteConfigFileInfo>(&data) {
+            Ok(info) => info,
+            Err(err) => {
+                warn!("remote config {} corrupted: {}", CURRENT_FILE, err);
+                return Ok(None);
+            }
+        };
+        let data = fs::read(self.data_path(&info.config_id)?).await?;
+        crate::util::validate(&data, &info.sign_method, &info.sign)?;
+        self.apply(info, data)?;
+        Ok(self.current.as_ref())
+    }
+
+    /// 订阅配置、请求一次当前配置，之后持续处理云端下发的配置
+    pub async fn run<C: Channel>(&mut self, module: &mut super::Module<C>) -> Result<()> {
+        module.init().await?;
+        module.get(true).await?;
+        loop {
+            let recv = module.poll().await?;
+            if let Err(err) = self.handle(module, recv).await {
+                warn!("remote config error: {}", err);
+            }
+        }
+    }
+
+    /// 处理一条远程配置消息，应用了新配置时返回 true。
+    ///
+    /// 与当前配置相同时不重复下载；推送的配置按处理结果回复 200 或 400。
+    pub async fn handle<C: Channel>(
+        &mut self,
+        module: &mut super::Module<C>,
+        recv: RemoteConfigRecv,
+    ) -> Result<bool> {
+        let (id, info, push) = match recv {
+            RemoteConfigRecv::RemoteConfigGetReply(reply) => (reply.id, reply.data, false),
+            RemoteConfigRecv::RemoteConfigPush(push) => (push.id, push.data, true),
+        };
+        let result = match info {
+            Some(info) => self.update(module, info).await,
+            None => Ok(false),
+        };
+        if push {
+            let code = if result.is_ok() { 200 } else { 400 };
+            module.push_reply(id, code).await?;
+        }
+        result
+    }
+
+    async fn update<C: Channel>(
+        &mut self,
+        module: &mut super::Module<C>,
+        info: RemoteConfigFileInfo,
+    ) -> Result<bool> {
+        if let Some(current) = &self.current {
+            if current.info.config_id == info.config_id && current.info.sign == info.sign {
+                debug!("remote config {} already applied", info.config_id);
+                return Ok(false);
+            }
+        }
+        let data = module.download_config(&info).await?;
+        let previous = self.current.as_ref().map(|c| c.info.config_id.clone());
+        self.apply(info, data)?;
+        self.save(previous).await?;
+        Ok(true)
+    }
+
+    fn apply(&mut self, info: RemoteConfigFileInfo, data: Vec<u8>) -> Result<()> {
+        let value = self.parser.parse(&data)?;
+        let config = RemoteConfig { info, data, value };
+        (self.apply)(&config).map_err(Error::ConfigRejected)?;
+        info!("remote config {} applied", config.info.config_id);
+        self.current = Some(config);
+        Ok(())
+    }
+
+    /// 保存当前配置，删除之前的配置文件
+    async fn save(&self, previous: Option<String>) -> Result<()> {
+        let current = match &self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(&self.dir).await?;
+        write_atomic(&self.data_path(&current.info.config_id)?, &current.data).await?;
+        write_atomic(
+            &self.dir.join(CURRENT_FILE),
+            &serde_json::to_vec(&current.info)?,
+        )
+        .await?;
+        if let Some(previous) = previous.filter(|p| *p != current.info.config_id) {
+            fs::remove_file(self.data_path(&previous)?).await.ok();
+        }
+        Ok(())
+    }
+
+    /// `config_id` 来自云端，只允许作为文件名使用
+    fn data_path(&self, config_id: &str) -> Result<PathBuf> {
+        let name = Path::new(config_id);
+        if config_id.is_empty() || name.file_name() != Some(name.as_os_str()) {
+            return Err(Error::InvalidPath);
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+#[test]
+fn test_config_parser() {
+    assert_eq!(JsonParser.parse(br#"{"a": 1}"#).unwrap()["a"], 1);
+    assert!(matches!(
+        JsonParser.parse(b"a = 1"),
+        Err(Error::ConfigParse(_))
+    ));
+    #[cfg(feature = "toml")]
+    assert_eq!(
+        TomlParser.parse(b"[server]\nport = 80").unwrap()["server"]["port"],
+        80
+    );
+    #[cfg(feature = "yaml")]
+    assert_eq!(
+        YamlParser.parse(b"server:\n  port: 80").unwrap()["server"]["port"],
+        80
+    );
+}
+
+#[tokio::test]
+async fn test_load_corrupted() {
+    let dir = tempdir::TempDir::new("config").unwrap();
+    let mut manager = RemoteConfigManager::new(dir.path());
+    assert!(manager.load().await.unwrap().is_none());
+    // 写入中断留下的信息文件
+    std::fs::write(dir.path().join(CURRENT_FILE), br#"{"configId":"#).unwrap();
+    assert!(manager.load().await.unwrap().is_none());
+}
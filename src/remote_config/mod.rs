@@ -1,33 +1,46 @@
 //! 远程配置
 
 use crate::alink::aiot_module::AiotModule;
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::ModuleRecvKind;
-use crate::mqtt::MqttConnection;
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
 use rumqttc::{AsyncClient, QoS};
 use serde::Serialize;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
+#[cfg(feature = "toml")]
+pub use self::manager::TomlParser;
+#[cfg(feature = "yaml")]
+pub use self::manager::YamlParser;
+pub use self::manager::{ConfigParser, JsonParser, RemoteConfig, RemoteConfigManager};
+
 pub mod base;
+pub mod manager;
 pub mod push;
 pub mod recv;
 
 pub type Recv = RemoteConfigRecv;
 pub type RecvKind = RemoteConfigRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
 
-impl MqttConnection {
-    pub fn remote_config(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> Module<C> {
+    pub async fn init(&self) -> Result<()> {
+        self.sub_all::<RecvKind>().await
+    }
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    pub fn remote_config(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
 
         self.module(Box::new(executor), rx, ())
@@ -36,13 +49,103 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{drive, serve_http, three, HttpResponse};
+    use crate::mock::MockCloud;
+    use crate::DeviceAuthInfo;
+    use serde_json::Value;
+
+    /// 对所有请求返回 `body` 的 HTTP 服务器，返回地址
+    async fn serve(body: Vec<u8>) -> String {
+        let host = serve_http(move |_| HttpResponse::ok(body.clone())).await;
+        format!("http://{}/config", host)
+    }
+
+    #[tokio::test]
+    async fn 远程配置() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let dir = tempdir::TempDir::new("config").unwrap();
+        let data = br#"{"interval": 10}"#.to_vec();
+        let info = RemoteConfigFileInfo {
+            config_id: "c1".to_string(),
+            config_size: data.len() as u64,
+            sign: crate::util::sha256(&data),
+            sign_method: "Sha256".to_string(),
+            url: serve(data).await,
+            get_type: "file".to_string(),
+        };
+        let mut cloud = MockCloud::new(&three());
+        cloud.set_config(Some(info.clone()));
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let mut module = conn.remote_config().unwrap();
+        module.init().await.unwrap();
+
+        let mut manager = RemoteConfigManager::new(dir.path());
+        assert!(manager.load().await.unwrap().is_none());
+        module.get(true).await.unwrap();
+        conn.poll().await.unwrap();
+        let recv = module.poll().await.unwrap();
+        assert!(manager.handle(&mut module, recv).await.unwrap());
+        assert_eq!(manager.current().unwrap().value["interval"], 10);
+
+        // 相同的配置不重复下载，直接回复成功
+        cloud.config_push(info.clone()).unwrap();
+        conn.poll().await.unwrap();
+        let recv = module.poll().await.unwrap();
+        assert!(!manager.handle(&mut module, recv).await.unwrap());
+        let (topic, payload) = cloud.published().pop().unwrap();
+        assert!(topic.ends_with("/thing/config/push_reply"));
+        let reply: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(reply["code"], 200);
+
+        // 重启后在连接前加载缓存的配置
+        let applied = Arc::new(AtomicBool::new(false));
+        let mut manager = RemoteConfigManager::new(dir.path());
+        let flag = applied.clone();
+        manager.set_apply(move |config| {
+            if config.value["interval"].as_u64() > Some(60) {
+                return Err("interval too large".to_string());
+            }
+            flag.store(true, Ordering::Relaxed);
+            Ok(())
+        });
+        let config = manager.load().await.unwrap().unwrap();
+        assert_eq!(config.info.config_id, "c1");
+        assert!(applied.load(Ordering::Relaxed));
+
+        // 应用回调拒绝的配置不保存，回复失败
+        let data = br#"{"interval": 100}"#.to_vec();
+        let rejected = RemoteConfigFileInfo {
+            config_id: "c2".to_string(),
+            config_size: data.len() as u64,
+            sign: crate::util::sha256(&data),
+            url: serve(data).await,
+            ..info
+        };
+        cloud.config_push(rejected).unwrap();
+        conn.poll().await.unwrap();
+        let recv = module.poll().await.unwrap();
+        assert!(manager.handle(&mut module, recv).await.is_err());
+        let (_, payload) = cloud.published().pop().unwrap();
+        let reply: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(reply["code"], 400);
+        assert_eq!(manager.current().unwrap().info.config_id, "c1");
+        assert!(dir.path().join("c1").exists() && !dir.path().join("c2").exists());
     }
 }
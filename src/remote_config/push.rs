@@ -1,4 +1,5 @@
 use super::recv::RemoteConfigFileInfo;
+use crate::alink::channel::Channel;
 use crate::Error;
 use crate::{
     alink::{global_id_next, AlinkRequest, AlinkResponse, SysAck, ALINK_VERSION},
@@ -10,7 +11,7 @@ use serde_json::Value;
 use std::fs;
 use tempdir::TempDir;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 设备主动请求配置信息
     pub async fn get(&self, ack: bool) -> crate::Result<()> {
         let payload = RemoteConfigGetRequest {
@@ -64,35 +65,17 @@ impl super::Module {
         let config_id = config_info.config_id.clone();
         let tmp_dir = TempDir::new("remote_config")?;
         let file_path = tmp_dir.path().join(config_id.to_string());
-        let downloader = HttpDownloader::new(&config_info.url, file_path);
+        let config = HttpDownloadConfig::builder(&config_info.url, file_path)
+            .expected_length(config_info.config_size)
+            .expected_digest(&config_info.sign_method, &config_info.sign)
+            .build();
+        let downloader = HttpDownloader::with_config(config)?;
         let config_file_path = downloader.start().await?;
-        let mut buffer = fs::read(config_file_path)?;
-        crate::util::validate(&buffer, &config_info.sign_method, &config_info.sign)?;
+        let buffer = fs::read(config_file_path)?;
         // std::fs::remove_file(file_path);
         // std::fs::remove_dir_all(tmp_dir);
         Ok(buffer)
     }
-
-    /* 	/// 设备主动上报网络状态
-    pub async fn report_network_status(&self)-> crate::Result<()>{
-        let payload = AlinkResponse {
-            id,
-            code,
-            data: (),
-            message: None,
-            method: None,
-            version: None,
-        };
-        self
-            .publish(
-                format!(
-                    "/sys/{}/{}/_thing/diag/post",
-                    self.three.product_key, self.three.device_name
-                ),
-                &payload,
-            )
-            .await
-    } */
 }
 
 /// 远程配置获取请求
@@ -1,16 +1,16 @@
 //! 设备影子
 
 use crate::alink::aiot_module::AiotModule;
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::ModuleRecvKind;
-use crate::mqtt::MqttConnection;
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
 use rumqttc::{AsyncClient, QoS};
 use serde::Serialize;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
@@ -20,14 +20,14 @@ pub mod recv;
 
 pub type Recv = ShadowRecv;
 pub type RecvKind = ShadowRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
 
-impl MqttConnection {
-    pub fn shadow(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn shadow(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
 
         self.module(Box::new(executor), rx, ())
@@ -36,13 +36,13 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
     }
 }
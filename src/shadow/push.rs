@@ -1,9 +1,10 @@
+use crate::alink::channel::Channel;
 use crate::alink::{global_id_next, SysAck, ALINK_VERSION};
 use crate::shadow::base::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 影子设备属性更新
     ///
     /// # 参数
@@ -1,3 +1,4 @@
+use crate::alink::channel::Channel;
 use crate::alink::{global_id_next, AlinkRequest, AlinkResponse, SysAck, ALINK_VERSION};
 use crate::subdev::base::*;
 use serde::{Deserialize, Serialize};
@@ -18,7 +19,7 @@ pub struct LoginParam {
     pub clean_session: bool,
 }
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 子设备上线
     ///
     /// # 参数
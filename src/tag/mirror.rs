@@ -0,0 +1,196 @@
+//! 设备标签的本地镜像。
+//!
+//! 在本地维护期望的标签，与云端最近一次确认的标签比较后只上报变化的部分。
+
+use super::base::DeviceInfoKeyValue;
+use super::recv::TagRecv;
+use crate::alink::aiot_module::ChannelConnection;
+use crate::alink::channel::Channel;
+use crate::util::write_atomic;
+use crate::{Error, Result};
+use log::*;
+use rumqttc::AsyncClient;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// 标签同步的参数
+#[derive(Debug, Clone)]
+pub struct TagOptions {
+    /// 等待云端响应的超时
+    pub timeout: Duration,
+    /// 超时或发送失败后的重试次数
+    pub retries: u32,
+    /// 保存已确认标签的文件，重启后不重复上报没有变化的标签
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for TagOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            state_path: None,
+        }
+    }
+}
+
+enum Request {
+    Update(Vec<DeviceInfoKeyValue>),
+    Delete(Vec<String>),
+}
+
+/// 设备标签的本地镜像，需要同时调用连接的 `poll` 才能收到云端的响应
+pub struct TagMirror<C = AsyncClient> {
+    module: super::Module<C>,
+    options: TagOptions,
+    desired: BTreeMap<String, String>,
+    confirmed: BTreeMap<String, String>,
+}
+
+impl<C: Channel> ChannelConnection<C> {
+    pub fn tag_mirror(&mut self, options: TagOptions) -> Result<TagMirror<C>> {
+        Ok(TagMirror {
+            module: self.tag()?,
+            options,
+            desired: BTreeMap::new(),
+            confirmed: BTreeMap::new(),
+        })
+    }
+}
+
+impl<C: Channel> TagMirror<C> {
+    /// 订阅响应，并从 `state_path` 加载已确认的标签作为初始的期望标签。
+    /// 文件损坏时忽略，重新上报全部标签。
+    pub async fn init(&mut self) -> Result<()> {
+        self.module.init().await?;
+        if let Some(path) = &self.options.state_path {
+            if let Ok(data) = tokio::fs::read(path).await {
+                match serde_json::from_slice(&data) {
+                    Ok(confirmed) => {
+                        self.confirmed = confirmed;
+                        self.desired = self.confirmed.clone();
+                    }
+                    Err(err) => warn!("ignore corrupted tag state {:?}: {}", path, err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.desired.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.desired.remove(key);
+    }
+
+    /// 替换全部期望的标签
+    pub fn set_all(&mut self, tags: BTreeMap<String, String>) {
+        self.desired = tags;
+    }
+
+    pub fn desired(&self) -> &BTreeMap<String, String> {
+        &self.desired
+    }
+
+    /// 云端已确认的标签
+    pub fn confirmed(&self) -> &BTreeMap<String, String> {
+        &self.confirmed
+    }
+
+    /// 需要上报的标签和需要删除的标签名
+    pub fn diff(&self) -> (Vec<DeviceInfoKeyValue>, Vec<String>) {
+        let updates = self
+            .desired
+            .iter()
+            .filter(|(k, v)| self.confirmed.get(*k) != Some(*v))
+            .map(|(k, v)| DeviceInfoKeyValue {
+                attr_key: k.clone(),
+                attr_value: v.clone(),
+            })
+            .collect();
+        let deletes = self
+            .confirmed
+            .keys()
+            .filter(|k| !self.desired.contains_key(*k))
+            .cloned()
+            .collect();
+        (updates, deletes)
+    }
+
+    /// 上报与已确认标签的差异，成功后更新已确认的标签
+    pub async fn sync(&mut self) -> Result<()> {
+        let (updates, deletes) = self.diff();
+        if !updates.is_empty() {
+            self.retry(&Request::Update(updates.clone())).await?;
+            for item in updates {
+                self.confirmed.insert(item.attr_key, item.attr_value);
+            }
+            self.save().await?;
+        }
+        if !deletes.is_empty() {
+            self.retry(&Request::Delete(deletes.clone())).await?;
+            for key in deletes {
+                self.confirmed.remove(&key);
+            }
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// 发送请求并等待对应的响应，超时或发送失败时重试
+    async fn retry(&mut self, request: &Request) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let sent = match request {
+                Request::Update(infos) => self.module.update(infos.clone(), true).await,
+                Request::Delete(keys) => {
+                    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                    self.module.delete(&keys, true).await
+                }
+            };
+            let result = match sent {
+                Ok(id) => self.wait(&id).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err @ Error::CodeParams(..)) => return Err(err),
+                Err(err) if attempt < self.options.retries => {
+                    attempt += 1;
+                    warn!("tag sync error: {}, retry {}", err, attempt);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn wait(&mut self, id: &str) -> Result<()> {
+        let deadline = Instant::now() + self.options.timeout;
+        loop {
+            let recv = time::timeout_at(deadline, self.module.poll())
+                .await
+                .map_err(|_| Error::WaitResponseTimeout(id.to_string()))??;
+            let reply = match recv {
+                TagRecv::DeviceInfoUpdateResponse(reply) => reply,
+                TagRecv::DeviceInfoDeleteResponse(reply) => reply,
+            };
+            if reply.id != id {
+                continue;
+            }
+            if reply.code != 200 {
+                return Err(Error::CodeParams(reply.code, None));
+            }
+            return Ok(());
+        }
+    }
+
+    async fn save(&self) -> Result<()> {
+        if let Some(path) = &self.options.state_path {
+            write_atomic(path, &serde_json::to_vec(&self.confirmed)?).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,33 +1,42 @@
 //! 设备标签
 
 use crate::alink::aiot_module::AiotModule;
+use crate::alink::aiot_module::ChannelConnection;
 use crate::alink::aiot_module::ModuleRecvKind;
-use crate::mqtt::MqttConnection;
+use crate::alink::channel::Channel;
+use crate::alink::queue::QueueSender;
 use crate::{Error, Result, ThreeTuple};
 use enum_iterator::IntoEnumIterator;
 use log::*;
 use rumqttc::{AsyncClient, QoS};
 use serde::Serialize;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
 
 use self::recv::*;
 
+pub use self::mirror::{TagMirror, TagOptions};
+
 pub mod base;
+pub mod mirror;
 pub mod push;
 pub mod recv;
 
 pub type Recv = TagRecv;
 pub type RecvKind = TagRecvKind;
-pub type Module = AiotModule<Recv>;
+pub type Module<C = AsyncClient> = AiotModule<Recv, (), C>;
+
+impl<C: Channel> Module<C> {
+    pub async fn init(&self) -> Result<()> {
+        self.sub_all::<RecvKind>().await
+    }
+}
 
-impl MqttConnection {
-    pub fn tag(&mut self) -> Result<Module> {
-        let (tx, rx) = mpsc::channel(64);
+impl<C: Channel> ChannelConnection<C> {
+    pub fn tag(&mut self) -> Result<Module<C>> {
+        let (tx, rx) = self.queue(64);
         let executor = Executor {
             tx,
-            three: self.mqtt_client.three.clone(),
+            three: self.three.clone(),
         };
 
         self.module(Box::new(executor), rx, ())
@@ -36,13 +45,97 @@ impl MqttConnection {
 
 pub struct Executor {
     three: Arc<ThreeTuple>,
-    tx: Sender<Recv>,
+    tx: QueueSender<Recv>,
 }
 
 #[async_trait::async_trait]
 impl crate::Executor for Executor {
     async fn execute(&mut self, topic: &str, payload: &[u8]) -> crate::Result<()> {
         let data = crate::execute::<RecvKind>(&self.three, topic, payload)?;
-        self.tx.send(data).await.map_err(|_| Error::MpscSendError)
+        self.tx.send(data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::testing::{drive, three};
+    use crate::mock::MockCloud;
+    use crate::DeviceAuthInfo;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn 标签同步() {
+        let dir = tempdir::TempDir::new("tag").unwrap();
+        let options = TagOptions {
+            timeout: Duration::from_millis(100),
+            retries: 1,
+            state_path: Some(dir.path().join("tags.json")),
+        };
+        let mut cloud = MockCloud::new(&three());
+        let mut conn = cloud
+            .connect(&DeviceAuthInfo::from_tuple(&three()))
+            .unwrap();
+        let mut mirror = conn.tag_mirror(options.clone()).unwrap();
+        mirror.init().await.unwrap();
+        let published = |cloud: &MockCloud, method: &str| -> Vec<Value> {
+            cloud
+                .published()
+                .iter()
+                .filter(|(topic, _)| topic.ends_with(method))
+                .map(|(_, payload)| {
+                    serde_json::from_slice::<Value>(payload).unwrap()["params"].clone()
+                })
+                .collect()
+        };
+
+        // 没有收到响应时重试，之后返回超时
+        mirror.set("site", "sh");
+        assert!(matches!(
+            mirror.sync().await,
+            Err(Error::WaitResponseTimeout(_))
+        ));
+        assert_eq!(published(&cloud, "/deviceinfo/update").len(), 2);
+        assert!(mirror.confirmed().is_empty());
+        drive(&mut conn, mirror.sync()).await.unwrap();
+        assert_eq!(mirror.confirmed().get("site").unwrap(), "sh");
+
+        // 只上报变化的部分
+        mirror.set("build", "1.0.1");
+        mirror.set("hw", "v2");
+        drive(&mut conn, mirror.sync()).await.unwrap();
+        mirror.set("build", "1.0.2");
+        mirror.remove("hw");
+        drive(&mut conn, mirror.sync()).await.unwrap();
+        let updates = published(&cloud, "/deviceinfo/update");
+        assert_eq!(updates.len(), 5);
+        assert_eq!(
+            updates[4],
+            json!([{ "attrKey": "build", "attrValue": "1.0.2" }])
+        );
+        assert_eq!(
+            published(&cloud, "/deviceinfo/delete"),
+            vec![json!([{ "attrKey": "hw" }])]
+        );
+
+        // 重启后加载已确认的标签，没有变化时不上报
+        let mut mirror = conn.tag_mirror(options.clone()).unwrap();
+        mirror.init().await.unwrap();
+        assert_eq!(mirror.confirmed().len(), 2);
+        mirror.set("site", "sh");
+        drive(&mut conn, mirror.sync()).await.unwrap();
+        assert_eq!(published(&cloud, "/deviceinfo/update").len(), 5);
+
+        // 文件损坏时忽略，从空的已确认标签开始
+        let path = options.state_path.clone().unwrap();
+        std::fs::write(&path, b"{\"site\":").unwrap();
+        let mut mirror = conn.tag_mirror(options).unwrap();
+        mirror.init().await.unwrap();
+        assert!(mirror.confirmed().is_empty());
+        mirror.set("site", "sh");
+        drive(&mut conn, mirror.sync()).await.unwrap();
+        assert_eq!(published(&cloud, "/deviceinfo/update").len(), 6);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("site"));
     }
 }
@@ -1,4 +1,5 @@
 use super::base::*;
+use crate::alink::channel::Channel;
 use crate::alink::{global_id_next, AlinkRequest, AlinkResponse, SysAck, ALINK_VERSION};
 use crate::Error;
 use log::debug;
@@ -6,14 +7,16 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs;
 
-impl super::Module {
+impl<C: Channel> super::Module<C> {
     /// 标签信息上报
     ///
     /// # 参数
     ///
     /// * `infos`： 标签信息
     /// * `ack`：是否需要响应
-    pub async fn update(&self, infos: Vec<DeviceInfoKeyValue>, ack: bool) -> crate::Result<()> {
+    ///
+    /// 返回消息ID，用于匹配响应。
+    pub async fn update(&self, infos: Vec<DeviceInfoKeyValue>, ack: bool) -> crate::Result<String> {
         let payload = DeviceInfoUpdateRequest {
             id: global_id_next().to_string(),
             version: ALINK_VERSION.to_string(),
@@ -28,7 +31,8 @@ impl super::Module {
             ),
             &payload,
         )
-        .await
+        .await?;
+        Ok(payload.id)
     }
 
     /// 标签信息删除
@@ -37,7 +41,9 @@ impl super::Module {
     ///
     /// * `keys`：要删除的key数组
     /// * `ack`：是否需要响应
-    pub async fn delete(&self, keys: &[&str], ack: bool) -> crate::Result<()> {
+    ///
+    /// 返回消息ID，用于匹配响应。
+    pub async fn delete(&self, keys: &[&str], ack: bool) -> crate::Result<String> {
         let payload = DeviceInfoDeleteRequest {
             id: global_id_next().to_string(),
             version: ALINK_VERSION.to_string(),
@@ -57,7 +63,8 @@ impl super::Module {
             ),
             &payload,
         )
-        .await
+        .await?;
+        Ok(payload.id)
     }
 }
 
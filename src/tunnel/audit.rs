@@ -0,0 +1,128 @@
+//! 会话访问控制和审计记录。
+
+use super::protocol::ReleaseCode;
+use super::session::SessionInfo;
+use crate::logpost::base::LogItem;
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+/// 创建会话的请求
+#[derive(Debug, Clone)]
+pub struct SessionRequest {
+    pub tunnel_id: String,
+    pub session_id: String,
+    pub service_type: String,
+}
+
+/// 会话访问策略，通过 [`TunnelProxy::set_policy`](super::proxy::TunnelProxy::set_policy) 设置。
+///
+/// 闭包 `Fn(&SessionRequest) -> Result<(), String>` 也实现了这个 trait。
+#[async_trait::async_trait]
+pub trait SessionPolicy: Send + Sync + 'static {
+    /// 返回 `Err(原因)` 拒绝创建会话，原因会返回给访问端
+    async fn check(&self, request: &SessionRequest) -> Result<(), String>;
+}
+
+#[async_trait::async_trait]
+impl<F> SessionPolicy for F
+where
+    F: Fn(&SessionRequest) -> Result<(), String> + Send + Sync + 'static,
+{
+    async fn check(&self, request: &SessionRequest) -> Result<(), String> {
+        self(request)
+    }
+}
+
+/// 会话审计记录，会话被拒绝或关闭时通过 [`Event::Audit`](super::Event::Audit) 发出
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub tunnel_id: String,
+    pub session_id: String,
+    pub service_type: String,
+    /// 会话开始时间，毫秒时间戳
+    pub start_time: i64,
+    /// 会话结束时间，毫秒时间戳
+    pub end_time: i64,
+    /// 云端发往本地服务的字节数
+    pub bytes_in: u64,
+    /// 本地服务发往云端的字节数
+    pub bytes_out: u64,
+    /// 会话关闭原因，会话被拒绝时为 `None`
+    pub close_code: Option<ReleaseCode>,
+    /// 会话被拒绝的原因
+    pub refused: Option<String>,
+}
+
+impl AuditRecord {
+    /// 已关闭的会话，`now` 为关闭时的 Unix 毫秒时间
+    pub fn closed(tunnel_id: &str, info: SessionInfo, code: ReleaseCode, now: i64) -> Self {
+        Self {
+            tunnel_id: tunnel_id.to_string(),
+            session_id: info.id,
+            service_type: info.service_type,
+            start_time: info.created,
+            end_time: now,
+            bytes_in: info.bytes_in,
+            bytes_out: info.bytes_out,
+            close_code: Some(code),
+            refused: None,
+        }
+    }
+
+    /// 被拒绝的会话，`now` 为拒绝时的 Unix 毫秒时间
+    pub fn refused(request: SessionRequest, reason: String, now: i64) -> Self {
+        Self {
+            tunnel_id: request.tunnel_id,
+            session_id: request.session_id,
+            service_type: request.service_type,
+            start_time: now,
+            end_time: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            close_code: None,
+            refused: Some(reason),
+        }
+    }
+}
+
+/// 转换为日志，可以通过 `logpost::Module::post` 上报
+impl From<AuditRecord> for LogItem {
+    fn from(record: AuditRecord) -> Self {
+        let time = Utc
+            .timestamp_millis_opt(record.end_time)
+            .single()
+            .unwrap_or_default();
+        let (log_level, code) = match (&record.refused, record.close_code) {
+            (Some(_), _) => ("WARN", "refused".to_string()),
+            (None, Some(code)) => ("INFO", (code as u8).to_string()),
+            (None, None) => ("INFO", String::new()),
+        };
+        LogItem {
+            utc_time: time.format("%Y-%m-%dT%H:%M:%S%.3f%z").to_string(),
+            log_level: log_level.to_string(),
+            module: "secure_tunnel".to_string(),
+            code,
+            trace_context: Some(record.session_id.clone()),
+            log_content: serde_json::to_string(&record).unwrap_or_default(),
+        }
+    }
+}
+
+#[test]
+fn test_audit_log_item() {
+    let info = SessionInfo {
+        id: "s1".to_string(),
+        service_type: "_SSH".to_string(),
+        created: 0,
+        bytes_in: 10,
+        bytes_out: 20,
+    };
+    let record = AuditRecord::closed("t1", info, ReleaseCode::ClientClose, 1000);
+    assert_eq!(record.start_time, 0);
+    let item = LogItem::from(record);
+    assert_eq!(item.code, "0");
+    assert_eq!(item.trace_context.as_deref(), Some("s1"));
+    assert!(item.log_content.contains(r#""bytesOut":20"#));
+    assert_eq!(item.utc_time, "1970-01-01T00:00:01.000+0000");
+}
@@ -1,13 +1,22 @@
+pub mod audit;
 pub mod protocol;
 pub mod proxy;
 pub mod session;
+pub mod source;
 
-/// tunnel内部事件类型
+/// tunnel内部事件类型，均携带隧道ID
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
     /// 当tunnel实例连接代理通道成功
-    Connect,
+    Connect(String),
     /// 当tunnel实例从代理通道断开
-    Disconnect,
-    /// 隧道认证信息已经过期，需要重新连接
-    Expired,
+    Disconnect(String),
+    /// 隧道认证信息即将过期，需要通过 `ra::Module::proxy_request` 请求新的认证信息
+    Expired(String),
+    /// 连接失败或断开后，正在等待第 n 次重连
+    Reconnecting(String, u32),
+    /// 隧道被删除或重连次数用尽，不再重连
+    Closed(String),
+    /// 会话被拒绝或关闭
+    Audit(audit::AuditRecord),
 }
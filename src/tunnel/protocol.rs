@@ -2,6 +2,52 @@ use crate::{util::rand_u64, Error, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::fmt;
+use std::future::Future;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_tungstenite::{client_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+
+/// WebSocket 子协议
+pub const SUB_PROTOCOL: &str = "aliyun.iot.securetunnel-v1.1";
+
+pub(crate) type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// 使用 token 连接隧道的 WebSocket 地址，`wss` 地址使用 `client_config` 进行 TLS 握手
+pub(crate) async fn connect_ws(
+    uri: &str,
+    token: &str,
+    client_config: &Arc<rustls::ClientConfig>,
+) -> Result<WsStream> {
+    let url = url::Url::parse(uri)?;
+    let addrs = url.socket_addrs(|| None)?;
+    let socket = TcpStream::connect(&*addrs).await?;
+    let connector = match url.scheme() {
+        "wss" => Some(Connector::Rustls(client_config.clone())),
+        _ => None,
+    };
+    let request = http::request::Request::builder()
+        .uri(uri)
+        .header("tunnel-access-token", token)
+        .header("Sec-WebSocket-Protocol", SUB_PROTOCOL)
+        .header(
+            "Sec-WebSocket-Key",
+            tungstenite::handshake::client::generate_key(),
+        )
+        .header("Host", url.host_str().unwrap_or_default())
+        .header("Sec-WebSocket-Version", "13")
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .body(())
+        .map_err(|err| Error::HttpRequestBuild)?;
+    let (ws_stream, _) = client_async_tls_with_config(request, socket, None, connector).await?;
+    Ok(ws_stream)
+}
 
 /// 本地服务信息
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -12,11 +58,107 @@ pub struct Service {
     pub ip: String,
     /// 服务端口号
     pub port: u16,
+    /// 单个隧道内该服务的最大并发会话数，`None` 表示只受隧道总数限制
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// 非 TCP 的本地服务，设置后忽略 `ip` 和 `port`
+    #[serde(skip)]
+    pub endpoint: Option<Endpoint>,
+}
+
+/// 会话对接的本地数据流
+pub trait LocalStream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> LocalStream for T {}
+
+/// 进程内的服务，每个新会话调用一次 [`ServiceHandler::open`]。
+///
+/// 闭包 `Fn(DuplexStream) -> Future` 也实现了这个 trait，闭包在新的任务中运行，
+/// 参数为与会话对接的一端。
+#[async_trait::async_trait]
+pub trait ServiceHandler: Send + Sync + 'static {
+    async fn open(&self, session_id: &str) -> Result<Box<dyn LocalStream>>;
+}
+
+#[async_trait::async_trait]
+impl<F, Fut> ServiceHandler for F
+where
+    F: Fn(DuplexStream) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    async fn open(&self, _session_id: &str) -> Result<Box<dyn LocalStream>> {
+        let (local, remote) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        tokio::spawn(self(remote));
+        Ok(Box::new(local))
+    }
+}
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 非 TCP 的本地服务
+#[derive(Clone)]
+pub enum Endpoint {
+    /// Unix 域套接字路径
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// 进程内的服务
+    Handler(Arc<dyn ServiceHandler>),
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(path) => f.debug_tuple("Unix").field(path).finish(),
+            Self::Handler(_) => f.write_str("Handler"),
+        }
+    }
 }
 
 impl Service {
     pub fn new(r#type: String, ip: String, port: u16) -> Self {
-        Self { r#type, ip, port }
+        Self {
+            r#type,
+            ip,
+            port,
+            max_sessions: None,
+            endpoint: None,
+        }
+    }
+
+    /// Unix 域套接字服务
+    #[cfg(unix)]
+    pub fn unix(r#type: String, path: impl Into<PathBuf>) -> Self {
+        let mut service = Self::new(r#type, String::new(), 0);
+        service.endpoint = Some(Endpoint::Unix(path.into()));
+        service
+    }
+
+    /// 进程内的服务
+    pub fn handler(r#type: String, handler: impl ServiceHandler) -> Self {
+        let mut service = Self::new(r#type, String::new(), 0);
+        service.endpoint = Some(Endpoint::Handler(Arc::new(handler)));
+        service
+    }
+
+    /// 为会话连接本地服务
+    pub async fn connect(&self, session_id: &str) -> Result<Box<dyn LocalStream>> {
+        match &self.endpoint {
+            #[cfg(unix)]
+            Some(Endpoint::Unix(path)) => {
+                log::info!("unix://{} session_id: {}", path.display(), session_id);
+                Ok(Box::new(UnixStream::connect(path).await?))
+            }
+            Some(Endpoint::Handler(handler)) => {
+                log::info!("handler://{} session_id: {}", self.r#type, session_id);
+                handler.open(session_id).await
+            }
+            None => {
+                let addr = format!("{}:{}", self.ip, self.port);
+                log::info!("tcp://{} session_id: {}", addr, session_id);
+                Ok(Box::new(TcpStream::connect(&addr).await?))
+            }
+        }
     }
 }
 
@@ -1,30 +1,112 @@
+use super::audit::{AuditRecord, SessionPolicy, SessionRequest};
 use super::protocol::{Header, Service};
-use super::session::{Session, SessionList};
-use crate::tunnel::protocol::{Frame, FrameType, ReleaseCode, ResponseBody, ResponseCode};
+use super::session::{
+    Backlog, Outgoing, SessionInfo, SessionList, DEFAULT_BACKLOG_TIMEOUT,
+    DEFAULT_HALF_CLOSE_TIMEOUT, DEFAULT_MAX_FRAME_SIZE,
+};
+use super::Event;
+use crate::tunnel::protocol::{
+    connect_ws, Frame, FrameType, ReleaseCode, ResponseBody, ResponseCode, WsStream,
+};
 use crate::util::auth::aliyun_client_config;
+use crate::util::clock::{default_clock, Clock};
 use crate::util::inc_u64;
 use crate::{Error, Result};
 use futures::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tokio::time;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use tokio_tungstenite::Connector;
 use tokio_tungstenite::{client_async_tls_with_config, MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
 
+#[derive(Debug, Clone)]
 pub struct TunnelParams {
     pub id: String,
     pub host: String,
     pub port: String,
     pub path: String,
     pub token: String,
+    /// token 剩余的有效时间，单位为秒
+    pub token_expire: Option<u64>,
+    /// 连接协议，默认为 `wss`
+    pub schema: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TunnelOptions {
+    /// 断线重连的初始等待时间，之后每次失败翻倍
+    pub reconnect_backoff: Duration,
+    /// 断线重连的最大等待时间
+    pub max_backoff: Duration,
+    /// 连续重连失败的最大次数，`None` 表示一直重试
+    pub max_retries: Option<u32>,
+    /// 在 token 过期前多久发出 [`Event::Expired`]
+    pub refresh_ahead: Duration,
+    /// 向云端发送 WebSocket Ping 的间隔
+    pub ping_interval: Duration,
+    /// 超过这个时间没有收到云端的任何数据（包括 Pong）则认为连接已断开并重连
+    pub keepalive_timeout: Duration,
+    /// 会话在这个时间内没有收发数据则关闭本地连接并通知云端，`None` 表示不限制。
+    /// 每个 Ping 间隔检查一次。
+    pub session_idle_timeout: Option<Duration>,
+    /// 单个隧道的最大并发会话数，与云端的限制一致
+    pub max_sessions: usize,
+    /// 单个隧道创建会话的频率限制，`None` 表示不限制
+    pub session_rate_limit: Option<RateLimit>,
+    /// 本地服务发往云端的单帧最大数据长度
+    pub max_frame_size: usize,
+    /// 会话的写缓冲已满时暂停读取云端，本地服务超过这个时间仍不读取则关闭该会话
+    pub backlog_timeout: Duration,
+    /// 本地服务关闭写端后仍然转发云端的数据，云端超过这个时间没有数据时关闭该会话
+    pub half_close_timeout: Duration,
+    /// token 有效期和审计记录使用的时钟
+    pub clock: Arc<dyn Clock>,
+}
+
+/// 在 `period` 时间内最多允许 `count` 次
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub count: usize,
+    pub period: Duration,
+}
+
+impl Default for TunnelOptions {
+    fn default() -> Self {
+        Self {
+            reconnect_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_retries: None,
+            refresh_ahead: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(30),
+            keepalive_timeout: Duration::from_secs(90),
+            session_idle_timeout: None,
+            max_sessions: 10,
+            session_rate_limit: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            backlog_timeout: DEFAULT_BACKLOG_TIMEOUT,
+            half_close_timeout: DEFAULT_HALF_CLOSE_TIMEOUT,
+            clock: default_clock(),
+        }
+    }
+}
+
+impl TunnelOptions {
+    /// 第 `attempt` 次重连前的等待时间
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.reconnect_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
 }
 
 pub enum TunnelAction {
@@ -33,27 +115,59 @@ pub enum TunnelAction {
     DeleteTunnel(String),
     AddService(Service),
     DeleteService(String),
+    Tunnels(oneshot::Sender<Vec<TunnelInfo>>),
+    SetPolicy(Option<Arc<dyn SessionPolicy>>),
 }
 
 enum ProxyAction {
     UpdateTunnel(TunnelParams),
     DeleteTunnel(String),
+    Query(oneshot::Sender<TunnelInfo>),
+}
+
+/// 隧道信息
+#[derive(Debug, Clone)]
+pub struct TunnelInfo {
+    pub id: String,
+    /// 是否已经连接到云端
+    pub connected: bool,
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// 一次连接结束的原因
+enum Exit {
+    Disconnected,
+    Update(TunnelParams),
+    Delete,
 }
 
 #[derive(Debug, Clone)]
 pub struct TunnelProxy {
     tx: Sender<TunnelAction>,
+    events: broadcast::Sender<Event>,
 }
 
 impl TunnelProxy {
     pub fn new() -> Self {
+        Self::with_options(TunnelOptions::default())
+    }
+
+    pub fn with_options(options: TunnelOptions) -> Self {
         let (tx, rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let events_tx = events.clone();
         tokio::spawn(async move {
-            RemoteAccessProxy::start(rx).await;
+            RemoteAccessProxy::start(rx, options, events_tx).await;
         });
-        Self { tx }
+        Self { tx, events }
     }
 
+    /// 订阅隧道状态变化
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// 添加隧道，隧道已经存在时使用新的参数重连
     pub async fn add_tunnel(&self, params: TunnelParams) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         self.tx
@@ -74,6 +188,7 @@ impl TunnelProxy {
         Ok(())
     }
 
+    /// 使用新的参数重连隧道，隧道不存在时添加
     pub async fn update_tunnel(&self, params: TunnelParams) -> Result<()> {
         self.tx
             .send(TunnelAction::UpdateTunnel(params))
@@ -97,211 +212,568 @@ impl TunnelProxy {
             .map_err(|err| Error::MpscSendError)?;
         Ok(())
     }
+
+    /// 设置会话访问策略，对所有隧道生效
+    pub async fn set_policy(&self, policy: impl SessionPolicy) -> Result<()> {
+        self.tx
+            .send(TunnelAction::SetPolicy(Some(Arc::new(policy))))
+            .await
+            .map_err(|err| Error::MpscSendError)?;
+        Ok(())
+    }
+
+    /// 清除会话访问策略
+    pub async fn clear_policy(&self) -> Result<()> {
+        self.tx
+            .send(TunnelAction::SetPolicy(None))
+            .await
+            .map_err(|err| Error::MpscSendError)?;
+        Ok(())
+    }
+
+    /// 查询当前的隧道和会话
+    pub async fn tunnels(&self) -> Result<Vec<TunnelInfo>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(TunnelAction::Tunnels(tx))
+            .await
+            .map_err(|err| Error::MpscSendError)?;
+        rx.await.map_err(|err| Error::OneshotRecvError)
+    }
+}
+
+struct Link {
+    read: SplitStream<WsStream>,
+    write: SplitSink<WsStream, Message>,
+    ping: time::Interval,
+    last_recv: Instant,
 }
 
 struct RemoteAccessProxy {
     params: TunnelParams,
-    read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    options: TunnelOptions,
+    client_config: Arc<rustls::ClientConfig>,
     action_rx: Receiver<ProxyAction>,
-    cloud_rx: Receiver<Vec<u8>>,
+    events: broadcast::Sender<Event>,
     local_tx: Sender<Frame>,
     local_rx: Receiver<Frame>,
     one_tx: Option<oneshot::Sender<String>>, // 上送 sessionId
-    exit_flag: bool,
-    update_flag: bool,
+    /// token 过期提醒的时钟单调时间
+    expire_at: Option<Duration>,
+    connected: bool,
+    shared: Arc<Shared>,
+    session_starts: VecDeque<Instant>,
     session_list: SessionList,
+    /// 会话写缓冲已满时暂存的云端数据，暂存期间不再读取云端
+    backlog: Option<Backlog>,
 }
 
-lazy_static! {
-    static ref SERVICE_LIST: Arc<RwLock<HashMap<String, Service>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+/// 同一个 [`TunnelProxy`] 下的隧道共享的服务列表和访问策略
+#[derive(Default)]
+struct Shared {
+    services: RwLock<HashMap<String, Service>>,
+    policy: RwLock<Option<Arc<dyn SessionPolicy>>>,
 }
 
+/// 查询隧道信息的超时时间，正在建立连接的隧道来不及应答
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// 所有会话发往云端的缓冲帧数，缓冲满时会话暂停读取本地服务
+const LOCAL_QUEUE: usize = 64;
+/// 一次 flush 最多合并的帧数
+const SEND_BATCH: usize = 32;
+
 impl RemoteAccessProxy {
-    pub async fn start(mut rx: Receiver<TunnelAction>) {
+    pub async fn start(
+        mut rx: Receiver<TunnelAction>,
+        options: TunnelOptions,
+        events: broadcast::Sender<Event>,
+    ) {
         let client_config = Arc::new(aliyun_client_config().unwrap());
         let mut proxytxs: HashMap<String, Sender<ProxyAction>> = HashMap::new();
-        loop {
-            if let Some(action) = rx.recv().await {
-                match action {
-                    TunnelAction::AddService(service) => {
-                        SERVICE_LIST
-                            .write()
-                            .await
-                            .insert(service.r#type.clone(), service);
-                    }
-                    TunnelAction::DeleteService(id) => {
-                        SERVICE_LIST.write().await.remove(&id);
-                    }
-                    TunnelAction::UpdateTunnel(params) => {
-                        if let Some(tx) = proxytxs.get(&params.id) {
-                            tx.send(ProxyAction::UpdateTunnel(params)).await.ok();
+        let shared = Arc::new(Shared::default());
+        while let Some(action) = rx.recv().await {
+            match action {
+                TunnelAction::AddService(service) => {
+                    shared
+                        .services
+                        .write()
+                        .await
+                        .insert(service.r#type.clone(), service);
+                }
+                TunnelAction::DeleteService(id) => {
+                    shared.services.write().await.remove(&id);
+                }
+                TunnelAction::SetPolicy(policy) => {
+                    *shared.policy.write().await = policy;
+                }
+                TunnelAction::Tunnels(reply) => {
+                    proxytxs.retain(|_, tx| !tx.is_closed());
+                    let txs: Vec<_> = proxytxs
+                        .iter()
+                        .map(|(id, tx)| (id.clone(), tx.clone()))
+                        .collect();
+                    tokio::spawn(async move {
+                        let mut infos = Vec::new();
+                        for (id, tx) in txs {
+                            let (one_tx, one_rx) = oneshot::channel();
+                            tx.send(ProxyAction::Query(one_tx)).await.ok();
+                            let info = match time::timeout(QUERY_TIMEOUT, one_rx).await {
+                                Ok(Ok(info)) => info,
+                                _ => TunnelInfo {
+                                    id,
+                                    connected: false,
+                                    sessions: Vec::new(),
+                                },
+                            };
+                            infos.push(info);
                         }
+                        reply.send(infos).ok();
+                    });
+                }
+                TunnelAction::AddTunnel(params, one_tx) => {
+                    let tx = proxytxs.get(&params.id).filter(|tx| !tx.is_closed());
+                    if let Some(tx) = tx {
+                        tx.send(ProxyAction::UpdateTunnel(params)).await.ok();
+                    } else {
+                        let id = params.id.clone();
+                        let tx =
+                            Self::spawn(params, one_tx, &options, &client_config, &events, &shared);
+                        proxytxs.insert(id, tx);
                     }
-                    TunnelAction::DeleteTunnel(id) => {
-                        if let Some(tx) = proxytxs.remove(&id) {
-                            tx.send(ProxyAction::DeleteTunnel(id)).await.ok();
-                        }
+                }
+                TunnelAction::UpdateTunnel(params) => {
+                    let tx = proxytxs.get(&params.id).filter(|tx| !tx.is_closed());
+                    if let Some(tx) = tx {
+                        tx.send(ProxyAction::UpdateTunnel(params)).await.ok();
+                    } else {
+                        let id = params.id.clone();
+                        let (one_tx, _) = oneshot::channel();
+                        let tx =
+                            Self::spawn(params, one_tx, &options, &client_config, &events, &shared);
+                        proxytxs.insert(id, tx);
                     }
-                    TunnelAction::AddTunnel(params, one_tx) => {
-                        let (tx, rx) = mpsc::channel(16);
-                        proxytxs.insert(params.id.clone(), tx);
-                        if let Err(err) = Self::new(params, rx, one_tx, client_config.clone()).await
-                        {
-                            log::error!("add tunnel: {}", err);
-                        }
+                }
+                TunnelAction::DeleteTunnel(id) => {
+                    if let Some(tx) = proxytxs.remove(&id) {
+                        tx.send(ProxyAction::DeleteTunnel(id)).await.ok();
                     }
                 }
             }
         }
     }
 
-    async fn new(
+    fn spawn(
         params: TunnelParams,
-        action_rx: Receiver<ProxyAction>,
         one_tx: oneshot::Sender<String>,
-        client_config: Arc<rustls::ClientConfig>,
-    ) -> Result<()> {
-        let uri = format!("wss://{}:{}{}", params.host, params.port, params.path);
-        let url = url::Url::parse(&uri)?;
-        let addrs = url.socket_addrs(|| None)?;
-        let socket = TcpStream::connect(&*addrs).await?;
-        let connecter = Connector::Rustls(client_config);
-        let mut request = http::request::Request::builder()
-            .uri(uri)
-            .header("tunnel-access-token", &params.token)
-            .header("Sec-WebSocket-Protocol", "aliyun.iot.securetunnel-v1.1")
-            .header(
-                "Sec-WebSocket-Key",
-                tungstenite::handshake::client::generate_key(),
-            )
-            .header("Host", &params.host)
-            .header("Sec-WebSocket-Version", "13")
-            .header("Connection", "Upgrade")
-            .header("Upgrade", "websocket")
-            .body(())
-            .map_err(|err| Error::HttpRequestBuild)?;
-
-        let (ws_stream, _) =
-            client_async_tls_with_config(request, socket, None, Some(connecter)).await?;
-        let (mut write, mut read) = ws_stream.split();
-
-        let (cloud_tx, cloud_rx) = mpsc::channel(16);
-        let (local_tx, local_rx) = mpsc::channel(16);
+        options: &TunnelOptions,
+        client_config: &Arc<rustls::ClientConfig>,
+        events: &broadcast::Sender<Event>,
+        shared: &Arc<Shared>,
+    ) -> Sender<ProxyAction> {
+        let (tx, action_rx) = mpsc::channel(16);
+        let (local_tx, local_rx) = mpsc::channel(LOCAL_QUEUE);
+        let mut session_list = SessionList::new();
+        session_list.set_max_frame_size(options.max_frame_size);
+        session_list.set_backlog_timeout(options.backlog_timeout);
+        session_list.set_half_close_timeout(options.half_close_timeout);
+        session_list.set_clock(options.clock.clone());
         let mut proxy = RemoteAccessProxy {
-            params,
-            read,
-            write,
+            params: params.clone(),
+            options: options.clone(),
+            client_config: client_config.clone(),
             action_rx,
-            exit_flag: false,
-            update_flag: false,
-            cloud_rx,
+            events: events.clone(),
             local_tx,
             local_rx,
             one_tx: Some(one_tx),
-            session_list: SessionList::new(),
+            expire_at: None,
+            connected: false,
+            shared: shared.clone(),
+            session_starts: VecDeque::new(),
+            session_list,
+            backlog: None,
         };
-        tokio::spawn(async move {
-            loop {
-                if let Err(err) = proxy.poll().await {
-                    log::warn!("proxy {} error: {err}", proxy.params.id);
+        proxy.set_params(params);
+        tokio::spawn(proxy.run());
+        tx
+    }
+
+    /// 连接并处理隧道数据，断线后按指数退避重连，直到隧道被删除或重连次数用尽
+    async fn run(mut self) {
+        let mut attempt = 0u32;
+        loop {
+            let exit = match self.connect().await {
+                Ok(mut link) => {
+                    attempt = 0;
+                    log::info!("proxy {} connected", self.params.id);
+                    self.emit(Event::Connect(self.params.id.clone()));
+                    self.connected = true;
+                    let exit = self.serve(&mut link).await;
+                    self.connected = false;
+                    self.backlog = None;
+                    link.write.close().await.ok();
+                    for info in self.session_list.drain() {
+                        self.audit(info, ReleaseCode::CloudDeviceDisconnect);
+                    }
+                    self.emit(Event::Disconnect(self.params.id.clone()));
+                    exit
                 }
-                if proxy.exit_flag {
-                    log::info!("proxy {} exit", proxy.params.id);
-                    break;
+                Err(err) => {
+                    log::warn!("proxy {} connect error: {err}", self.params.id);
+                    Exit::Disconnected
+                }
+            };
+            match exit {
+                Exit::Delete => break,
+                Exit::Update(params) => {
+                    attempt = 0;
+                    self.set_params(params);
+                }
+                Exit::Disconnected => {
+                    attempt += 1;
+                    if let Some(max) = self.options.max_retries {
+                        if attempt > max {
+                            log::error!("proxy {} reconnect failed {} times", self.params.id, max);
+                            break;
+                        }
+                    }
+                    let delay = self.options.backoff(attempt);
+                    self.emit(Event::Reconnecting(self.params.id.clone(), attempt));
+                    match self.wait(delay).await {
+                        Some(Exit::Update(params)) => {
+                            attempt = 0;
+                            self.set_params(params);
+                        }
+                        Some(_) => break,
+                        None => {}
+                    }
                 }
             }
-            if proxy.update_flag {
-                // TODO: 自循环引用
-                // if let Err(err) = Self::new(proxy.params, proxy.action_rx, one_tx, client_config).await {
-                //     log::error!("update tunnel: {}", err);
-                // }
+        }
+        log::info!("proxy {} exit", self.params.id);
+        self.emit(Event::Closed(self.params.id.clone()));
+    }
+
+    async fn connect(&self) -> Result<Link> {
+        let params = &self.params;
+        let schema = params.schema.as_deref().unwrap_or("wss");
+        let uri = format!(
+            "{}://{}:{}{}",
+            schema, params.host, params.port, params.path
+        );
+        let ws_stream = connect_ws(&uri, &params.token, &self.client_config).await?;
+        let (write, read) = ws_stream.split();
+        let interval = self.options.ping_interval;
+        let mut ping = time::interval_at(Instant::now() + interval, interval);
+        ping.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        Ok(Link {
+            read,
+            write,
+            ping,
+            last_recv: Instant::now(),
+        })
+    }
+
+    /// 等待重连期间处理隧道操作，等待结束时返回 `None`
+    async fn wait(&mut self, delay: Duration) -> Option<Exit> {
+        let deadline = Instant::now() + delay;
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => return None,
+                action = self.action_rx.recv() => match action {
+                    Some(ProxyAction::UpdateTunnel(params)) => return Some(Exit::Update(params)),
+                    Some(ProxyAction::Query(tx)) => {
+                        tx.send(self.info()).ok();
+                    }
+                    Some(ProxyAction::DeleteTunnel(_)) | None => return Some(Exit::Delete),
+                }
             }
+        }
+    }
+
+    async fn serve(&mut self, link: &mut Link) -> Exit {
+        loop {
+            match self.poll(link).await {
+                Ok(Some(exit)) => return exit,
+                Ok(None) => {}
+                Err(Error::KeepaliveTimeout) => {
+                    log::warn!("proxy {} keepalive timeout", self.params.id);
+                    return Exit::Disconnected;
+                }
+                Err(err) => log::warn!("proxy {} error: {err}", self.params.id),
+            }
+        }
+    }
+
+    fn set_params(&mut self, params: TunnelParams) {
+        self.expire_at = params.token_expire.map(|secs| {
+            self.options.clock.monotonic()
+                + Duration::from_secs(secs).saturating_sub(self.options.refresh_ahead)
         });
-        Ok(())
+        self.params = params;
     }
 
-    async fn new_session(&mut self, header: Header) -> Result<()> {
-        let id = header.session_id.unwrap_or("".to_string());
+    fn info(&self) -> TunnelInfo {
+        TunnelInfo {
+            id: self.params.id.clone(),
+            connected: self.connected,
+            sessions: self.session_list.infos(),
+        }
+    }
+
+    fn audit(&self, info: SessionInfo, code: ReleaseCode) {
+        let record =
+            AuditRecord::closed(&self.params.id, info, code, self.options.clock.now_millis());
+        self.emit(Event::Audit(record));
+    }
+
+    fn emit(&self, event: Event) {
+        // 没有订阅者时忽略
+        self.events.send(event).ok();
+    }
+
+    async fn new_session(&mut self, request: &SessionRequest) -> Result<()> {
         if let Some(tx) = self.one_tx.take() {
-            tx.send(id.clone()).ok();
+            tx.send(request.session_id.clone()).ok();
+        }
+        let service_type = &request.service_type;
+        let service = self
+            .shared
+            .services
+            .read()
+            .await
+            .get(service_type)
+            .cloned()
+            .ok_or_else(|| Error::SessionCreate(format!("找不到 service: {service_type}")))?;
+        let now = Instant::now();
+        if let Some(limit) = self.options.session_rate_limit {
+            while let Some(start) = self.session_starts.front() {
+                if now.duration_since(*start) < limit.period {
+                    break;
+                }
+                self.session_starts.pop_front();
+            }
+            if self.session_starts.len() >= limit.count {
+                return Err(Error::SessionCreate("创建会话过于频繁".to_string()));
+            }
         }
-        let service_type = header.service_type.unwrap_or("".to_string());
-        if let Some(service) = SERVICE_LIST.read().await.get(&service_type) {
-            if let Err(err) = self
-                .session_list
-                .add(id, service, self.local_tx.clone())
-                .await
-            {
-                return Err(err);
+        let policy = self.shared.policy.read().await.clone();
+        if let Some(policy) = policy {
+            policy.check(request).await.map_err(Error::SessionCreate)?;
+        }
+        if self.session_list.len() >= self.options.max_sessions {
+            return Err(Error::SessionLimit(format!(
+                "隧道会话数已达上限 {}",
+                self.options.max_sessions
+            )));
+        }
+        if let Some(max) = service.max_sessions {
+            if self.session_list.count(service_type) >= max {
+                return Err(Error::SessionLimit(format!(
+                    "service {service_type} 会话数已达上限 {max}"
+                )));
             }
-            return Ok(());
         }
-        Err(Error::SessionCreate(format!(
-            "找不到 service: {service_type}"
-        )))
+        self.session_list
+            .add(request.session_id.clone(), &service, self.local_tx.clone())
+            .await?;
+        // 只统计建立成功的会话，被拒绝的请求不占用限流额度
+        if self.options.session_rate_limit.is_some() {
+            self.session_starts.push_back(now);
+        }
+        Ok(())
     }
 
-    pub async fn poll(&mut self) -> Result<()> {
+    async fn poll(&mut self, link: &mut Link) -> Result<Option<Exit>> {
+        let expire_in = self
+            .expire_at
+            .map(|at| at.saturating_sub(self.options.clock.monotonic()));
+        let expired = async move {
+            match expire_in {
+                Some(delay) => time::sleep(delay).await,
+                None => futures::future::pending().await,
+            }
+        };
+        let ready = self.backlog.as_ref().map(Backlog::ready);
+        let ready = async move {
+            match ready {
+                Some(ready) => ready.await,
+                None => futures::future::pending().await,
+            }
+        };
         tokio::select! {
-            Some(Ok(data)) = self.read.next() => {
-                let data = Frame::from_slice(&data.into_data())?;
-                // log::info!("云端下发 {:?}", data);
-                match data.header.frame_type {
-                    FrameType::Response => {},
-                    FrameType::NewSession => {
-                        let data = match self.new_session(data.header.clone()).await {
-                            Ok(()) => {
-                                Frame::response(data.session_id(), data.frame_id(), data.service_type(), ResponseCode::Success, "new session response".to_string())
-                            },
-                            Err(err) => {
-                                Frame::response(data.session_id(), data.frame_id(), data.service_type(), ResponseCode::DeviceRefused, format!("{err}"))
-                            }
-                        };
-                        self.write.send(data.to_vec()?.into()).await.ok();
-                    },
-                    FrameType::ReleaseSession => if let Some(id) = data.header.session_id {
-                        let code = if let Ok(body) = serde_json::from_slice::<ResponseBody<ReleaseCode>>(&data.body) {
-                            body.code
-                        } else {
-                            ReleaseCode::DeviceClose
-                        };
-                        self.session_list.release(id, code, "".to_string()).await.ok();
-                    },
-                    FrameType::RawData => if let Some(id) = data.header.session_id {
-                        self.session_list.write(id, data.body).await.ok();
-                    },
+            msg = link.read.next(), if self.backlog.is_none() => {
+                link.last_recv = Instant::now();
+                match msg {
+                    Some(Ok(msg)) if msg.is_binary() || msg.is_text() => {
+                        let data = Frame::from_slice(&msg.into_data())?;
+                        self.on_frame(link, data).await?;
+                        Ok(None)
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        log::info!("proxy {} closed by cloud: {:?}", self.params.id, frame);
+                        Ok(Some(Exit::Disconnected))
+                    }
+                    Some(Ok(_)) => Ok(None),
+                    Some(Err(err)) => {
+                        log::warn!("proxy {} read error: {err}", self.params.id);
+                        Ok(Some(Exit::Disconnected))
+                    }
+                    None => Ok(Some(Exit::Disconnected)),
                 }
-                Ok(())
+            },
+            permit = ready => {
+                // 暂停读取期间收不到 Pong，不计入保活时间
+                link.last_recv = Instant::now();
+                if let Some(backlog) = self.backlog.take() {
+                    if let Some((info, frame)) = self.session_list.resume(backlog, permit) {
+                        self.audit(info, ReleaseCode::DeviceClose);
+                        link.write.send(frame.to_vec()?.into()).await?;
+                    }
+                }
+                Ok(None)
             },
             Some(frame) = self.local_rx.recv() => {
-                if let Err(err) = self.write.send(frame.to_vec()?.into()).await {
+                if let Err(err) = self.forward(link, frame).await {
                     log::error!("send local data error: {}", err);
+                    return Ok(Some(Exit::Disconnected));
+                }
+                Ok(None)
+            },
+            action = self.action_rx.recv() => match action {
+                Some(ProxyAction::UpdateTunnel(params)) => Ok(Some(Exit::Update(params))),
+                Some(ProxyAction::Query(tx)) => {
+                    tx.send(self.info()).ok();
+                    Ok(None)
                 }
-                Ok(())
+                Some(ProxyAction::DeleteTunnel(_)) | None => Ok(Some(Exit::Delete)),
             },
-            Some(data) = self.action_rx.recv() => {
-                match data {
-                    ProxyAction::UpdateTunnel(params) => {
-                        self.params = params;
-                        self.update_flag = true;
-                        self.exit_flag = true;
+            _ = expired => {
+                self.expire_at = None;
+                self.emit(Event::Expired(self.params.id.clone()));
+                Ok(None)
+            }
+            _ = link.ping.tick() => {
+                if self.backlog.is_none() && link.last_recv.elapsed() > self.options.keepalive_timeout {
+                    return Err(Error::KeepaliveTimeout);
+                }
+                link.write.send(Message::Ping(Vec::new())).await?;
+                if let Some(timeout) = self.options.session_idle_timeout {
+                    for id in self.session_list.idle(timeout) {
+                        log::info!("session {} idle timeout", id);
+                        let msg = "idle timeout".to_string();
+                        if let Some((info, frame)) =
+                            self.session_list.release(&id, ReleaseCode::DeviceClose, msg)
+                        {
+                            self.audit(info, ReleaseCode::DeviceClose);
+                            link.write.send(frame.to_vec()?.into()).await?;
+                        }
                     }
-                    ProxyAction::DeleteTunnel(id) => {
-                        self.update_flag = false;
-                        self.exit_flag = true;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// 把本地会话的帧发往云端，`local_rx` 中已经就绪的帧合并为一次 flush
+    async fn forward(&mut self, link: &mut Link, frame: Frame) -> Result<()> {
+        let mut next = Some(frame);
+        let mut count = 0;
+        while let Some(frame) = next.take() {
+            match self.session_list.outgoing(&frame) {
+                Outgoing::Send => link.write.feed(frame.to_vec()?.into()).await?,
+                Outgoing::Closed(info) => {
+                    self.audit(info, ReleaseCode::DeviceClose);
+                    link.write.feed(frame.to_vec()?.into()).await?;
+                }
+                Outgoing::Drop => {}
+            }
+            count += 1;
+            if count < SEND_BATCH {
+                next = self.local_rx.try_recv().ok();
+            }
+        }
+        link.write.flush().await?;
+        Ok(())
+    }
+
+    async fn on_frame(&mut self, link: &mut Link, data: Frame) -> Result<()> {
+        // log::info!("云端下发 {:?}", data);
+        match data.header.frame_type {
+            FrameType::Response => {}
+            FrameType::NewSession => {
+                let request = SessionRequest {
+                    tunnel_id: self.params.id.clone(),
+                    session_id: data.session_id(),
+                    service_type: data.service_type(),
+                };
+                let data = match self.new_session(&request).await {
+                    Ok(()) => Frame::response(
+                        data.session_id(),
+                        data.frame_id(),
+                        data.service_type(),
+                        ResponseCode::Success,
+                        "new session response".to_string(),
+                    ),
+                    Err(err) => {
+                        let code = match err {
+                            Error::SessionLimit(_) => ResponseCode::SessionLimit,
+                            _ => ResponseCode::DeviceRefused,
+                        };
+                        log::info!("session {} refused: {err}", request.session_id);
+                        self.emit(Event::Audit(AuditRecord::refused(
+                            request,
+                            err.to_string(),
+                            self.options.clock.now_millis(),
+                        )));
+                        Frame::response(
+                            data.session_id(),
+                            data.frame_id(),
+                            data.service_type(),
+                            code,
+                            format!("{err}"),
+                        )
+                    }
+                };
+                link.write.send(data.to_vec()?.into()).await?;
+            }
+            FrameType::ReleaseSession => {
+                if let Some(id) = data.header.session_id {
+                    let code = if let Ok(body) =
+                        serde_json::from_slice::<ResponseBody<ReleaseCode>>(&data.body)
+                    {
+                        body.code
+                    } else {
+                        ReleaseCode::DeviceClose
+                    };
+                    // 云端关闭会话，只需关闭本地连接
+                    if let Some(info) = self.session_list.remove(&id) {
+                        self.audit(info, code);
                     }
                 }
-                Ok(())
-            },
-            Some(data) = self.cloud_rx.recv() => {
-                self.write.send(data.into()).await?;
-                Ok(())
             }
-            else => {
-                Ok(())
+            FrameType::RawData => {
+                if let Some(id) = data.header.session_id {
+                    match self.session_list.write(id, data.body) {
+                        Ok(backlog) => self.backlog = backlog,
+                        Err(err) => {
+                            if let Some(frame) = self.session_list.write_error(err) {
+                                link.write.send(frame.to_vec()?.into()).await?;
+                            }
+                        }
+                    }
+                }
             }
         }
+        Ok(())
     }
 }
+
+#[test]
+fn test_reconnect_backoff() {
+    let options = TunnelOptions::default();
+    assert_eq!(options.backoff(1), Duration::from_secs(1));
+    assert_eq!(options.backoff(3), Duration::from_secs(4));
+    assert_eq!(options.backoff(7), Duration::from_secs(60));
+    assert_eq!(options.backoff(100), Duration::from_secs(60));
+}
@@ -1,110 +1,614 @@
-use super::protocol::{Frame, ReleaseCode, Service};
+use super::protocol::{Frame, FrameType, LocalStream, ReleaseCode, Service};
+use crate::util::clock::{default_clock, Clock};
 use crate::util::inc_u64;
 use crate::{Error, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, Sender};
-use tokio::task;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, error::TrySendError, OwnedPermit, Receiver, Sender};
+use tokio::task::{self, AbortHandle};
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// 本地读缓冲的初始大小，连续读满时翻倍，直到单帧最大长度
+const MIN_READ_SIZE: usize = 4 * 1024;
+/// 默认的单帧最大数据长度
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+/// 每个会话发往本地服务的缓冲帧数
+const SESSION_QUEUE: usize = 64;
+/// 会话关闭后，等待已缓冲的数据写入本地服务的最长时间
+const LINGER: Duration = Duration::from_secs(5);
+/// 默认的写缓冲等待时间，超过后关闭该会话
+pub const DEFAULT_BACKLOG_TIMEOUT: Duration = Duration::from_secs(10);
+/// 本地服务关闭写端后，默认等待对端数据的最长时间
+pub const DEFAULT_HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct Session {
     tx: Sender<Vec<u8>>,
     service_type: String,
-    local_tx: Sender<Frame>,
+    reader: AbortHandle,
+    writer: AbortHandle,
+    last_active: Instant,
+    created: i64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Session {
+    fn info(&self, id: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.to_string(),
+            service_type: self.service_type.clone(),
+            created: self.created,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+        }
+    }
+
+    /// 停止读取本地服务，已缓冲的数据写完后关闭本地连接的写端
+    fn close(self, id: &str) -> SessionInfo {
+        let info = self.info(id);
+        self.reader.abort();
+        let writer = self.writer;
+        task::spawn(async move {
+            time::sleep(LINGER).await;
+            writer.abort();
+        });
+        info
+    }
+}
+
+/// 会话信息
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub service_type: String,
+    /// 会话创建时间，Unix 毫秒时间
+    pub created: i64,
+    /// 云端发往本地服务的字节数
+    pub bytes_in: u64,
+    /// 本地服务发往云端的字节数
+    pub bytes_out: u64,
+}
+
+/// 会话的写缓冲已满时暂存的数据
+pub struct Backlog {
+    id: String,
+    tx: Sender<Vec<u8>>,
+    data: Vec<u8>,
+    deadline: Instant,
+}
+
+impl Backlog {
+    /// 等待会话的写缓冲有空位，会话已关闭或超过等待时间时返回 `None`。
+    /// 结果交给 [`SessionList::resume`] 处理。
+    pub fn ready(&self) -> impl Future<Output = Option<OwnedPermit<Vec<u8>>>> + 'static {
+        let tx = self.tx.clone();
+        let deadline = self.deadline;
+        async move {
+            time::timeout_at(deadline, tx.reserve_owned())
+                .await
+                .ok()?
+                .ok()
+        }
+    }
+}
+
+/// 本地发往对端的帧的处理结果
+pub enum Outgoing {
+    /// 发送给对端
+    Send,
+    /// 本地关闭了会话，发送关闭帧
+    Closed(SessionInfo),
+    /// 会话已经关闭，丢弃
+    Drop,
 }
 
 pub struct SessionList {
     txs: HashMap<String, Session>,
+    close_code: ReleaseCode,
+    max_frame_size: usize,
+    backlog_timeout: Duration,
+    half_close_timeout: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl SessionList {
     pub fn new() -> Self {
+        Self::with_close_code(ReleaseCode::DeviceClose)
+    }
+
+    /// 本地数据流关闭时使用 `close_code` 通知对端
+    pub fn with_close_code(close_code: ReleaseCode) -> Self {
         Self {
             txs: HashMap::new(),
+            close_code,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            backlog_timeout: DEFAULT_BACKLOG_TIMEOUT,
+            half_close_timeout: DEFAULT_HALF_CLOSE_TIMEOUT,
+            clock: default_clock(),
         }
     }
+
+    /// 设置本地服务发往对端的单帧最大数据长度
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size.max(MIN_READ_SIZE);
+    }
+
+    /// 设置等待会话写缓冲的最长时间
+    pub fn set_backlog_timeout(&mut self, timeout: Duration) {
+        self.backlog_timeout = timeout;
+    }
+
+    /// 设置本地服务关闭写端后等待对端数据的最长时间，超过后关闭会话
+    pub fn set_half_close_timeout(&mut self, timeout: Duration) {
+        self.half_close_timeout = timeout;
+    }
+
+    /// 设置记录会话创建时间的时钟
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
 }
 
 impl SessionList {
     pub async fn add(&mut self, id: String, info: &Service, local_tx: Sender<Frame>) -> Result<()> {
-        let addr = format!("{}:{}", info.ip, info.port);
-        log::info!("tcp://{} session_id: {}", addr, id);
+        let stream = info.connect(&id).await?;
+        self.attach(id, info.r#type.clone(), stream, local_tx);
+        Ok(())
+    }
 
-        let (tx, mut rx) = mpsc::channel(128);
-        let service_type = info.r#type.clone();
-        self.txs.insert(
+    /// 把已经建立的本地数据流作为会话，本地数据流出错时向 `local_tx` 发送关闭帧。
+    ///
+    /// 读写分别在两个任务中进行：`local_tx` 已满时暂停读取本地服务，
+    /// 写缓冲已满时由 [`SessionList::write`] 返回 [`Backlog`]，两个方向互不阻塞。
+    /// 本地服务关闭写端后只停止读取，仍然写入对端的数据，
+    /// 对端超过 `half_close_timeout` 没有数据时才发送关闭帧。
+    pub fn attach(
+        &mut self,
+        id: String,
+        service_type: String,
+        stream: Box<dyn LocalStream>,
+        local_tx: Sender<Frame>,
+    ) {
+        let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+        let (read, write) = tokio::io::split(stream);
+        let eof = CancellationToken::new();
+        let reader = task::spawn(read_local(
+            read,
+            id.clone(),
+            service_type.clone(),
+            self.max_frame_size,
+            self.close_code,
+            local_tx.clone(),
+            eof.clone(),
+        ));
+        let writer = task::spawn(write_local(
+            write,
+            rx,
             id.clone(),
+            self.close_code,
+            local_tx,
+            (eof, self.half_close_timeout),
+        ));
+        self.txs.insert(
+            id,
             Session {
                 tx,
-                service_type: service_type.clone(),
-                local_tx: local_tx.clone(),
+                service_type,
+                reader: reader.abort_handle(),
+                writer: writer.abort_handle(),
+                last_active: Instant::now(),
+                created: self.clock.now_millis(),
+                bytes_in: 0,
+                bytes_out: 0,
             },
         );
+    }
 
-        let mut stream = TcpStream::connect(&addr).await?;
-        task::spawn(async move {
-            let mut buf = [0; 1024];
-            loop {
-                tokio::select! {
-                    Some(w) = rx.recv() => {
-                        // log::debug!("write {}={}", addr, String::from_utf8_lossy(&w));
-                        if let Err(err) = stream.write_all(&w).await {
-                            log::error!("write error: {:?}", err);
-                        }
-                    },
-                    Ok(n) = stream.read(&mut buf) => {
-                        // log::debug!("read={:x?}", &buf[..n]);
-                        if n == 0 {
-                            let frame = Frame::release(id.clone(), inc_u64(), ReleaseCode::DeviceClose, "server closed".to_string());
-                            local_tx.send(frame).await.ok();
-                            return;
-                        }
-                        let frame = Frame::raw(id.clone(), inc_u64(), Some(service_type.clone()), buf[..n].into());
-                        local_tx.send(frame).await.ok();
-                    },
-                    else => break,
-                }
+    /// 主动关闭会话，返回会话信息和需要发给对端的关闭帧
+    pub fn release(
+        &mut self,
+        id: &str,
+        code: ReleaseCode,
+        msg: String,
+    ) -> Option<(SessionInfo, Frame)> {
+        let info = self.remove(id)?;
+        let frame = Frame::release(id.to_string(), inc_u64(), code, msg);
+        Some((info, frame))
+    }
+
+    /// 移除会话并关闭本地连接，不通知对端
+    pub fn remove(&mut self, id: &str) -> Option<SessionInfo> {
+        self.txs.remove(id).map(|s| s.close(id))
+    }
+
+    /// 移除所有会话
+    pub fn drain(&mut self) -> Vec<SessionInfo> {
+        self.txs.drain().map(|(id, s)| s.close(&id)).collect()
+    }
+
+    /// 记录本地服务发往对端的数据，会话不存在时返回 `false`
+    pub fn sent(&mut self, id: &str, len: usize) -> bool {
+        match self.txs.get_mut(id) {
+            Some(session) => {
+                session.last_active = Instant::now();
+                session.bytes_out += len as u64;
+                true
             }
-            let frame = Frame::release(
-                id.clone(),
-                inc_u64(),
-                ReleaseCode::DeviceClose,
-                "unknown".to_string(),
-            );
-            local_tx.send(frame).await.ok();
-        });
+            None => false,
+        }
+    }
 
-        Ok(())
+    /// 处理本地会话任务发往对端的帧。
+    ///
+    /// 会话已经被关闭后，本地任务可能还有未发出的数据帧或关闭帧，这些帧需要丢弃，
+    /// 保证对端不会在关闭帧之后收到同一会话的数据，也不会重复收到关闭帧。
+    pub fn outgoing(&mut self, frame: &Frame) -> Outgoing {
+        let id = match &frame.header.session_id {
+            Some(id) => id,
+            None => return Outgoing::Send,
+        };
+        match frame.header.frame_type {
+            FrameType::ReleaseSession => match self.remove(id) {
+                Some(info) => Outgoing::Closed(info),
+                None => Outgoing::Drop,
+            },
+            FrameType::RawData if !self.sent(id, frame.body.len()) => Outgoing::Drop,
+            _ => Outgoing::Send,
+        }
     }
 
-    pub async fn release(&mut self, id: String, code: ReleaseCode, msg: String) -> Result<()> {
-        self.txs.remove(&id);
-        let data = Frame::release(id.clone(), inc_u64(), code, msg);
+    /// 会话总数
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
 
-        let session = self
-            .txs
-            .get(&id)
-            .ok_or(Error::SessionNotFound(id.to_string()))?
-            .clone();
-        session
-            .local_tx
-            .send(data)
-            .await
-            .map_err(|_| Error::MpscSendError)
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// 指定服务的会话数
+    pub fn count(&self, service_type: &str) -> usize {
+        self.txs
+            .values()
+            .filter(|s| s.service_type == service_type)
+            .count()
     }
 
-    pub async fn write(&mut self, id: String, data: Vec<u8>) -> Result<()> {
+    pub fn infos(&self) -> Vec<SessionInfo> {
+        self.txs.iter().map(|(id, s)| s.info(id)).collect()
+    }
+
+    /// 超过 `timeout` 没有数据收发的会话
+    pub fn idle(&self, timeout: Duration) -> Vec<String> {
+        self.txs
+            .iter()
+            .filter(|(_, s)| s.last_active.elapsed() > timeout)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// 把对端的数据写入会话，不会等待。
+    ///
+    /// 会话的写缓冲已满时返回 `Some(Backlog)`，调用方应当暂停读取对端的数据，
+    /// 等待 [`Backlog::ready`] 后再继续，从而把本地服务的处理速度反馈给对端。
+    /// 本地服务一直不读取时，等待超时后只关闭这个会话，见 [`SessionList::resume`]。
+    pub fn write(&mut self, id: String, data: Vec<u8>) -> Result<Option<Backlog>> {
         let session = self
             .txs
-            .get(&id)
-            .ok_or(Error::SessionNotFound(id.to_string()))?
-            .clone();
-        session
-            .tx
-            .send(data)
+            .get_mut(&id)
+            .ok_or(Error::SessionNotFound(id.to_string()))?;
+        session.last_active = Instant::now();
+        session.bytes_in += data.len() as u64;
+        match session.tx.try_send(data) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(data)) => Ok(Some(Backlog {
+                id,
+                tx: session.tx.clone(),
+                data,
+                deadline: Instant::now() + self.backlog_timeout,
+            })),
+            Err(TrySendError::Closed(_)) => Err(Error::SessionClosed(id)),
+        }
+    }
+
+    /// 处理 [`SessionList::write`] 的错误，返回需要发给对端的关闭帧。
+    ///
+    /// 会话不存在时通知对端关闭，让对端停止发送这个会话的数据；
+    /// 会话已关闭时写任务已经发送了关闭帧，只记录日志。
+    pub fn write_error(&self, err: Error) -> Option<Frame> {
+        match err {
+            Error::SessionNotFound(id) => {
+                log::warn!("session {} not found, release", id);
+                let msg = "session not found".to_string();
+                Some(Frame::release(id, inc_u64(), self.close_code, msg))
+            }
+            err => {
+                log::warn!("drop session data: {}", err);
+                None
+            }
+        }
+    }
+
+    /// 处理 [`Backlog::ready`] 的结果，写入暂存的数据。
+    ///
+    /// 等待超时时关闭会话，返回会话信息和需要发给对端的关闭帧。
+    pub fn resume(
+        &mut self,
+        backlog: Backlog,
+        permit: Option<OwnedPermit<Vec<u8>>>,
+    ) -> Option<(SessionInfo, Frame)> {
+        match permit {
+            Some(permit) => {
+                permit.send(backlog.data);
+                None
+            }
+            // 本地连接写入出错，写任务会发送关闭帧
+            None if backlog.tx.is_closed() => None,
+            None => {
+                log::warn!("session {} backlog timeout", backlog.id);
+                let msg = "backlog timeout".to_string();
+                self.release(&backlog.id, self.close_code, msg)
+            }
+        }
+    }
+}
+
+/// 读取本地服务的数据发往对端，出错时发送关闭帧，读到结束时通知 [`write_local`]
+async fn read_local(
+    mut read: ReadHalf<Box<dyn LocalStream>>,
+    id: String,
+    service_type: String,
+    max_frame_size: usize,
+    close_code: ReleaseCode,
+    local_tx: Sender<Frame>,
+    eof: CancellationToken,
+) {
+    let mut size = MIN_READ_SIZE.min(max_frame_size);
+    let msg = loop {
+        let mut buf = vec![0; size];
+        let n = match read.read(&mut buf).await {
+            Ok(0) => {
+                eof.cancel();
+                return;
+            }
+            Ok(n) => n,
+            Err(err) => break format!("read error: {err}"),
+        };
+        // 连续读满说明本地服务在批量发送，加大缓冲以减少帧数
+        if n == size {
+            size = (size * 2).min(max_frame_size);
+        } else if n < size / 4 {
+            size = (size / 2).max(MIN_READ_SIZE).min(max_frame_size);
+        }
+        buf.truncate(n);
+        let frame = Frame::raw(id.clone(), inc_u64(), Some(service_type.clone()), buf);
+        // local_tx 已满时在这里等待，不再读取本地服务
+        if local_tx.send(frame).await.is_err() {
+            return;
+        }
+    };
+    let frame = Frame::release(id, inc_u64(), close_code, msg);
+    local_tx.send(frame).await.ok();
+}
+
+/// 把对端的数据写入本地服务，会话被移除后写完剩余数据并关闭写端。
+///
+/// 本地服务关闭写端（`eof`）后，对端超过 `timeout` 没有数据时发送关闭帧。
+async fn write_local(
+    mut write: WriteHalf<Box<dyn LocalStream>>,
+    mut rx: Receiver<Vec<u8>>,
+    id: String,
+    close_code: ReleaseCode,
+    local_tx: Sender<Frame>,
+    (eof, timeout): (CancellationToken, Duration),
+) {
+    let mut half_closed = false;
+    loop {
+        let data = if half_closed {
+            match time::timeout(timeout, rx.recv()).await {
+                Ok(data) => data,
+                Err(_) => {
+                    let frame = Frame::release(id, inc_u64(), close_code, "server closed".into());
+                    local_tx.send(frame).await.ok();
+                    break;
+                }
+            }
+        } else {
+            tokio::select! {
+                data = rx.recv() => data,
+                _ = eof.cancelled() => {
+                    half_closed = true;
+                    continue;
+                }
+            }
+        };
+        let data = match data {
+            Some(data) => data,
+            None => break,
+        };
+        if let Err(err) = write.write_all(&data).await {
+            log::warn!("session {} write error: {:?}", id, err);
+            let frame = Frame::release(id, inc_u64(), close_code, format!("write error: {err}"));
+            local_tx.send(frame).await.ok();
+            return;
+        }
+    }
+    write.shutdown().await.ok();
+}
+
+#[tokio::test]
+async fn test_session_idle() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let service = Service::new("_TEST".into(), "127.0.0.1".into(), port);
+    let (local_tx, _local_rx) = mpsc::channel(16);
+    let clock = crate::util::clock::ManualClock::new(1_000);
+    let mut list = SessionList::new();
+    list.set_clock(clock.clone());
+    list.add("s1".to_string(), &service, local_tx)
+        .await
+        .unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    clock.advance(Duration::from_secs(1));
+
+    assert!(list.idle(Duration::from_secs(60)).is_empty());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(list.idle(Duration::from_millis(10)), vec!["s1".to_string()]);
+    // 收到对端的数据后不再空闲
+    list.write("s1".to_string(), b"ping".to_vec()).unwrap();
+    assert!(list.idle(Duration::from_millis(10)).is_empty());
+
+    list.sent("s1", 2);
+    let mut buf = [0; 8];
+    assert_eq!(server.read(&mut buf).await.unwrap(), 4);
+    let info = &list.infos()[0];
+    assert_eq!((info.bytes_in, info.bytes_out), (4, 2));
+    // 创建时间来自设置的时钟
+    assert_eq!(info.created, 1_000);
+    assert_eq!(list.count("_TEST"), 1);
+
+    // 移除会话后本地连接被关闭
+    list.remove("s1");
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_session_endpoints() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = Service::handler(
+        "_ECHO".into(),
+        |mut stream: tokio::io::DuplexStream| async move {
+            let mut buf = [0; 64];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        },
+    );
+    let (local_tx, mut local_rx) = mpsc::channel(16);
+    let mut list = SessionList::new();
+    list.add("s1".to_string(), &echo, local_tx.clone())
+        .await
+        .unwrap();
+    list.write("s1".to_string(), b"hello".to_vec()).unwrap();
+    let frame = local_rx.recv().await.unwrap();
+    assert_eq!(frame.session_id(), "s1");
+    assert_eq!(frame.body, b"hello".to_vec());
+
+    #[cfg(unix)]
+    {
+        let dir = tempdir::TempDir::new("tunnel").unwrap();
+        let path = dir.path().join("service.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let service = Service::unix("_UNIX".into(), &path);
+        list.add("s2".to_string(), &service, local_tx)
             .await
-            .map_err(|_| Error::UploadDataError)?;
-        Ok(())
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.write_all(b"world").await.unwrap();
+        let frame = local_rx.recv().await.unwrap();
+        assert_eq!(frame.session_id(), "s2");
+        assert_eq!(frame.body, b"world".to_vec());
+    }
+}
+
+#[tokio::test]
+async fn test_session_release() {
+    let (local_tx, mut local_rx) = mpsc::channel(16);
+    let mut list = SessionList::new();
+    list.set_half_close_timeout(Duration::from_millis(100));
+
+    // 本地服务关闭连接，等待对端数据超时后发送关闭帧，关闭帧只发送一次
+    let (stream, peer) = tokio::io::duplex(64);
+    list.attach(
+        "s1".into(),
+        "_TEST".into(),
+        Box::new(stream),
+        local_tx.clone(),
+    );
+    drop(peer);
+    let frame = local_rx.recv().await.unwrap();
+    assert_eq!(frame.header.frame_type, FrameType::ReleaseSession);
+    assert!(matches!(list.outgoing(&frame), Outgoing::Closed(_)));
+    assert!(matches!(list.outgoing(&frame), Outgoing::Drop));
+
+    // 主动关闭会话后，还没发出的数据帧被丢弃，本地连接的写端被关闭
+    let (stream, mut peer) = tokio::io::duplex(64);
+    list.attach(
+        "s2".into(),
+        "_TEST".into(),
+        Box::new(stream),
+        local_tx.clone(),
+    );
+    peer.write_all(b"data").await.unwrap();
+    let frame = local_rx.recv().await.unwrap();
+    let (info, release) = list
+        .release("s2", ReleaseCode::DeviceClose, "idle".into())
+        .unwrap();
+    assert_eq!(
+        (info.id.as_str(), release.session_id()),
+        ("s2", "s2".into())
+    );
+    assert!(matches!(list.outgoing(&frame), Outgoing::Drop));
+    assert!(list
+        .release("s2", ReleaseCode::DeviceClose, "idle".into())
+        .is_none());
+    let mut buf = [0; 8];
+    assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+
+    // 本地连接写入失败后，会话不再接受对端的数据
+    let (stream, peer) = tokio::io::duplex(64);
+    list.attach("s3".into(), "_TEST".into(), Box::new(stream), local_tx);
+    drop(peer);
+    let mut closed = false;
+    for _ in 0..50 {
+        match list.write("s3".into(), b"data".to_vec()) {
+            Ok(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            Err(err) => {
+                closed = matches!(err, Error::SessionClosed(_));
+                // 写任务已经发送了关闭帧
+                assert!(list.write_error(err).is_none());
+                break;
+            }
+        }
     }
+    assert!(closed);
+
+    // 不存在的会话通知对端关闭
+    let err = list.write("s4".into(), b"data".to_vec()).err().unwrap();
+    let frame = list.write_error(err).unwrap();
+    assert_eq!(frame.header.frame_type, FrameType::ReleaseSession);
+    assert_eq!(frame.session_id(), "s4");
+}
+
+#[tokio::test]
+async fn test_session_backlog() {
+    let (local_tx, _local_rx) = mpsc::channel(16);
+    let mut list = SessionList::new();
+    let (stream, mut peer) = tokio::io::duplex(1024);
+    list.attach("s1".into(), "_TEST".into(), Box::new(stream), local_tx);
+
+    // 本地服务不读取数据时，写缓冲最终被填满
+    let chunk = vec![0u8; 1024];
+    let mut written = 0;
+    let backlog = loop {
+        written += chunk.len();
+        if let Some(backlog) = list.write("s1".into(), chunk.clone()).unwrap() {
+            break backlog;
+        }
+        assert!(written <= (SESSION_QUEUE + 3) * chunk.len());
+    };
+    // 本地服务读取后写缓冲有空位，暂存的数据随后写入
+    let ready = tokio::spawn(backlog.ready());
+    let mut buf = vec![0; written - chunk.len()];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert!(list.resume(backlog, ready.await.unwrap()).is_none());
+    peer.read_exact(&mut buf[..chunk.len()]).await.unwrap();
+    assert_eq!(list.infos()[0].bytes_in, written as u64);
 }
@@ -0,0 +1,488 @@
+//! 安全隧道的访问端。
+//!
+//! 使用云端 `OpenSecureTunnel` 返回的访问端地址和 token 连接隧道，
+//! 每个本地连接对应隧道中的一个会话，可以不借助控制台直接访问设备上的服务。
+
+use super::protocol::{
+    connect_ws, Frame, FrameType, LocalStream, ReleaseCode, ResponseBody, ResponseCode, WsStream,
+};
+use super::session::{Backlog, Outgoing, SessionList};
+use crate::util::auth::aliyun_client_config;
+use crate::util::inc_u64;
+use crate::{Error, Result};
+use futures::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tungstenite::Message;
+
+/// 访问端连接参数
+#[derive(Debug, Clone)]
+pub struct SourceParams {
+    /// 访问端 WebSocket 地址，如 `wss://host:443/path`
+    pub uri: String,
+    /// 访问端 token
+    pub token: String,
+}
+
+enum SourceAction {
+    Open(
+        String,
+        Box<dyn LocalStream>,
+        oneshot::Sender<Result<String>>,
+    ),
+}
+
+/// 等待云端创建会话的请求
+struct Pending {
+    service_type: String,
+    stream: Box<dyn LocalStream>,
+    reply: oneshot::Sender<Result<String>>,
+}
+
+/// 安全隧道的访问端
+#[derive(Debug, Clone)]
+pub struct SourceClient {
+    tx: Sender<SourceAction>,
+}
+
+impl SourceClient {
+    /// 连接隧道，连接断开后所有会话关闭
+    pub async fn connect(params: SourceParams) -> Result<Self> {
+        let client_config = Arc::new(aliyun_client_config()?);
+        let ws_stream = connect_ws(&params.uri, &params.token, &client_config).await?;
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(Self::run(ws_stream, rx));
+        Ok(Self { tx })
+    }
+
+    /// 为本地数据流创建访问 `service_type` 服务的会话，返回会话ID
+    pub async fn open_session(
+        &self,
+        service_type: &str,
+        stream: Box<dyn LocalStream>,
+    ) -> Result<String> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(SourceAction::Open(service_type.to_string(), stream, tx))
+            .await
+            .map_err(|_| Error::MpscSendError)?;
+        rx.await.map_err(|_| Error::OneshotRecvError)?
+    }
+
+    /// 在本地监听 TCP 端口，每个连接创建一个访问 `service_type` 服务的会话，返回实际监听的地址。
+    ///
+    /// 隧道断开后停止监听。
+    pub async fn listen(&self, addr: &str, service_type: &str) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let client = self.clone();
+        let service_type = service_type.to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                match client.open_session(&service_type, Box::new(stream)).await {
+                    Ok(id) => log::info!("{} -> session {}", peer, id),
+                    Err(Error::MpscSendError) => break,
+                    Err(err) => log::warn!("{} open session error: {}", peer, err),
+                }
+            }
+            log::info!("stop listening {}", local_addr);
+        });
+        Ok(local_addr)
+    }
+
+    async fn run(ws_stream: WsStream, mut rx: Receiver<SourceAction>) {
+        let (mut write, mut read) = ws_stream.split();
+        let (local_tx, mut local_rx) = mpsc::channel(64);
+        let mut session_list = SessionList::with_close_code(ReleaseCode::ClientClose);
+        let mut pending: HashMap<u64, Pending> = HashMap::new();
+        // 会话写缓冲已满时暂存的数据，暂存期间不再读取隧道
+        let mut backlog: Option<Backlog> = None;
+        loop {
+            let ready = backlog.as_ref().map(Backlog::ready);
+            let ready = async move {
+                match ready {
+                    Some(ready) => ready.await,
+                    None => futures::future::pending().await,
+                }
+            };
+            let res: Result<()> = tokio::select! {
+                msg = read.next(), if backlog.is_none() => match msg {
+                    Some(Ok(msg)) if msg.is_binary() || msg.is_text() => {
+                        match Frame::from_slice(&msg.into_data()) {
+                            Ok(frame) => {
+                                let res = Self::on_frame(frame, &mut pending, &mut session_list, &local_tx, &mut write).await;
+                                res.map(|res| backlog = res)
+                            }
+                            Err(err) => Err(err),
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                },
+                permit = ready => match backlog.take().and_then(|b| session_list.resume(b, permit)) {
+                    Some((_, frame)) => match frame.to_vec() {
+                        Ok(data) => match write.send(data.into()).await {
+                            Ok(()) => Ok(()),
+                            Err(_) => break,
+                        },
+                        Err(err) => Err(err),
+                    },
+                    None => Ok(()),
+                },
+                Some(frame) = local_rx.recv() => {
+                    match session_list.outgoing(&frame) {
+                        Outgoing::Drop => Ok(()),
+                        _ => match frame.to_vec() {
+                            Ok(data) => match write.send(data.into()).await {
+                                Ok(()) => Ok(()),
+                                // 隧道已断开
+                                Err(_) => break,
+                            },
+                            Err(err) => Err(err),
+                        },
+                    }
+                },
+                action = rx.recv() => match action {
+                    Some(SourceAction::Open(service_type, stream, reply)) => {
+                        let frame_id = inc_u64();
+                        let frame = Frame::new_session(frame_id, service_type.clone());
+                        let res = match frame.to_vec() {
+                            Ok(data) => write.send(data.into()).await.map_err(Error::from),
+                            Err(err) => Err(err),
+                        };
+                        match res {
+                            Ok(()) => {
+                                pending.insert(frame_id, Pending { service_type, stream, reply });
+                            }
+                            Err(err) => {
+                                reply.send(Err(err)).ok();
+                            }
+                        }
+                        Ok(())
+                    }
+                    None => break,
+                },
+            };
+            if let Err(err) = res {
+                log::warn!("source error: {}", err);
+            }
+        }
+        log::info!("source closed");
+        session_list.drain();
+        write.close().await.ok();
+    }
+
+    /// 处理隧道下发的帧，会话写缓冲已满时返回暂存的数据
+    async fn on_frame(
+        frame: Frame,
+        pending: &mut HashMap<u64, Pending>,
+        session_list: &mut SessionList,
+        local_tx: &Sender<Frame>,
+        write: &mut SplitSink<WsStream, Message>,
+    ) -> Result<Option<Backlog>> {
+        match frame.header.frame_type {
+            FrameType::Response => {
+                let request = match frame.header.frame_id.and_then(|id| pending.remove(&id)) {
+                    Some(request) => request,
+                    None => return Ok(None),
+                };
+                let id = frame.session_id();
+                match serde_json::from_slice::<ResponseBody<ResponseCode>>(&frame.body) {
+                    Ok(body) if body.code == ResponseCode::Success => {
+                        session_list.attach(
+                            id.clone(),
+                            request.service_type,
+                            request.stream,
+                            local_tx.clone(),
+                        );
+                        request.reply.send(Ok(id)).ok();
+                    }
+                    Ok(body) => {
+                        let err = Error::SessionCreate(format!("{:?} {}", body.code, body.msg));
+                        request.reply.send(Err(err)).ok();
+                    }
+                    Err(err) => {
+                        request.reply.send(Err(err.into())).ok();
+                    }
+                }
+            }
+            FrameType::RawData => {
+                if let Some(id) = frame.header.session_id {
+                    match session_list.write(id, frame.body) {
+                        Ok(backlog) => return Ok(backlog),
+                        Err(err) => {
+                            if let Some(frame) = session_list.write_error(err) {
+                                write.send(frame.to_vec()?.into()).await?;
+                            }
+                        }
+                    }
+                }
+            }
+            FrameType::ReleaseSession => {
+                if let Some(id) = frame.header.session_id {
+                    session_list.remove(&id);
+                }
+            }
+            FrameType::NewSession => {}
+        }
+        Ok(None)
+    }
+}
+
+/// 模拟云端转发访问端和设备端的帧，设备端先连接
+#[cfg(test)]
+async fn relay(listener: TcpListener) {
+    let (device, _) = listener.accept().await.unwrap();
+    let device = tokio_tungstenite::accept_async(device).await.unwrap();
+    let (source, _) = listener.accept().await.unwrap();
+    let source = tokio_tungstenite::accept_async(source).await.unwrap();
+    let (mut device_tx, mut device_rx) = device.split();
+    let (mut source_tx, mut source_rx) = source.split();
+    let mut session = 0;
+    loop {
+        tokio::select! {
+            Some(Ok(msg)) = source_rx.next() => {
+                let mut frame = Frame::from_slice(&msg.into_data()).unwrap();
+                if frame.header.frame_type == FrameType::NewSession {
+                    session += 1;
+                    frame.header.session_id = Some(format!("session-{}", session));
+                }
+                device_tx.send(frame.to_vec().unwrap().into()).await.unwrap();
+            }
+            Some(Ok(msg)) = device_rx.next() => {
+                if msg.is_binary() {
+                    source_tx.send(msg).await.unwrap();
+                }
+            }
+            else => break,
+        }
+    }
+}
+
+/// 通过 [`relay`] 连接设备端和访问端，设备端提供回显服务 `_ECHO`
+#[cfg(test)]
+async fn loopback() -> (crate::TunnelProxy, SourceClient) {
+    loopback_with(Default::default()).await
+}
+
+#[cfg(test)]
+async fn loopback_with(options: super::proxy::TunnelOptions) -> (crate::TunnelProxy, SourceClient) {
+    use super::protocol::Service;
+    use super::Event;
+    use crate::{TunnelParams, TunnelProxy};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(relay(listener));
+
+    let proxy = TunnelProxy::with_options(options);
+    let echo = Service::handler(
+        "_ECHO".into(),
+        |mut stream: tokio::io::DuplexStream| async move {
+            let mut buf = vec![0; 16 * 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        },
+    );
+    proxy.add_service(echo).await.unwrap();
+    let mut events = proxy.events();
+    proxy
+        .add_tunnel(TunnelParams {
+            id: "tunnel".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: port.to_string(),
+            path: "/device".to_string(),
+            token: "device-token".to_string(),
+            token_expire: None,
+            schema: Some("ws".to_string()),
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        events.recv().await.unwrap(),
+        Event::Connect("tunnel".to_string())
+    );
+
+    let source = SourceClient::connect(SourceParams {
+        uri: format!("ws://127.0.0.1:{}/source", port),
+        token: "source-token".to_string(),
+    })
+    .await
+    .unwrap();
+    (proxy, source)
+}
+
+#[tokio::test]
+async fn test_source_loopback() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (proxy, source) = loopback().await;
+    let addr = source.listen("127.0.0.1:0", "_ECHO").await.unwrap();
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"hello tunnel").await.unwrap();
+    let mut buf = [0; 12];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello tunnel");
+
+    let tunnels = proxy.tunnels().await.unwrap();
+    assert_eq!(tunnels[0].sessions[0].bytes_in, 12);
+
+    // 不存在的服务被设备端拒绝
+    let (local, _remote) = tokio::io::duplex(64);
+    let err = source.open_session("_NONE", Box::new(local)).await;
+    assert!(matches!(err, Err(Error::SessionCreate(_))));
+}
+
+/// 被拒绝的会话不占用创建频率的额度
+#[tokio::test]
+async fn test_session_rate_limit() {
+    use super::proxy::{RateLimit, TunnelOptions};
+
+    let options = TunnelOptions {
+        max_sessions: 1,
+        session_rate_limit: Some(RateLimit {
+            count: 2,
+            period: std::time::Duration::from_secs(60),
+        }),
+        ..Default::default()
+    };
+    let (proxy, source) = loopback_with(options).await;
+    let (local, remote) = tokio::io::duplex(64);
+    source.open_session("_ECHO", Box::new(local)).await.unwrap();
+    // 超过最大会话数
+    let (local, _remote) = tokio::io::duplex(64);
+    let err = source.open_session("_ECHO", Box::new(local)).await;
+    assert!(matches!(err, Err(Error::SessionCreate(_))));
+
+    // 本地连接关闭后等待对端数据超时才释放会话
+    drop(remote);
+    for _ in 0..100 {
+        if proxy.tunnels().await.unwrap()[0].sessions.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let (local, _remote) = tokio::io::duplex(64);
+    source.open_session("_ECHO", Box::new(local)).await.unwrap();
+    let (local, _remote) = tokio::io::duplex(64);
+    let err = source.open_session("_ECHO", Box::new(local)).await;
+    assert!(matches!(err, Err(Error::SessionCreate(_))));
+}
+
+/// 一个会话的本地服务不读取数据时，只关闭这个会话，不影响隧道和其他会话
+#[tokio::test]
+async fn test_session_backlog_timeout() {
+    use super::proxy::TunnelOptions;
+    use super::{protocol::Service, Event};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let options = TunnelOptions {
+        ping_interval: Duration::from_millis(100),
+        // 短于写缓冲的等待时间，等待期间不能触发保活超时
+        keepalive_timeout: Duration::from_millis(300),
+        backlog_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let (proxy, source) = loopback_with(options).await;
+    let sink = Service::handler(
+        "_SINK".into(),
+        |stream: tokio::io::DuplexStream| async move {
+            let _stream = stream;
+            futures::future::pending::<()>().await
+        },
+    );
+    proxy.add_service(sink).await.unwrap();
+    let mut events = proxy.events();
+
+    let (local, stalled) = tokio::io::duplex(64 * 1024);
+    source.open_session("_SINK", Box::new(local)).await.unwrap();
+    let (local, mut echo) = tokio::io::duplex(64 * 1024);
+    source.open_session("_ECHO", Box::new(local)).await.unwrap();
+    let (mut stalled_read, mut stalled_write) = tokio::io::split(stalled);
+    tokio::spawn(async move {
+        let data = vec![0u8; 64 * 1024];
+        while stalled_write.write_all(&data).await.is_ok() {}
+    });
+
+    let test = async {
+        // 写缓冲等待超时后关闭不读取的会话
+        assert_eq!(stalled_read.read(&mut [0; 8]).await.unwrap(), 0);
+        echo.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        echo.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    };
+    tokio::time::timeout(Duration::from_secs(10), test)
+        .await
+        .unwrap();
+
+    let sessions = proxy.tunnels().await.unwrap()[0].sessions.clone();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].service_type, "_ECHO");
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event, Event::Disconnect(_)), "{:?}", event);
+    }
+}
+
+/// 本地关闭写端后仍然能读到对端的回复
+#[tokio::test]
+async fn test_source_half_close() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (_proxy, source) = loopback().await;
+    let addr = source.listen("127.0.0.1:0", "_ECHO").await.unwrap();
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut read, mut write) = stream.into_split();
+    write.write_all(b"hello").await.unwrap();
+    write.shutdown().await.unwrap();
+
+    let mut buf = [0; 5];
+    read.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(read.read(&mut [0; 8]).await.unwrap(), 0);
+}
+
+/// 类似 scp 的批量传输：回显服务的两个方向同时满载，不能互相阻塞
+#[tokio::test(flavor = "multi_thread")]
+async fn test_source_bulk_transfer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (proxy, source) = loopback().await;
+    let addr = source.listen("127.0.0.1:0", "_ECHO").await.unwrap();
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut read, mut write) = stream.into_split();
+
+    let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let start = std::time::Instant::now();
+    let sender = tokio::spawn(async move {
+        write.write_all(&data).await.unwrap();
+        write
+    });
+    let mut received = vec![0; expected.len()];
+    read.read_exact(&mut received).await.unwrap();
+    log::info!("8MiB echo in {:?}", start.elapsed());
+    assert!(received == expected);
+
+    // 本地关闭连接后，等待对端数据超时，两端的会话都被释放
+    let mut write = sender.await.unwrap();
+    write.shutdown().await.unwrap();
+    assert_eq!(read.read(&mut [0; 8]).await.unwrap(), 0);
+    for _ in 0..100 {
+        if proxy.tunnels().await.unwrap()[0].sessions.is_empty() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("device session not released");
+}
@@ -94,6 +94,33 @@ pub mod http {
     }
 }
 
+pub mod coap {
+    pub fn client_id(product_key: &str, device_name: &str) -> String {
+        format!("{}&{}", product_key, device_name)
+    }
+
+    /// 对称加密方式需要传入 `seq` 参与签名，DTLS 方式不需要。
+    pub fn sign(
+        product_key: &str,
+        device_name: &str,
+        device_secret: &str,
+        seq: Option<&str>,
+        timestamp: &str,
+    ) -> String {
+        let mut text = format!(
+            "clientId{}deviceName{}productKey{}",
+            client_id(product_key, device_name),
+            device_name,
+            product_key
+        );
+        if let Some(seq) = seq {
+            text.push_str(&format!("seq{}", seq));
+        }
+        text.push_str(&format!("timestamp{}", timestamp));
+        super::sign(&text, device_secret)
+    }
+}
+
 /// 阿里云物联网平台的 X509 根证书
 pub const ALI_CA_CERT: &str = r#"-----BEGIN CERTIFICATE-----
 MIIDdTCCAl2gAwIBAgILBAAAAAABFUtaw5QwDQYJKoZIhvcNAQEFBQAwVzELMAkG
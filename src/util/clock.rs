@@ -0,0 +1,111 @@
+//! SDK 使用的时钟。
+//!
+//! 墙上时间用于签名和日志的时间戳，单调时间用于 token 有效期等时长计算，不受系统时间调整的影响。
+
+use chrono::{DateTime, TimeZone, Utc};
+use lazy_static::lazy_static;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+}
+
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// 当前的 Unix 时间，单位毫秒
+    fn now_millis(&self) -> i64;
+
+    /// 单调递增的时间，只用于计算时长
+    fn monotonic(&self) -> Duration;
+
+    fn now(&self) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(self.now_millis())
+            .single()
+            .unwrap_or_default()
+    }
+
+    /// 日志使用的 UTC 时间，格式为 `yyyy-MM-dd'T'HH:mm:ss.SSSZ`
+    fn utc_time(&self) -> String {
+        self.now().format("%Y-%m-%dT%H:%M:%S%.3f%z").to_string()
+    }
+}
+
+/// 系统时钟
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        crate::ntp::base::system_millis()
+    }
+
+    fn monotonic(&self) -> Duration {
+        START.elapsed()
+    }
+}
+
+/// 经过 NTP 校正的系统时钟，偏移由 [`NtpAgent`](crate::ntp::NtpAgent) 维护
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NtpClock;
+
+impl Clock for NtpClock {
+    fn now_millis(&self) -> i64 {
+        crate::ntp::now_millis()
+    }
+
+    fn monotonic(&self) -> Duration {
+        START.elapsed()
+    }
+}
+
+/// 手动控制的时钟，用于测试
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    inner: Mutex<(i64, Duration)>,
+}
+
+impl ManualClock {
+    pub fn new(millis: i64) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new((millis, Duration::ZERO)),
+        })
+    }
+
+    /// 时间前进 `duration`
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0 += duration.as_millis() as i64;
+        inner.1 += duration;
+    }
+
+    /// 修改墙上时间，单调时间不变
+    pub fn set_millis(&self, millis: i64) {
+        self.inner.lock().unwrap().0 = millis;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> i64 {
+        self.inner.lock().unwrap().0
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.inner.lock().unwrap().1
+    }
+}
+
+/// SDK 默认使用的时钟，即 [`NtpClock`]
+pub fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(NtpClock)
+}
+
+#[test]
+fn test_manual_clock() {
+    let clock = ManualClock::new(1_600_000_000_000);
+    assert_eq!(clock.utc_time(), "2020-09-13T12:26:40.000+0000");
+    clock.advance(Duration::from_millis(1500));
+    clock.set_millis(0);
+    assert_eq!(clock.now_millis(), 0);
+    assert_eq!(clock.monotonic(), Duration::from_millis(1500));
+}
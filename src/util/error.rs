@@ -30,6 +30,8 @@ pub enum Error {
     DownloadError(#[from] crate::http_downloader::Error),
     #[error(transparent)]
     HttpError(#[from] crate::https::HttpError),
+    #[error(transparent)]
+    CoapError(#[from] crate::coap::CoapError),
     #[error("set system time error")]
     SetSystemTimeError,
     #[error("tokio mpsc send error")]
@@ -42,6 +44,8 @@ pub enum Error {
     HeaderFormatError(String),
     #[error("session {0} not found")]
     SessionNotFound(String),
+    #[error("session {0} closed")]
+    SessionClosed(String),
     #[error("send topic to proxy failed")]
     SendTopicError,
     #[error("recv topic from tx failed")]
@@ -86,4 +90,18 @@ pub enum Error {
     HttpRequestBuild,
     #[error("Session 创建失败 {0}")]
     SessionCreate(String),
+    #[error("Session 数量超过限制 {0}")]
+    SessionLimit(String),
+    #[error("连接被拒绝 {0}")]
+    ConnectRefused(String),
+    #[error("无效文件名 {0}")]
+    InvalidFileName(String),
+    #[error("文件大小 {0} 超过限制")]
+    FileTooLarge(u64),
+    #[error("文件完整性校验失败 {0} != {1}")]
+    FicNotMatch(String, String),
+    #[error("配置解析失败 {0}")]
+    ConfigParse(String),
+    #[error("配置被拒绝 {0}")]
+    ConfigRejected(String),
 }
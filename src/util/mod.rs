@@ -1,6 +1,7 @@
 //! 工具类
 
 pub mod auth;
+pub mod clock;
 pub mod error;
 
 use crate::{Error, Result};
@@ -27,7 +28,7 @@ pub fn str2hex(input: &str) -> Vec<u8> {
     fn c2u(c: u8) -> u8 {
         match c {
             b'0'..=b'9' => c - b'0',
-            b'A'..=b'F' => c - b'F' + 0x0A,
+            b'A'..=b'F' => c - b'A' + 0x0A,
             b'a'..=b'f' => c - b'a' + 0x0A,
             _ => 0,
         }
@@ -109,6 +110,15 @@ pub fn validate(buffer: &[u8], sign_method: &str, sign: &str) -> Result<()> {
     Ok(())
 }
 
+/// 先写入临时文件再改名，写入中断时不会留下不完整的文件
+pub(crate) async fn write_atomic(path: &std::path::Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;